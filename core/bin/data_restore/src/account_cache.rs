@@ -0,0 +1,184 @@
+// Built-in deps
+use std::collections::HashMap;
+// Workspace deps
+use zksync_types::{account::Account, AccountId};
+
+/// Cached account with its neighbours in the usage order
+#[derive(Debug)]
+struct CacheEntry {
+    account_id: AccountId,
+    account: Account,
+    /// Slot of the less recently used entry
+    prev: Option<usize>,
+    /// Slot of the more recently used entry
+    next: Option<usize>,
+}
+
+/// Least recently used cache of the accounts read from the tree state.
+///
+/// The entries are kept in a doubly linked list of the usage order stored in the slots
+/// vector, so every operation takes constant time.
+///
+/// The cache isn't aware of the tree updates, so every account changed in the tree
+/// must be invalidated, otherwise the outdated account will be served.
+#[derive(Debug)]
+pub struct AccountCache {
+    capacity: usize,
+    /// Slots of the cached accounts
+    slots: HashMap<AccountId, usize>,
+    entries: Vec<CacheEntry>,
+    /// Slots of the invalidated entries, reused by the next insertions
+    free_slots: Vec<usize>,
+    /// Slot of the least recently used entry
+    head: Option<usize>,
+    /// Slot of the most recently used entry
+    tail: Option<usize>,
+}
+
+impl AccountCache {
+    /// Returns the empty cache
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - Maximum amount of the cached accounts
+    ///
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            slots: HashMap::new(),
+            entries: Vec::new(),
+            free_slots: Vec::new(),
+            head: None,
+            tail: None,
+        }
+    }
+
+    /// Returns the cached account, marking it as the most recently used
+    pub fn get(&mut self, account_id: AccountId) -> Option<Account> {
+        let slot = *self.slots.get(&account_id)?;
+        self.detach(slot);
+        self.push_back(slot);
+        Some(self.entries[slot].account.clone())
+    }
+
+    /// Caches the account, evicting the least recently used one if the cache is full
+    pub fn insert(&mut self, account_id: AccountId, account: Account) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(&slot) = self.slots.get(&account_id) {
+            self.entries[slot].account = account;
+            self.detach(slot);
+            self.push_back(slot);
+            return;
+        }
+
+        let entry = CacheEntry {
+            account_id,
+            account,
+            prev: None,
+            next: None,
+        };
+        let slot = if self.slots.len() == self.capacity {
+            // The cache is full, so the least recently used slot is reused.
+            let slot = self.head.expect("Full cache has no entries");
+            self.detach(slot);
+            self.slots.remove(&self.entries[slot].account_id);
+            self.entries[slot] = entry;
+            slot
+        } else if let Some(slot) = self.free_slots.pop() {
+            self.entries[slot] = entry;
+            slot
+        } else {
+            self.entries.push(entry);
+            self.entries.len() - 1
+        };
+        self.slots.insert(account_id, slot);
+        self.push_back(slot);
+    }
+
+    /// Removes the account from the cache
+    pub fn invalidate(&mut self, account_id: AccountId) {
+        if let Some(slot) = self.slots.remove(&account_id) {
+            self.detach(slot);
+            self.free_slots.push(slot);
+        }
+    }
+
+    /// Removes all the accounts from the cache
+    pub fn clear(&mut self) {
+        self.slots.clear();
+        self.entries.clear();
+        self.free_slots.clear();
+        self.head = None;
+        self.tail = None;
+    }
+
+    /// Returns the amount of the cached accounts
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Returns true if there are no cached accounts
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Unlinks the entry from the usage order
+    fn detach(&mut self, slot: usize) {
+        let (prev, next) = (self.entries[slot].prev, self.entries[slot].next);
+        match prev {
+            Some(prev) => self.entries[prev].next = next,
+            None => self.head = next,
+        }
+        match next {
+            Some(next) => self.entries[next].prev = prev,
+            None => self.tail = prev,
+        }
+        self.entries[slot].prev = None;
+        self.entries[slot].next = None;
+    }
+
+    /// Links the entry as the most recently used one
+    fn push_back(&mut self, slot: usize) {
+        self.entries[slot].prev = self.tail;
+        match self.tail {
+            Some(tail) => self.entries[tail].next = Some(slot),
+            None => self.head = Some(slot),
+        }
+        self.tail = Some(slot);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AccountCache;
+    use zksync_types::Account;
+
+    #[test]
+    fn least_recently_used_eviction() {
+        let account = |id: u8| Account::default_with_address(&[id; 20].into());
+        let mut cache = AccountCache::new(2);
+        cache.insert(0, account(0));
+        cache.insert(1, account(1));
+        // Account 0 becomes the most recently used, so the account 1 is evicted.
+        assert!(cache.get(0).is_some());
+        cache.insert(2, account(2));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(1).is_none());
+        assert_eq!(cache.get(0).unwrap().address, [0u8; 20].into());
+        assert_eq!(cache.get(2).unwrap().address, [2u8; 20].into());
+
+        cache.invalidate(0);
+        assert!(cache.get(0).is_none());
+        assert_eq!(cache.len(), 1);
+
+        // The invalidated slot is reused, and the account 2 is the least recently used.
+        cache.insert(3, account(3));
+        cache.insert(4, account(4));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(2).is_none());
+        assert_eq!(cache.get(3).unwrap().address, [3u8; 20].into());
+        assert_eq!(cache.get(4).unwrap().address, [4u8; 20].into());
+    }
+}
@@ -0,0 +1,198 @@
+// Built-in deps
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::future::Future;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+// External deps
+use serde::{Deserialize, Serialize};
+use web3::{RequestId, Transport};
+
+/// Web3 request stored in the archive along with its response
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedRequest {
+    /// JSON-RPC method name
+    pub method: String,
+    /// JSON-RPC method parameters
+    pub params: Vec<jsonrpc_core::Value>,
+    /// Response returned by the node
+    pub response: jsonrpc_core::Value,
+}
+
+/// Returns the JSON-RPC method name and parameters of the call
+fn call_method_and_params(call: &jsonrpc_core::Call) -> Option<(String, Vec<jsonrpc_core::Value>)> {
+    match call {
+        jsonrpc_core::Call::MethodCall(call) => {
+            let params = match &call.params {
+                jsonrpc_core::Params::Array(params) => params.clone(),
+                jsonrpc_core::Params::Map(params) => {
+                    vec![jsonrpc_core::Value::Object(params.clone())]
+                }
+                jsonrpc_core::Params::None => vec![],
+            };
+            Some((call.method.clone(), params))
+        }
+        _ => None,
+    }
+}
+
+/// Web3 transport that records all the successful responses of the wrapped transport,
+/// so they can be saved into the archive and replayed by the `ArchiveTransport` later
+#[derive(Debug, Clone)]
+pub struct RecordingTransport<T> {
+    transport: T,
+    records: Arc<Mutex<Vec<ArchivedRequest>>>,
+}
+
+impl<T: Transport> RecordingTransport<T> {
+    /// Returns the new transport recording the responses of the provided one
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            records: Default::default(),
+        }
+    }
+
+    /// Returns the recorded requests in the order they were made
+    pub fn records(&self) -> Vec<ArchivedRequest> {
+        self.records.lock().unwrap().clone()
+    }
+
+    /// Saves the recorded requests into the newline-delimited JSON archive file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Archive file path
+    ///
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), anyhow::Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        for record in self.records() {
+            serde_json::to_writer(&mut writer, &record)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<T> Transport for RecordingTransport<T>
+where
+    T: Transport,
+    T::Out: Send + 'static,
+{
+    type Out =
+        Pin<Box<dyn Future<Output = Result<jsonrpc_core::Value, web3::Error>> + Send + 'static>>;
+
+    fn prepare(
+        &self,
+        method: &str,
+        params: Vec<jsonrpc_core::Value>,
+    ) -> (RequestId, jsonrpc_core::Call) {
+        self.transport.prepare(method, params)
+    }
+
+    fn send(&self, id: RequestId, request: jsonrpc_core::Call) -> Self::Out {
+        let method_and_params = call_method_and_params(&request);
+        let response = self.transport.send(id, request);
+        let records = self.records.clone();
+
+        Box::pin(async move {
+            let response = response.await?;
+            if let Some((method, params)) = method_and_params {
+                records.lock().unwrap().push(ArchivedRequest {
+                    method,
+                    params,
+                    response: response.clone(),
+                });
+            }
+            Ok(response)
+        })
+    }
+}
+
+/// Web3 transport that serves the archived responses, allowing to restore the state
+/// without any Ethereum node.
+///
+/// The responses of the repeated requests are replayed in the recorded order,
+/// and the last one is returned once they're exhausted.
+#[derive(Debug, Clone)]
+pub struct ArchiveTransport {
+    responses: Arc<Mutex<HashMap<(String, String), VecDeque<jsonrpc_core::Value>>>>,
+}
+
+impl ArchiveTransport {
+    /// Returns the transport serving the provided archived requests
+    pub fn from_records(records: Vec<ArchivedRequest>) -> Self {
+        let mut responses: HashMap<_, VecDeque<_>> = HashMap::new();
+        for record in records {
+            let params = jsonrpc_core::Value::Array(record.params).to_string();
+            responses
+                .entry((record.method, params))
+                .or_default()
+                .push_back(record.response);
+        }
+        Self {
+            responses: Arc::new(Mutex::new(responses)),
+        }
+    }
+
+    /// Loads the archived requests from the newline-delimited JSON archive file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Archive file path
+    ///
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let mut records = Vec::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            records.push(serde_json::from_str(&line)?);
+        }
+        Ok(Self::from_records(records))
+    }
+}
+
+impl Transport for ArchiveTransport {
+    type Out =
+        Pin<Box<dyn Future<Output = Result<jsonrpc_core::Value, web3::Error>> + Send + 'static>>;
+
+    fn prepare(
+        &self,
+        method: &str,
+        params: Vec<jsonrpc_core::Value>,
+    ) -> (RequestId, jsonrpc_core::Call) {
+        (
+            1,
+            jsonrpc_core::Call::MethodCall(jsonrpc_core::MethodCall {
+                jsonrpc: Some(jsonrpc_core::Version::V2),
+                method: method.to_string(),
+                params: jsonrpc_core::Params::Array(params),
+                id: jsonrpc_core::Id::Num(1),
+            }),
+        )
+    }
+
+    fn send(&self, _id: RequestId, request: jsonrpc_core::Call) -> Self::Out {
+        let response = call_method_and_params(&request).and_then(|(method, params)| {
+            let params = jsonrpc_core::Value::Array(params).to_string();
+            let mut responses = self.responses.lock().unwrap();
+            let method_responses = responses.get_mut(&(method, params))?;
+            if method_responses.len() > 1 {
+                method_responses.pop_front()
+            } else {
+                method_responses.front().cloned()
+            }
+        });
+
+        Box::pin(async move {
+            response.ok_or_else(|| {
+                web3::Error::InvalidResponse("Request is missing in the archive".into())
+            })
+        })
+    }
+}
@@ -0,0 +1,323 @@
+// External deps
+use anyhow::format_err;
+use num::{BigInt, BigUint};
+// Workspace deps
+use zksync_types::{operations::ZkSyncOp, AccountId, TokenId};
+// Local deps
+use crate::{rollup_ops::RollupOpsBlock, tree_state::TreeState};
+
+/// Difference between the reconstructed account balance and the balance expected
+/// from the committed operations
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceDiscrepancy {
+    /// Account id
+    pub account_id: AccountId,
+    /// Token id
+    pub token: TokenId,
+    /// Balance computed from the deposits, exits, transfers and fees of the account
+    pub expected: BigUint,
+    /// Balance stored in the tree state
+    pub reconstructed: BigUint,
+}
+
+/// Returns the change of the account token balance made by the operation,
+/// without the fees collected by the block fee account
+fn balance_change(op: &ZkSyncOp, account_id: AccountId, token: TokenId) -> BigInt {
+    let amount = |value: &BigUint| BigInt::from(value.clone());
+    let mut change = BigInt::from(0);
+    match op {
+        ZkSyncOp::Deposit(op) => {
+            if op.account_id == account_id && op.priority_op.token == token {
+                change += amount(&op.priority_op.amount);
+            }
+        }
+        ZkSyncOp::Transfer(op) if op.tx.token == token => {
+            if op.from == account_id {
+                change -= amount(&op.tx.amount) + amount(&op.tx.fee);
+            }
+            if op.to == account_id {
+                change += amount(&op.tx.amount);
+            }
+        }
+        ZkSyncOp::TransferToNew(op) if op.tx.token == token => {
+            if op.from == account_id {
+                change -= amount(&op.tx.amount) + amount(&op.tx.fee);
+            }
+            if op.to == account_id {
+                change += amount(&op.tx.amount);
+            }
+        }
+        ZkSyncOp::Withdraw(op) => {
+            if op.account_id == account_id && op.tx.token == token {
+                change -= amount(&op.tx.amount) + amount(&op.tx.fee);
+            }
+        }
+        ZkSyncOp::FullExit(op) => {
+            if op.priority_op.account_id == account_id && op.priority_op.token == token {
+                if let Some(withdraw_amount) = &op.withdraw_amount {
+                    change -= amount(&withdraw_amount.0);
+                }
+            }
+        }
+        ZkSyncOp::ForcedExit(op) if op.tx.token == token => {
+            if op.tx.initiator_account_id == account_id {
+                change -= amount(&op.tx.fee);
+            }
+            if op.target_account_id == account_id {
+                if let Some(withdraw_amount) = &op.withdraw_amount {
+                    change -= amount(&withdraw_amount.0);
+                }
+            }
+        }
+        ZkSyncOp::ChangePubKeyOffchain(op) => {
+            if op.account_id == account_id && op.tx.fee_token == token {
+                change -= amount(&op.tx.fee);
+            }
+        }
+        _ => {}
+    }
+    change
+}
+
+/// Returns the fee in the token collected by the block fee account for the operation
+fn collected_fee(op: &ZkSyncOp, token: TokenId) -> BigInt {
+    let fee = match op {
+        ZkSyncOp::Transfer(op) if op.tx.token == token => Some(&op.tx.fee),
+        ZkSyncOp::TransferToNew(op) if op.tx.token == token => Some(&op.tx.fee),
+        ZkSyncOp::Withdraw(op) if op.tx.token == token => Some(&op.tx.fee),
+        ZkSyncOp::ForcedExit(op) if op.tx.token == token => Some(&op.tx.fee),
+        ZkSyncOp::ChangePubKeyOffchain(op) if op.tx.fee_token == token => Some(&op.tx.fee),
+        _ => None,
+    };
+    fee.map(|fee| BigInt::from(fee.clone()))
+        .unwrap_or_else(|| BigInt::from(0))
+}
+
+/// Replay check against the applied operations: compares the account token balance stored
+/// in the tree state with the balance recomputed from the same operations blocks.
+///
+/// The balance isn't taken from an independent source, so the check only catches the errors
+/// of applying the operations to the tree state, not the corrupted operations themselves.
+/// Use `diff_expected_balances` to compare against an external source of truth.
+/// Returns the discrepancy, if any
+///
+/// # Arguments
+///
+/// * `tree_state` - Rollup accounts states
+/// * `ops_blocks` - All the Rollup operations blocks applied to the tree state
+/// * `account_id` - Account id
+/// * `token` - Token id
+///
+pub fn replay_check_account_balance(
+    tree_state: &TreeState,
+    ops_blocks: &[RollupOpsBlock],
+    account_id: AccountId,
+    token: TokenId,
+) -> Result<Option<BalanceDiscrepancy>, anyhow::Error> {
+    let reconstructed = tree_state
+        .get_account(account_id)
+        .ok_or_else(|| format_err!("Account {} doesn't exist", account_id))?
+        .get_balance(token);
+
+    let mut expected = BigInt::from(0);
+    for block in ops_blocks {
+        for op in &block.ops {
+            expected += balance_change(op, account_id, token);
+            if block.fee_account == account_id {
+                expected += collected_fee(op, token);
+            }
+        }
+    }
+    let expected = expected.to_biguint().ok_or_else(|| {
+        format_err!(
+            "Operations of the account {} spend more than it has received",
+            account_id
+        )
+    })?;
+
+    if expected == reconstructed {
+        Ok(None)
+    } else {
+        Ok(Some(BalanceDiscrepancy {
+            account_id,
+            token,
+            expected,
+            reconstructed,
+        }))
+    }
+}
+
+/// Compares the balances of an external source of truth with the tree state one by one,
+/// so the expected balances don't have to be loaded into memory at once.
+/// Returns at most `limit` first discrepancies, balances of the nonexistent accounts
+/// are considered zero
+///
+/// # Arguments
+///
+/// * `tree_state` - Rollup accounts states
+/// * `expected_balances` - Expected `(account id, token, balance)` records
+/// * `limit` - Maximum amount of the reported discrepancies
+///
+pub fn diff_expected_balances(
+    tree_state: &TreeState,
+    expected_balances: impl IntoIterator<Item = (AccountId, TokenId, BigUint)>,
+    limit: usize,
+) -> Vec<BalanceDiscrepancy> {
+    expected_balances
+        .into_iter()
+        .filter_map(|(account_id, token, expected)| {
+            let reconstructed = tree_state
+                .get_account(account_id)
+                .map(|account| account.get_balance(token))
+                .unwrap_or_else(|| BigUint::from(0u32));
+            if expected == reconstructed {
+                None
+            } else {
+                Some(BalanceDiscrepancy {
+                    account_id,
+                    token,
+                    expected,
+                    reconstructed,
+                })
+            }
+        })
+        .take(limit)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diff_expected_balances, replay_check_account_balance, BalanceDiscrepancy};
+    use crate::{rollup_ops::RollupOpsBlock, tree_state::TreeState};
+    use num::BigUint;
+    use zksync_types::{Deposit, DepositOp, Transfer, TransferOp, Withdraw, WithdrawOp, ZkSyncOp};
+
+    #[test]
+    fn replay_check_balance() {
+        let deposit = |to: u8, account_id, amount: u32| {
+            ZkSyncOp::Deposit(Box::new(DepositOp {
+                priority_op: Deposit {
+                    from: [1u8; 20].into(),
+                    token: 1,
+                    amount: BigUint::from(amount),
+                    to: [to; 20].into(),
+                },
+                account_id,
+            }))
+        };
+        let transfer = ZkSyncOp::Transfer(Box::new(TransferOp {
+            tx: Transfer::new(
+                0,
+                [7u8; 20].into(),
+                [8u8; 20].into(),
+                1,
+                BigUint::from(100u32),
+                BigUint::from(10u32),
+                0,
+                None,
+            ),
+            from: 0,
+            to: 1,
+        }));
+        let withdraw = ZkSyncOp::Withdraw(Box::new(WithdrawOp {
+            tx: Withdraw::new(
+                1,
+                [8u8; 20].into(),
+                [9u8; 20].into(),
+                1,
+                BigUint::from(50u32),
+                BigUint::from(5u32),
+                0,
+                None,
+            ),
+            account_id: 1,
+        }));
+        let ops_blocks = vec![
+            RollupOpsBlock {
+                block_num: 1,
+                ops: vec![deposit(7, 0, 1000), deposit(8, 1, 500)],
+                fee_account: 0,
+            },
+            RollupOpsBlock {
+                block_num: 2,
+                ops: vec![transfer, withdraw],
+                fee_account: 1,
+            },
+        ];
+
+        let mut tree_state = TreeState::new(vec![50]);
+        for block in &ops_blocks {
+            tree_state
+                .update_tree_states_from_ops_block(block)
+                .expect("Cant update state");
+        }
+
+        assert_eq!(
+            replay_check_account_balance(&tree_state, &ops_blocks, 0, 1).unwrap(),
+            None
+        );
+        assert_eq!(
+            replay_check_account_balance(&tree_state, &ops_blocks, 1, 1).unwrap(),
+            None
+        );
+
+        // The balance diverges if some of the applied operations are not accounted.
+        assert_eq!(
+            replay_check_account_balance(&tree_state, &ops_blocks[..1], 1, 1).unwrap(),
+            Some(BalanceDiscrepancy {
+                account_id: 1,
+                token: 1,
+                expected: BigUint::from(500u32),
+                reconstructed: BigUint::from(560u32),
+            })
+        );
+        assert!(replay_check_account_balance(&tree_state, &ops_blocks, 2, 1).is_err());
+    }
+
+    #[test]
+    fn diff_with_expected_balances() {
+        let mut tree_state = TreeState::new(vec![50]);
+        let deposits = (0..3u32)
+            .map(|account_id| {
+                ZkSyncOp::Deposit(Box::new(DepositOp {
+                    priority_op: Deposit {
+                        from: [1u8; 20].into(),
+                        token: 1,
+                        amount: BigUint::from(100u32),
+                        to: [account_id as u8 + 7; 20].into(),
+                    },
+                    account_id,
+                }))
+            })
+            .collect();
+        tree_state
+            .update_tree_states_from_ops_block(&RollupOpsBlock {
+                block_num: 1,
+                ops: deposits,
+                fee_account: 0,
+            })
+            .expect("Cant update state");
+
+        let expected_balances = vec![
+            (0, 1, BigUint::from(100u32)),
+            (1, 1, BigUint::from(90u32)),
+            (2, 1, BigUint::from(100u32)),
+            (2, 2, BigUint::from(0u32)),
+            (5, 1, BigUint::from(10u32)),
+        ];
+        let discrepancy = |account_id, expected: u32, reconstructed: u32| BalanceDiscrepancy {
+            account_id,
+            token: 1,
+            expected: BigUint::from(expected),
+            reconstructed: BigUint::from(reconstructed),
+        };
+        assert_eq!(
+            diff_expected_balances(&tree_state, expected_balances.clone(), 10),
+            vec![discrepancy(1, 90, 100), discrepancy(5, 10, 0)]
+        );
+        assert_eq!(
+            diff_expected_balances(&tree_state, expected_balances, 1),
+            vec![discrepancy(1, 90, 100)]
+        );
+    }
+}
@@ -1,7 +1,11 @@
 use crate::eth_tx_helpers::get_input_data_from_ethereum_transaction;
+use crate::tree_state::TreeState;
+use anyhow::{ensure, format_err};
+use std::path::Path;
 use web3::contract::{Contract, Options};
-use web3::types::{Address, BlockId, Transaction, U256};
+use web3::types::{Address, BlockId, Transaction, H256, U256};
 use web3::Transport;
+use zksync_crypto::convert::FeConvert;
 use zksync_crypto::params::{INPUT_DATA_ADDRESS_BYTES_WIDTH, INPUT_DATA_ROOT_HASH_BYTES_WIDTH};
 use zksync_types::account::Account;
 
@@ -68,6 +72,51 @@ pub fn get_genesis_account(genesis_transaction: &Transaction) -> Result<Account,
     })
 }
 
+/// Error of loading the contract ABI from the JSON file
+#[derive(Debug)]
+pub enum ContractAbiError {
+    /// The file can't be read
+    Io(std::io::Error),
+    /// The file isn't valid JSON or the ABI entries are malformed
+    Json(serde_json::Error),
+    /// The file is neither the ABI array nor the artifact with the `abi` field
+    MissingAbi,
+}
+
+impl std::fmt::Display for ContractAbiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContractAbiError::Io(e) => write!(f, "Can't read contract ABI file: {}", e),
+            ContractAbiError::Json(e) => write!(f, "Malformed contract ABI: {}", e),
+            ContractAbiError::MissingAbi => write!(f, "Contract ABI file has no ABI"),
+        }
+    }
+}
+
+impl std::error::Error for ContractAbiError {}
+
+/// Loads the contract ABI from the JSON file.
+/// Both the plain ABI array and the compiled contract artifact (with the ABI stored
+/// in the `abi` field) are accepted.
+///
+/// # Arguments
+///
+/// * `path` - Path to the JSON file with the contract ABI
+///
+pub fn load_contract_abi(path: impl AsRef<Path>) -> Result<ethabi::Contract, ContractAbiError> {
+    let content = std::fs::read_to_string(path).map_err(ContractAbiError::Io)?;
+    let value: serde_json::Value =
+        serde_json::from_str(&content).map_err(ContractAbiError::Json)?;
+    let abi = match value {
+        serde_json::Value::Array(_) => value,
+        serde_json::Value::Object(mut artifact) => {
+            artifact.remove("abi").ok_or(ContractAbiError::MissingAbi)?
+        }
+        _ => return Err(ContractAbiError::MissingAbi),
+    };
+    serde_json::from_value(abi).map_err(ContractAbiError::Json)
+}
+
 /// Returns total number of verified blocks on Rollup contract
 ///
 /// # Arguments
@@ -91,3 +140,308 @@ pub async fn get_total_verified_blocks<T: Transport>(
         .unwrap()
         .as_u32()
 }
+
+/// Returns the accounts tree root hash stored on Rollup contract for the block
+///
+/// # Arguments
+///
+/// * `zksync_contract` - Rollup contract
+/// * `block_number` - Rollup block number
+///
+pub async fn get_block_state_root<T: Transport>(
+    zksync_contract: &(ethabi::Contract, Contract<T>),
+    block_number: u32,
+) -> Result<H256, anyhow::Error> {
+    // Fields of the `Block` structure stored in the public `blocks` mapping:
+    // committedAtBlock, priorityOperations, chunks, withdrawalsDataHash, commitment, stateRoot.
+    let (_, _, _, _, _, state_root) = zksync_contract
+        .1
+        .query::<(U256, U256, U256, H256, H256, H256), Option<Address>, Option<BlockId>, _>(
+            "blocks",
+            (U256::from(block_number),),
+            None,
+            Options::default(),
+            None,
+        )
+        .await
+        .map_err(|e| format_err!("Can't get block {} from the contract: {}", block_number, e))?;
+    Ok(state_root)
+}
+
+/// Checks that the restored accounts tree root hash matches the root hash
+/// stored on Rollup contract for the last restored block
+///
+/// # Arguments
+///
+/// * `zksync_contract` - Rollup contract
+/// * `tree_state` - Rollup accounts states
+///
+pub async fn verify_against_contract_root<T: Transport>(
+    zksync_contract: &(ethabi::Contract, Contract<T>),
+    tree_state: &TreeState,
+) -> Result<(), anyhow::Error> {
+    let block_number = tree_state.state.block_number;
+    let contract_root = get_block_state_root(zksync_contract, block_number).await?;
+    let restored_root = H256::from_slice(&tree_state.root_hash().to_bytes());
+    ensure!(
+        restored_root == contract_root,
+        "Root hash mismatch for block {}: restored {:?}, stored on the contract {:?}",
+        block_number,
+        restored_root,
+        contract_root
+    );
+    Ok(())
+}
+
+/// Error of the restored accounts count not matching the total accounts counter
+/// of Rollup contract. Can be downcasted from the check error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccountsCountMismatch {
+    /// Amount of the accounts in the restored tree state
+    pub restored: u32,
+    /// Total accounts counter of the contract
+    pub on_contract: u32,
+}
+
+impl std::fmt::Display for AccountsCountMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Accounts count mismatch: restored {}, stored on the contract {}",
+            self.restored, self.on_contract
+        )
+    }
+}
+
+impl std::error::Error for AccountsCountMismatch {}
+
+/// Checks that the amount of the restored accounts matches the total accounts counter
+/// of Rollup contract. The upstream contract has no such counter, so the check is only
+/// available for the forked contracts that expose it via a view function without parameters
+///
+/// # Arguments
+///
+/// * `zksync_contract` - Rollup contract
+/// * `counter_function` - Name of the contract function returning the total accounts
+/// * `tree_state` - Rollup accounts states
+///
+pub async fn verify_against_contract_accounts_count<T: Transport>(
+    zksync_contract: &(ethabi::Contract, Contract<T>),
+    counter_function: &str,
+    tree_state: &TreeState,
+) -> Result<(), anyhow::Error> {
+    let on_contract = zksync_contract
+        .1
+        .query::<U256, Option<Address>, Option<BlockId>, ()>(
+            counter_function,
+            (),
+            None,
+            Options::default(),
+            None,
+        )
+        .await
+        .map_err(|e| format_err!("Can't get the total accounts from the contract: {}", e))?
+        .as_u32();
+    let restored = tree_state.get_accounts().len() as u32;
+    if restored != on_contract {
+        return Err(AccountsCountMismatch {
+            restored,
+            on_contract,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        load_contract_abi, verify_against_contract_accounts_count, verify_against_contract_root,
+        AccountsCountMismatch, ContractAbiError,
+    };
+    use crate::tests::utils::temp_path;
+    use crate::tree_state::TreeState;
+    use ethabi::Token;
+    use futures::future;
+    use web3::contract::Contract;
+    use web3::types::H256;
+    use web3::{RequestId, Transport, Web3};
+    use zksync_contracts::zksync_contract;
+    use zksync_crypto::convert::FeConvert;
+    use zksync_types::{Account, AccountMap};
+
+    const ABI: &str = r#"[{
+        "type": "event",
+        "name": "BlockCommit",
+        "inputs": [{ "name": "blockNumber", "type": "uint32", "indexed": true }],
+        "anonymous": false
+    }]"#;
+
+    fn write_abi_file(name: &str, content: &str) -> std::path::PathBuf {
+        let path = temp_path(name);
+        std::fs::write(&path, content).expect("Can't write ABI file");
+        path
+    }
+
+    #[test]
+    fn load_valid_abi() {
+        let path = write_abi_file("data_restore_valid_abi.json", ABI);
+        let contract = load_contract_abi(&path).expect("Valid ABI must be loaded");
+        assert!(contract.event("BlockCommit").is_ok());
+        std::fs::remove_file(&path).unwrap();
+
+        let artifact = format!(r#"{{ "contractName": "ZkSync", "abi": {} }}"#, ABI);
+        let path = write_abi_file("data_restore_valid_artifact.json", &artifact);
+        let contract = load_contract_abi(&path).expect("Valid artifact must be loaded");
+        assert!(contract.event("BlockCommit").is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Transport answering all the contract calls with the same output
+    #[derive(Debug, Clone)]
+    struct EthCallTransport {
+        output: Vec<Token>,
+    }
+
+    impl Transport for EthCallTransport {
+        type Out = future::Ready<Result<jsonrpc_core::Value, web3::Error>>;
+
+        fn prepare(
+            &self,
+            method: &str,
+            params: Vec<jsonrpc_core::Value>,
+        ) -> (RequestId, jsonrpc_core::Call) {
+            (
+                1,
+                jsonrpc_core::Call::MethodCall(jsonrpc_core::MethodCall {
+                    jsonrpc: Some(jsonrpc_core::Version::V2),
+                    method: method.to_string(),
+                    params: jsonrpc_core::Params::Array(params),
+                    id: jsonrpc_core::Id::Num(1),
+                }),
+            )
+        }
+
+        fn send(&self, _id: RequestId, request: jsonrpc_core::Call) -> Self::Out {
+            let response = match request {
+                jsonrpc_core::Call::MethodCall(call) if call.method == "eth_call" => Ok(
+                    serde_json::json!(format!("0x{}", hex::encode(ethabi::encode(&self.output)))),
+                ),
+                _ => Err(web3::Error::Unreachable),
+            };
+            future::ready(response)
+        }
+    }
+
+    #[tokio::test]
+    async fn verify_root_against_contract() {
+        let mut accounts = AccountMap::default();
+        accounts.insert(0, Account::default_with_address(&[7u8; 20].into()));
+        let tree_state = TreeState::load(3, accounts, 0, 0, vec![50]);
+        let state_root = H256::from_slice(&tree_state.root_hash().to_bytes());
+
+        let contract = |state_root: H256| {
+            // Stored block of the `blocks` mapping.
+            let output = vec![
+                Token::Uint(1.into()),
+                Token::Uint(0.into()),
+                Token::Uint(10.into()),
+                Token::FixedBytes(vec![0u8; 32]),
+                Token::FixedBytes(vec![0u8; 32]),
+                Token::FixedBytes(state_root.as_bytes().to_vec()),
+            ];
+            let web3 = Web3::new(EthCallTransport { output });
+            (
+                zksync_contract(),
+                Contract::new(web3.eth(), Default::default(), zksync_contract()),
+            )
+        };
+
+        verify_against_contract_root(&contract(state_root), &tree_state)
+            .await
+            .expect("Restored root hash must match the stored one");
+
+        let err = verify_against_contract_root(&contract(H256::from([1u8; 32])), &tree_state)
+            .await
+            .err()
+            .expect("Root hash mismatch must be detected");
+        assert!(err.to_string().contains("Root hash mismatch for block 3"));
+    }
+
+    #[tokio::test]
+    async fn verify_accounts_count_against_contract() {
+        const COUNTER_ABI: &str = r#"[{
+            "type": "function",
+            "name": "totalAccounts",
+            "inputs": [],
+            "outputs": [{ "name": "", "type": "uint32" }],
+            "constant": true,
+            "stateMutability": "view"
+        }]"#;
+        let path = write_abi_file("data_restore_counter_abi.json", COUNTER_ABI);
+        let abi = load_contract_abi(&path).expect("Valid ABI must be loaded");
+        std::fs::remove_file(&path).unwrap();
+
+        let mut accounts = AccountMap::default();
+        accounts.insert(0, Account::default_with_address(&[7u8; 20].into()));
+        accounts.insert(1, Account::default_with_address(&[8u8; 20].into()));
+        let tree_state = TreeState::load(3, accounts, 0, 0, vec![50]);
+
+        let contract = |total_accounts: u32| {
+            let web3 = Web3::new(EthCallTransport {
+                output: vec![Token::Uint(total_accounts.into())],
+            });
+            (
+                abi.clone(),
+                Contract::new(web3.eth(), Default::default(), abi.clone()),
+            )
+        };
+
+        verify_against_contract_accounts_count(&contract(2), "totalAccounts", &tree_state)
+            .await
+            .expect("Restored accounts count must match the contract counter");
+
+        let err =
+            verify_against_contract_accounts_count(&contract(3), "totalAccounts", &tree_state)
+                .await
+                .err()
+                .expect("Accounts count mismatch must be detected");
+        assert_eq!(
+            err.downcast_ref::<AccountsCountMismatch>(),
+            Some(&AccountsCountMismatch {
+                restored: 2,
+                on_contract: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn load_malformed_abi() {
+        let path = temp_path("data_restore_missing_abi.json");
+        assert!(matches!(
+            load_contract_abi(&path),
+            Err(ContractAbiError::Io(_))
+        ));
+
+        for content in &[
+            r#"[{ "type": "event" "#,
+            r#"[{ "type": "event", "name": 42 }]"#,
+        ] {
+            let path = write_abi_file("data_restore_malformed_abi.json", content);
+            assert!(matches!(
+                load_contract_abi(&path),
+                Err(ContractAbiError::Json(_))
+            ));
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        for content in &[r#"{ "contractName": "ZkSync" }"#, "42"] {
+            let path = write_abi_file("data_restore_no_abi.json", content);
+            assert!(matches!(
+                load_contract_abi(&path),
+                Err(ContractAbiError::MissingAbi)
+            ));
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+}
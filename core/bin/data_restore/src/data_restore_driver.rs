@@ -1,22 +1,38 @@
+// Built-in deps
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::Duration;
 // External deps
+use anyhow::{bail, ensure, format_err};
+use tokio::sync::Notify;
 use web3::{
     contract::Contract,
-    types::{H160, H256},
+    types::{Log, H160, H256},
     Transport, Web3,
 };
 // Workspace deps
 use zksync_contracts::{governance_contract, zksync_contract};
 use zksync_crypto::Fr;
 
-use zksync_types::{AccountMap, AccountUpdate};
+use zksync_types::{AccountId, AccountMap, AccountUpdate, ZkSyncOp};
 // Local deps
 use crate::storage_interactor::StorageInteractor;
 use crate::{
     contract_functions::{get_genesis_account, get_total_verified_blocks},
     eth_tx_helpers::get_ethereum_transaction,
-    events_state::EventsState,
+    event_decoder::{DefaultEventDecoder, EventDecoder},
+    events_state::{ContractTopics, EventsState, RpcUsage},
+    ops_block_source::{collect_op_blocks, EventsOpBlockSource, OpBlockSource, VecOpBlockSource},
+    ops_blocks_wal::OpsBlocksWal,
+    restore_progress::{RestoreEstimate, RestoreProgress},
     rollup_ops::RollupOpsBlock,
+    snapshot::{load_snapshot_binary_with_events, save_snapshot_binary_with_events},
     tree_state::TreeState,
+    POLL_INTERVAL,
 };
 use serde::export::PhantomData;
 
@@ -31,6 +47,41 @@ pub enum StorageUpdateState {
     Operations,
 }
 
+/// Handle used to request the running data restore driver to stop.
+///
+/// The driver checks it between the state updates, so the stop happens only
+/// after the block currently being restored is saved in storage. Thus, the storage
+/// always contains a consistent state that can be restored in the `continue` mode.
+#[derive(Debug, Clone, Default)]
+pub struct StopHandle {
+    stop_requested: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+}
+
+impl StopHandle {
+    /// Requests the driver to stop
+    pub fn stop(&self) {
+        self.stop_requested.store(true, Ordering::SeqCst);
+        self.notify.notify();
+    }
+
+    /// Returns true if the stop was requested
+    pub fn is_stop_requested(&self) -> bool {
+        self.stop_requested.load(Ordering::SeqCst)
+    }
+
+    /// Waits for the provided duration or until the stop is requested
+    async fn wait(&self, duration: Duration) {
+        if self.is_stop_requested() {
+            return;
+        }
+        tokio::select! {
+            _ = tokio::time::delay_for(duration) => {}
+            _ = self.notify.notified() => {}
+        }
+    }
+}
+
 /// Data restore driver is a high level interface for all restoring components.
 /// It is actually a finite state machine, that has following states:
 /// - Empty - The state is new
@@ -52,6 +103,10 @@ pub struct DataRestoreDriver<T: Transport, I> {
     pub governance_contract: (ethabi::Contract, Contract<T>),
     /// Provides Ethereum Rollup contract unterface
     pub zksync_contract: (ethabi::Contract, Contract<T>),
+    /// Accepted signatures of the watched contracts events
+    pub contract_topics: ContractTopics,
+    /// Decoder of the contracts events and calls data, may be replaced for the forked contracts
+    pub event_decoder: Box<dyn EventDecoder>,
     /// Rollup contract events state
     pub events_state: EventsState,
     /// Rollup accounts state
@@ -69,6 +124,18 @@ pub struct DataRestoreDriver<T: Transport, I> {
     /// Expected root hash to be observed after restoring process. Only
     /// available in finite mode, and intended for tests.
     pub final_hash: Option<Fr>,
+    /// Restore progress tracker, used to estimate the remaining work
+    pub progress: RestoreProgress,
+    /// Interval between the checks for the new events once all the known ones are processed
+    pub poll_interval: Duration,
+    /// Expected id of the Ethereum chain. If set, the chain of the web3 provider
+    /// is checked before applying any events.
+    pub expected_chain_id: Option<u64>,
+    /// Write-ahead log of the operations blocks. If set, every block is recorded
+    /// before it is applied, so it can be recovered after a crash.
+    pub ops_blocks_wal: Option<OpsBlocksWal>,
+    /// Handle used to stop the state updates
+    stop_handle: StopHandle,
     phantom_data: PhantomData<I>,
 }
 
@@ -116,6 +183,8 @@ where
             )
         };
 
+        let contract_topics = ContractTopics::new(&zksync_contract.0, &governance_contract.0);
+
         let events_state = EventsState::default();
 
         let tree_state = TreeState::new(available_block_chunk_sizes.clone());
@@ -124,6 +193,8 @@ where
             web3,
             governance_contract,
             zksync_contract,
+            contract_topics,
+            event_decoder: Box::new(DefaultEventDecoder),
             events_state,
             tree_state,
             eth_blocks_step,
@@ -131,10 +202,43 @@ where
             available_block_chunk_sizes,
             finite_mode,
             final_hash,
+            progress: RestoreProgress::default(),
+            poll_interval: POLL_INTERVAL,
+            expected_chain_id: None,
+            ops_blocks_wal: None,
+            stop_handle: StopHandle::default(),
             phantom_data: Default::default(),
         }
     }
 
+    /// Replaces the Rollup contract ABI used to decode the contract events and calls.
+    /// Accepted events signatures are reset to the ones declared in the new ABI.
+    ///
+    /// # Arguments
+    ///
+    /// * `abi` - Rollup contract ABI
+    ///
+    pub fn set_zksync_contract_abi(&mut self, abi: ethabi::Contract) {
+        let address = self.zksync_contract.1.address();
+        self.zksync_contract = (abi.clone(), Contract::new(self.web3.eth(), address, abi));
+        self.contract_topics =
+            ContractTopics::new(&self.zksync_contract.0, &self.governance_contract.0);
+    }
+
+    /// Replaces the Governance contract ABI used to decode the contract events.
+    /// Accepted events signatures are reset to the ones declared in the new ABI.
+    ///
+    /// # Arguments
+    ///
+    /// * `abi` - Governance contract ABI
+    ///
+    pub fn set_governance_contract_abi(&mut self, abi: ethabi::Contract) {
+        let address = self.governance_contract.1.address();
+        self.governance_contract = (abi.clone(), Contract::new(self.web3.eth(), address, abi));
+        self.contract_topics =
+            ContractTopics::new(&self.zksync_contract.0, &self.governance_contract.0);
+    }
+
     /// Sets the 'genesis' state.
     /// Tree with inserted genesis account will be created.
     /// Used when restore driver is restarted.
@@ -179,13 +283,14 @@ where
         let current_unprocessed_priority_op = 0;
         let fee_acc_num = 0;
 
-        let tree_state = TreeState::load(
+        let mut tree_state = TreeState::load(
             current_block,
             account_map,
             current_unprocessed_priority_op,
             fee_acc_num,
             self.available_block_chunk_sizes.clone(),
         );
+        tree_state.take_settings_from(&mut self.tree_state);
 
         log::info!("Genesis tree root hash: {:?}", tree_state.root_hash());
         log::debug!("Genesis accounts: {:?}", tree_state.get_accounts());
@@ -201,27 +306,33 @@ where
     pub async fn load_state_from_storage(&mut self, interactor: &mut I) -> bool {
         log::info!("Loading state from storage");
         let state = interactor.get_storage_state().await;
+        let lenient_decoding = self.events_state.lenient_decoding;
         self.events_state = interactor.get_block_events_state_from_storage().await;
-        let tree_state = interactor.get_tree_state().await;
-        self.tree_state = TreeState::load(
-            tree_state.last_block_number,     // current block
-            tree_state.account_map,           // account map
-            tree_state.unprocessed_prior_ops, // unprocessed priority op
-            tree_state.fee_acc_id,            // fee account
+        self.events_state.lenient_decoding = lenient_decoding;
+        let stored_tree_state = interactor.get_tree_state().await;
+        let mut tree_state = TreeState::load(
+            stored_tree_state.last_block_number,     // current block
+            stored_tree_state.account_map,           // account map
+            stored_tree_state.unprocessed_prior_ops, // unprocessed priority op
+            stored_tree_state.fee_acc_id,            // fee account
             self.available_block_chunk_sizes.clone(),
         );
+        tree_state.take_settings_from(&mut self.tree_state);
+        self.tree_state = tree_state;
         match state {
             StorageUpdateState::Events => {
                 // Update operations
                 let new_ops_blocks = self.update_operations_state(interactor).await;
                 // Update tree
-                self.update_tree_state(interactor, new_ops_blocks).await;
+                self.update_tree_state(interactor, VecOpBlockSource::from(new_ops_blocks))
+                    .await;
             }
             StorageUpdateState::Operations => {
                 // Update operations
                 let new_ops_blocks = interactor.get_ops_blocks_from_storage().await;
                 // Update tree
-                self.update_tree_state(interactor, new_ops_blocks).await;
+                self.update_tree_state(interactor, VecOpBlockSource::from(new_ops_blocks))
+                    .await;
             }
             StorageUpdateState::None => {}
         }
@@ -237,11 +348,122 @@ where
         self.finite_mode && (total_verified_blocks == last_verified_block)
     }
 
-    /// Activates states updates
+    /// Completes the graceful shutdown once the state updates are stopped: the tree state
+    /// and the events scan cursor are saved into the snapshot, so the next start resumes
+    /// right after the last restored block with `resume_from_snapshot`, followed by
+    /// `recover_from_wal`. The write-ahead log is truncated as the snapshot covers it
+    ///
+    /// # Arguments
+    ///
+    /// * `snapshot_path` - Snapshot file path
+    ///
+    pub fn shutdown(&mut self, snapshot_path: impl AsRef<Path>) -> Result<(), anyhow::Error> {
+        self.stop_handle.stop();
+        save_snapshot_binary_with_events(&self.tree_state, &self.events_state, snapshot_path)?;
+        if let Some(wal) = &mut self.ops_blocks_wal {
+            wal.truncate()?;
+        }
+
+        log::info!(
+            "State saved on shutdown, last restored block: {}, last watched ethereum block: {}",
+            self.tree_state.state.block_number,
+            self.events_state.last_watched_eth_block_number
+        );
+        Ok(())
+    }
+
+    /// Loads the tree state and the events scan cursor from the snapshot saved on shutdown.
+    /// The state is loaded from storage instead if storage has newer blocks than the snapshot.
+    /// Returns true if the finite mode restore is completed, like `load_state_from_storage`
+    ///
+    /// # Arguments
+    ///
+    /// * `interactor` - Storage interactor
+    /// * `snapshot_path` - Snapshot file path
+    ///
+    pub async fn resume_from_snapshot(
+        &mut self,
+        interactor: &mut I,
+        snapshot_path: impl AsRef<Path>,
+    ) -> Result<bool, anyhow::Error> {
+        let (mut tree_state, events_state) = load_snapshot_binary_with_events(
+            snapshot_path,
+            self.available_block_chunk_sizes.clone(),
+        )?;
+        let mut events_state =
+            events_state.ok_or_else(|| format_err!("Snapshot has no events scan cursor"))?;
+
+        let stored_block_number = interactor.get_tree_state().await.last_block_number;
+        if tree_state.state.block_number < stored_block_number {
+            log::warn!(
+                "Snapshot is stale, last restored block: {}, last stored block: {}",
+                tree_state.state.block_number,
+                stored_block_number
+            );
+            return Ok(self.load_state_from_storage(interactor).await);
+        }
+
+        events_state.lenient_decoding = self.events_state.lenient_decoding;
+        tree_state.take_settings_from(&mut self.tree_state);
+        self.events_state = events_state;
+        self.tree_state = tree_state;
+
+        log::info!(
+            "State resumed from the snapshot, last restored block: {}, last watched ethereum block: {}",
+            self.tree_state.state.block_number,
+            self.events_state.last_watched_eth_block_number
+        );
+        let total_verified_blocks = get_total_verified_blocks(&self.zksync_contract).await;
+        Ok(self.finite_mode && (total_verified_blocks == self.tree_state.state.block_number))
+    }
+
+    /// Returns the handle that can be used to stop the running state updates
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop_handle.clone()
+    }
+
+    /// Checks that the web3 provider is connected to the expected Ethereum chain.
+    /// Does nothing if the expected chain id isn't set
+    pub async fn verify_chain_id(&self) -> Result<(), anyhow::Error> {
+        let expected_chain_id = match self.expected_chain_id {
+            Some(chain_id) => chain_id,
+            None => return Ok(()),
+        };
+        let network_version = self.web3.net().version().await?;
+        let chain_id: u64 = network_version
+            .parse()
+            .map_err(|_| format_err!("Invalid network version of the node: {}", network_version))?;
+        ensure!(
+            chain_id == expected_chain_id,
+            "The node is connected to the chain {}, while the chain {} is expected",
+            chain_id,
+            expected_chain_id
+        );
+        Ok(())
+    }
+
+    /// Returns the amount of the Ethereum blocks between the chain head
+    /// and the last Ethereum block watched by the restored state
+    pub async fn lag_behind_head(&self) -> Result<u64, anyhow::Error> {
+        let head_block_number = EventsState::get_last_block_number(&self.web3).await?;
+        Ok(head_block_number.saturating_sub(self.events_state.last_watched_eth_block_number))
+    }
+
+    /// Activates states updates.
+    /// Returns once all the verified blocks are restored in the finite mode or
+    /// once the stop is requested via the `StopHandle`.
     pub async fn run_state_update(&mut self, interactor: &mut I) {
         let mut last_watched_block: u64 = self.events_state.last_watched_eth_block_number;
         let mut final_hash_was_found = false;
         loop {
+            if self.stop_handle.is_stop_requested() {
+                log::info!(
+                    "Stopping the state update, last restored block: {}",
+                    self.tree_state.state.block_number
+                );
+                break;
+            }
+
             log::debug!("Last watched ethereum block: {:?}", last_watched_block);
 
             // Update events
@@ -251,7 +473,8 @@ where
 
                 if !new_ops_blocks.is_empty() {
                     // Update tree
-                    self.update_tree_state(interactor, new_ops_blocks).await;
+                    self.update_tree_state(interactor, VecOpBlockSource::from(new_ops_blocks))
+                        .await;
 
                     let total_verified_blocks =
                         get_total_verified_blocks(&self.zksync_contract).await;
@@ -296,14 +519,131 @@ where
                 }
             }
 
+            self.progress.record(
+                std::time::Instant::now(),
+                self.events_state.last_watched_eth_block_number,
+            );
+
             if last_watched_block == self.events_state.last_watched_eth_block_number {
-                std::thread::sleep(std::time::Duration::from_secs(5));
+                self.stop_handle.wait(self.poll_interval).await;
             } else {
                 last_watched_block = self.events_state.last_watched_eth_block_number;
             }
         }
     }
 
+    /// Restores the state as of the specified Ethereum block: the events are watched up to
+    /// this block only, so just the blocks verified at or before it are applied.
+    /// Fails if the events are already watched past the block or the chain hasn't reached it
+    ///
+    /// # Arguments
+    ///
+    /// * `interactor` - Storage of the restored state
+    /// * `eth_block_number` - Ethereum block number to restore the state at
+    ///
+    pub async fn restore_as_of(
+        &mut self,
+        interactor: &mut I,
+        eth_block_number: u64,
+    ) -> Result<(), anyhow::Error> {
+        ensure!(
+            self.events_state.last_watched_eth_block_number <= eth_block_number,
+            "Events are already watched up to the ethereum block {}, past the block {}",
+            self.events_state.last_watched_eth_block_number,
+            eth_block_number
+        );
+
+        self.events_state.eth_block_limit = Some(eth_block_number);
+        while self.events_state.last_watched_eth_block_number < eth_block_number {
+            let last_watched_block = self.events_state.last_watched_eth_block_number;
+            if self.update_events_state(interactor).await {
+                let new_ops_blocks = self.update_operations_state(interactor).await;
+                self.update_tree_state(interactor, VecOpBlockSource::from(new_ops_blocks))
+                    .await;
+            }
+
+            if self.events_state.last_watched_eth_block_number == last_watched_block {
+                self.events_state.eth_block_limit = None;
+                bail!(
+                    "Ethereum block {} is not reached yet, events are watched up to the block {}",
+                    eth_block_number,
+                    last_watched_block
+                );
+            }
+        }
+        self.events_state.eth_block_limit = None;
+
+        log::info!(
+            "State restored as of the ethereum block {}, last restored block: {}",
+            eth_block_number,
+            self.tree_state.state.block_number
+        );
+        Ok(())
+    }
+
+    /// Applies the operations blocks recorded in the write-ahead log but missing in the
+    /// tree state, e.g. the ones not saved in storage because of a crash, saves them and
+    /// truncates the log. Returns the amount of the recovered blocks.
+    /// The log is kept if a logged block fails, see `OpsBlocksWal::recover`
+    pub async fn recover_from_wal(&mut self, interactor: &mut I) -> Result<usize, anyhow::Error> {
+        let recovered = match &self.ops_blocks_wal {
+            Some(wal) => wal.recover(&mut self.tree_state)?,
+            None => return Ok(0),
+        };
+        let count = recovered.len();
+        for (block, acc_updates) in recovered {
+            interactor.update_tree_state(block, acc_updates).await;
+        }
+        if let Some(wal) = &mut self.ops_blocks_wal {
+            wal.truncate()?;
+        }
+
+        log::info!(
+            "Recovered {} blocks from the write-ahead log, last restored block: {}",
+            count,
+            self.tree_state.state.block_number
+        );
+        Ok(count)
+    }
+
+    /// Returns the raw logs of the Rollup or Governance contract event emitted in the ethereum
+    /// block with the specified hash, sorted in the order they are processed, for debugging
+    ///
+    /// # Arguments
+    ///
+    /// * `block_hash` - Ethereum block hash
+    /// * `event` - Event name declared in the Rollup or Governance contract ABI
+    ///
+    pub async fn raw_logs_for_batch(
+        &mut self,
+        block_hash: H256,
+        event: &str,
+    ) -> Result<Vec<Log>, anyhow::Error> {
+        let contract = if self.zksync_contract.0.event(event).is_ok() {
+            &self.zksync_contract
+        } else {
+            &self.governance_contract
+        };
+        self.events_state
+            .get_raw_logs_in_eth_block(&self.web3, contract, block_hash, event)
+            .await
+    }
+
+    /// Returns the web3 provider usage made to fetch the contracts events
+    pub fn rpc_usage(&self) -> RpcUsage {
+        self.events_state.rpc_usage
+    }
+
+    /// Returns the estimate of the remaining restore work based on the recent throughput
+    ///
+    /// # Arguments
+    ///
+    /// * `head_block` - The current Ethereum chain head block number
+    ///
+    pub fn estimate_remaining(&self, head_block: u64) -> RestoreEstimate {
+        self.progress.estimate(head_block)
+    }
+
     /// Updates events state, saves new blocks, tokens events and the last watched eth block number in storage
     /// Returns bool flag, true if there are new block events
     async fn update_events_state(&mut self, interactor: &mut I) -> bool {
@@ -313,6 +653,8 @@ where
                 &self.web3,
                 &self.zksync_contract,
                 &self.governance_contract,
+                &self.contract_topics,
+                self.event_decoder.as_ref(),
                 self.eth_blocks_step,
                 self.end_eth_blocks_offset,
             )
@@ -336,13 +678,25 @@ where
     ///
     /// # Arguments
     ///
-    /// * `new_ops_blocks` - the new Rollup operations blocks
+    /// * `new_ops_blocks` - Source of the new Rollup operations blocks
     ///
-    async fn update_tree_state(&mut self, interactor: &mut I, new_ops_blocks: Vec<RollupOpsBlock>) {
+    async fn update_tree_state(
+        &mut self,
+        interactor: &mut I,
+        mut new_ops_blocks: impl OpBlockSource,
+    ) {
         let mut blocks = vec![];
         let mut updates = vec![];
         let mut count = 0;
-        for op_block in new_ops_blocks {
+        while let Some(op_block) = new_ops_blocks
+            .next_op_block()
+            .await
+            .expect("Updating tree state: cant get operations block")
+        {
+            if let Some(wal) = &mut self.ops_blocks_wal {
+                wal.append(&op_block)
+                    .expect("Updating tree state: cant record operations block in the log");
+            }
             let (block, acc_updates) = self
                 .tree_state
                 .update_tree_states_from_ops_block(&op_block)
@@ -356,6 +710,11 @@ where
                 .update_tree_state(blocks[i].clone(), updates[i].clone())
                 .await;
         }
+        // The logged blocks are saved in storage, so there is nothing to replay.
+        if let Some(wal) = &mut self.ops_blocks_wal {
+            wal.truncate()
+                .expect("Updating tree state: cant truncate the operations blocks log");
+        }
 
         log::debug!("Updated state");
     }
@@ -374,19 +733,39 @@ where
 
     /// Returns verified comitted operations blocks from verified op blocks events
     pub async fn get_new_operation_blocks_from_events(&mut self) -> Vec<RollupOpsBlock> {
-        let mut blocks = Vec::new();
+        let mut source = EventsOpBlockSource::new(
+            &self.web3,
+            self.event_decoder.as_ref(),
+            self.events_state.get_only_verified_committed_events(),
+        );
+        collect_op_blocks(&mut source)
+            .await
+            .expect("Cant get new operation blocks from events")
+    }
 
-        for event in self
-            .events_state
-            .get_only_verified_committed_events()
-            .iter()
-        {
-            let block = RollupOpsBlock::get_rollup_ops_block(&self.web3, &event)
-                .await
-                .expect("Cant get new operation blocks from events");
-            blocks.push(block);
+    /// Returns the accounts with the deposits and full exits included in the committed
+    /// blocks that are not verified yet, i.e. the priority requests still in flight
+    /// as of the last watched ethereum block
+    pub async fn pending_priority_accounts(&self) -> Result<BTreeSet<AccountId>, anyhow::Error> {
+        let mut source = EventsOpBlockSource::new(
+            &self.web3,
+            self.event_decoder.as_ref(),
+            self.events_state.get_only_unverified_committed_events(),
+        );
+        let mut accounts = BTreeSet::new();
+        while let Some(block) = source.next_op_block().await? {
+            for op in &block.ops {
+                match op {
+                    ZkSyncOp::Deposit(op) => {
+                        accounts.insert(op.account_id);
+                    }
+                    ZkSyncOp::FullExit(op) => {
+                        accounts.insert(op.priority_op.account_id);
+                    }
+                    _ => {}
+                }
+            }
         }
-
-        blocks
+        Ok(accounts)
     }
 }
@@ -4,9 +4,10 @@ use std::str::FromStr;
 use zksync_crypto::proof::EncodedProofPlonk;
 use zksync_storage::{data_restore::records::NewBlockEvent, StorageProcessor};
 use zksync_types::{
-    Action, Operation, Token, TokenGenesisListItem, TokenId,
+    AccountId, Action, Operation, Token, TokenGenesisListItem, TokenId, TokenLike,
     {block::Block, AccountUpdate, AccountUpdates, ZkSyncOp},
 };
+use zksync_utils::format_units;
 
 // Local deps
 use crate::storage_interactor::StoredTreeState;
@@ -203,6 +204,11 @@ impl StorageInteractor for DatabaseStorageInteractor<'_> {
             committed_events,
             verified_events,
             last_watched_eth_block_number,
+            eth_blocks_window: None,
+            rpc_usage: Default::default(),
+            lenient_decoding: false,
+            decode_errors: Vec::new(),
+            eth_block_limit: None,
         }
     }
 
@@ -285,4 +291,42 @@ impl StorageInteractor for DatabaseStorageInteractor<'_> {
             _ => panic!("Unknown storage state"),
         }
     }
+
+    async fn set_token_decimals(&mut self, token_id: TokenId, decimals: u8) -> bool {
+        let token = self
+            .storage
+            .tokens_schema()
+            .get_token(TokenLike::Id(token_id))
+            .await
+            .expect("Cant load token");
+        match token {
+            Some(mut token) => {
+                token.decimals = decimals;
+                self.storage
+                    .tokens_schema()
+                    .store_token(token)
+                    .await
+                    .expect("failed to store token");
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn get_formatted_balance(&mut self, id: AccountId, token_id: TokenId) -> Option<String> {
+        let token = self
+            .storage
+            .tokens_schema()
+            .get_token(TokenLike::Id(token_id))
+            .await
+            .expect("Cant load token")?;
+        let account = self
+            .storage
+            .chain()
+            .account_schema()
+            .last_committed_state_for_account(id)
+            .await
+            .expect("Cant load account")?;
+        Some(format_units(account.get_balance(token_id), token.decimals))
+    }
 }
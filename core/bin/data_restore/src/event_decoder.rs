@@ -0,0 +1,216 @@
+// External deps
+use web3::types::Log;
+// Local deps
+use crate::{
+    events_state::{NewTokenEvent, TopicIndices},
+    rollup_ops::{RollupOpsBlock, UnknownOp},
+};
+
+/// Decoder of the data emitted by the Rollup and Governance contracts.
+///
+/// The default implementations decode the data of the upstream contracts, so the decoder
+/// for a forked contract only has to override the methods for the changed data layouts.
+pub trait EventDecoder: Send + Sync {
+    /// Returns the Rollup operations block decoded from the `commitBlock` call input data
+    /// (without the function selector)
+    ///
+    /// # Arguments
+    ///
+    /// * `block_num` - Rollup block number
+    /// * `input_data` - Encoded call parameters
+    ///
+    fn decode_ops_block(
+        &self,
+        block_num: u32,
+        input_data: &[u8],
+    ) -> Result<RollupOpsBlock, anyhow::Error> {
+        RollupOpsBlock::from_commit_input_data_with_unknown_ops(
+            block_num,
+            input_data,
+            |op_type, data| self.decode_unknown_op(op_type, data),
+        )
+    }
+
+    /// Returns the operation of the type unknown to the data restore decoded from
+    /// the block public data, or the length of its public data to skip it.
+    /// Unknown operations are rejected by default
+    ///
+    /// # Arguments
+    ///
+    /// * `op_type` - Operation type
+    /// * `data` - Block public data starting with the operation
+    ///
+    fn decode_unknown_op(&self, op_type: u8, _data: &[u8]) -> Result<UnknownOp, anyhow::Error> {
+        Err(anyhow::format_err!("Wrong operation type: {}", op_type))
+    }
+
+    /// Returns the token addition event decoded from the `NewToken` event log
+    ///
+    /// # Arguments
+    ///
+    /// * `log` - `NewToken` event log
+    /// * `topic_indices` - Positions of the indexed event fields in the log topics
+    ///
+    fn decode_new_token(
+        &self,
+        log: Log,
+        topic_indices: &TopicIndices,
+    ) -> Result<NewTokenEvent, anyhow::Error> {
+        NewTokenEvent::from_log(log, topic_indices)
+    }
+}
+
+/// Decoder of the upstream contracts data
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultEventDecoder;
+
+impl EventDecoder for DefaultEventDecoder {}
+
+#[cfg(test)]
+mod test {
+    use super::{DefaultEventDecoder, EventDecoder};
+    use crate::rollup_ops::{RollupOpsBlock, UnknownOp};
+    use ethabi::{ParamType, Token};
+    use std::sync::Mutex;
+    use zksync_crypto::params::CHUNK_BYTES;
+    use zksync_types::operations::NoopOp;
+    use zksync_types::{Deposit, DepositOp, ZkSyncOp};
+
+    /// Decoder of the forked contract with the chain id added as the first `commitBlock` parameter
+    struct ChainIdEventDecoder {
+        chain_id: u32,
+    }
+
+    impl EventDecoder for ChainIdEventDecoder {
+        fn decode_ops_block(
+            &self,
+            block_num: u32,
+            input_data: &[u8],
+        ) -> Result<RollupOpsBlock, anyhow::Error> {
+            let tokens = ethabi::decode(
+                &[
+                    ParamType::Uint(32),
+                    ParamType::Uint(32),
+                    ParamType::Uint(32),
+                    ParamType::Array(Box::new(ParamType::FixedBytes(32))),
+                    ParamType::Bytes,
+                    ParamType::Bytes,
+                    ParamType::Array(Box::new(ParamType::Uint(32))),
+                ],
+                input_data,
+            )
+            .map_err(|_| anyhow::format_err!("Can't decode commitment parameters"))?;
+            match (&tokens[0], &tokens[2], &tokens[4]) {
+                (Token::Uint(chain_id), Token::Uint(fee_account), Token::Bytes(public_data)) => {
+                    anyhow::ensure!(
+                        chain_id.as_u32() == self.chain_id,
+                        "Block is committed to the other chain"
+                    );
+                    Ok(RollupOpsBlock {
+                        block_num,
+                        ops: RollupOpsBlock::get_rollup_ops_from_data(public_data)?,
+                        fee_account: fee_account.as_u32(),
+                    })
+                }
+                _ => anyhow::bail!("Can't parse commitment parameters"),
+            }
+        }
+    }
+
+    /// Decoder skipping the operations of the type added by the forked contract
+    #[derive(Default)]
+    struct CustomOpEventDecoder {
+        skipped_ops: Mutex<Vec<u8>>,
+    }
+
+    impl CustomOpEventDecoder {
+        const CUSTOM_OP_TYPE: u8 = 0xf0;
+        const CUSTOM_OP_CHUNKS: usize = 2;
+    }
+
+    impl EventDecoder for CustomOpEventDecoder {
+        fn decode_unknown_op(&self, op_type: u8, _data: &[u8]) -> Result<UnknownOp, anyhow::Error> {
+            anyhow::ensure!(
+                op_type == Self::CUSTOM_OP_TYPE,
+                "Wrong operation type: {}",
+                op_type
+            );
+            self.skipped_ops.lock().unwrap().push(op_type);
+            Ok(UnknownOp::Skipped(Self::CUSTOM_OP_CHUNKS * CHUNK_BYTES))
+        }
+    }
+
+    #[test]
+    fn unknown_ops_decoder() {
+        let deposit = ZkSyncOp::Deposit(Box::new(DepositOp {
+            priority_op: Deposit {
+                from: [1u8; 20].into(),
+                token: 1,
+                amount: 10u32.into(),
+                to: [7u8; 20].into(),
+            },
+            account_id: 6,
+        }));
+        let mut custom_op = vec![0u8; CustomOpEventDecoder::CUSTOM_OP_CHUNKS * CHUNK_BYTES];
+        custom_op[0] = CustomOpEventDecoder::CUSTOM_OP_TYPE;
+        let mut public_data = deposit.public_data();
+        public_data.extend(&custom_op);
+        public_data.extend(ZkSyncOp::Noop(NoopOp {}).public_data());
+        let input_data = ethabi::encode(&[
+            Token::Uint(3.into()),
+            Token::Uint(2.into()),
+            Token::Array(vec![Token::FixedBytes(vec![0u8; 32])]),
+            Token::Bytes(public_data),
+            Token::Bytes(vec![]),
+            Token::Array(vec![]),
+        ]);
+
+        assert!(DefaultEventDecoder
+            .decode_ops_block(3, &input_data)
+            .is_err());
+
+        let decoder = CustomOpEventDecoder::default();
+        let block = decoder
+            .decode_ops_block(3, &input_data)
+            .expect("Custom operation must be skipped");
+        assert_eq!(
+            *decoder.skipped_ops.lock().unwrap(),
+            vec![CustomOpEventDecoder::CUSTOM_OP_TYPE]
+        );
+        assert_eq!(block.ops.len(), 2);
+        assert_eq!(block.ops[0].public_data(), deposit.public_data());
+        assert!(matches!(block.ops[1], ZkSyncOp::Noop(_)));
+    }
+
+    #[test]
+    fn custom_ops_block_decoder() {
+        let deposit = ZkSyncOp::Deposit(Box::new(DepositOp {
+            priority_op: Deposit {
+                from: [1u8; 20].into(),
+                token: 1,
+                amount: 10u32.into(),
+                to: [7u8; 20].into(),
+            },
+            account_id: 6,
+        }));
+        let input_data = ethabi::encode(&[
+            Token::Uint(5.into()),
+            Token::Uint(3.into()),
+            Token::Uint(2.into()),
+            Token::Array(vec![Token::FixedBytes(vec![0u8; 32])]),
+            Token::Bytes(deposit.public_data()),
+            Token::Bytes(vec![]),
+            Token::Array(vec![]),
+        ]);
+
+        let block = ChainIdEventDecoder { chain_id: 5 }
+            .decode_ops_block(3, &input_data)
+            .expect("Custom decoder must parse the extended input data");
+        assert_eq!(block.fee_account, 2);
+        assert_eq!(block.recompute_public_data(), deposit.public_data());
+
+        assert!(ChainIdEventDecoder { chain_id: 1 }
+            .decode_ops_block(3, &input_data)
+            .is_err());
+    }
+}
@@ -7,29 +7,160 @@ use web3::types::{BlockNumber, FilterBuilder, Log, H256, U256};
 use web3::{Transport, Web3};
 // Workspace deps
 use crate::eth_tx_helpers::get_block_number_from_ethereum_transaction;
+use crate::event_decoder::EventDecoder;
 use crate::events::{BlockEvent, EventType};
 use zksync_types::{Address, TokenId};
 
+/// If a blocks window contains less logs than this value, the window is considered sparse
+/// and it is doubled for the next request
+const SPARSE_WINDOW_LOGS_COUNT: usize = 100;
+
 #[derive(Debug)]
 pub struct NewTokenEvent {
     pub address: Address,
     pub id: TokenId,
+    /// Hash of the Ethereum transaction that added the token
+    pub transaction_hash: Option<H256>,
+}
+
+impl NewTokenEvent {
+    /// Returns the event decoded from the `NewToken` event log
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - `NewToken` event log
+    /// * `topic_indices` - Positions of the indexed event fields in the log topics
+    ///
+    pub fn from_log(event: Log, topic_indices: &TopicIndices) -> Result<Self, anyhow::Error> {
+        let topics_count = topic_indices.token_address.max(topic_indices.token_id) + 1;
+        if event.topics.len() < topics_count {
+            return Err(format_err!(
+                "Failed to parse NewTokenEvent, expected {} topics, got {}: {:#?}",
+                topics_count,
+                event.topics.len(),
+                event
+            ));
+        }
+        let address_topic = event.topics[topic_indices.token_address];
+        let id_topic = event.topics[topic_indices.token_id];
+        Ok(NewTokenEvent {
+            address: Address::from_slice(&address_topic.as_fixed_bytes()[12..]),
+            id: U256::from_big_endian(&id_topic.as_fixed_bytes()[..]).as_u32() as u16,
+            transaction_hash: event.transaction_hash,
+        })
+    }
 }
 
 impl TryFrom<Log> for NewTokenEvent {
     type Error = anyhow::Error;
 
     fn try_from(event: Log) -> Result<NewTokenEvent, anyhow::Error> {
-        if event.topics.len() != 3 {
-            return Err(format_err!("Failed to parse NewTokenEvent: {:#?}", event));
+        Self::from_log(event, &TopicIndices::default())
+    }
+}
+
+/// Positions of the indexed events fields in the log topics.
+/// The first topic is always the event signature, the defaults match the upstream contracts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopicIndices {
+    /// Block number of the `BlockCommit` and `BlockVerification` events
+    pub block_number: usize,
+    /// Token address of the `NewToken` event
+    pub token_address: usize,
+    /// Token id of the `NewToken` event
+    pub token_id: usize,
+}
+
+impl Default for TopicIndices {
+    fn default() -> Self {
+        Self {
+            block_number: 1,
+            token_address: 1,
+            token_id: 2,
         }
-        Ok(NewTokenEvent {
-            address: Address::from_slice(&event.topics[1].as_fixed_bytes()[12..]),
-            id: U256::from_big_endian(&event.topics[2].as_fixed_bytes()[..]).as_u32() as u16,
-        })
     }
 }
 
+/// Signatures of the contracts events watched by the data restore.
+/// Each logical event may have several accepted signatures, so the state can be restored
+/// across the contract upgrades that change the event layout. All the versions of the
+/// event must keep its indexed fields at the same topics positions.
+#[derive(Debug, Clone)]
+pub struct ContractTopics {
+    /// `BlockVerification` event signatures
+    pub block_verified: Vec<H256>,
+    /// `BlockCommit` event signatures
+    pub block_committed: Vec<H256>,
+    /// `BlocksRevert` event signatures
+    pub blocks_reverted: Vec<H256>,
+    /// `NewToken` event signatures
+    pub new_token: Vec<H256>,
+    /// Positions of the indexed events fields, may be changed for the differently indexed contracts
+    pub topic_indices: TopicIndices,
+}
+
+impl ContractTopics {
+    /// Returns the event signatures declared in the contracts ABIs
+    ///
+    /// # Arguments
+    ///
+    /// * `zksync_contract` - Rollup contract ABI
+    /// * `governance_contract` - Governance contract ABI
+    ///
+    pub fn new(zksync_contract: &ethabi::Contract, governance_contract: &ethabi::Contract) -> Self {
+        let signature = |contract: &ethabi::Contract, name: &str| {
+            contract
+                .event(name)
+                .unwrap_or_else(|_| panic!("Contract abi error: no {} event", name))
+                .signature()
+        };
+
+        Self {
+            block_verified: vec![signature(zksync_contract, "BlockVerification")],
+            block_committed: vec![signature(zksync_contract, "BlockCommit")],
+            blocks_reverted: vec![signature(zksync_contract, "BlocksRevert")],
+            new_token: vec![signature(governance_contract, "NewToken")],
+            topic_indices: TopicIndices::default(),
+        }
+    }
+
+    /// Returns signatures of all the Rollup contract block events
+    pub fn block_events(&self) -> Vec<H256> {
+        self.block_verified
+            .iter()
+            .chain(&self.block_committed)
+            .chain(&self.blocks_reverted)
+            .copied()
+            .collect()
+    }
+}
+
+/// Usage of the web3 provider made to fetch the contracts events
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RpcUsage {
+    /// Amount of the `eth_getLogs` requests, including the failed ones
+    pub get_logs_calls: u64,
+    /// Total size of the fetched logs, in bytes of their JSON representation
+    pub fetched_bytes: u64,
+}
+
+impl RpcUsage {
+    /// Records the logs fetched by the `eth_getLogs` request
+    fn add_fetched_logs(&mut self, logs: &[Log]) {
+        let size = serde_json::to_vec(logs).map_or(0, |logs| logs.len());
+        self.fetched_bytes += size as u64;
+    }
+}
+
+/// Event log that failed to be decoded in the lenient decoding mode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    /// Description of the failed log
+    pub context: String,
+    /// Decoding error message
+    pub error: String,
+}
+
 /// Rollup contract events states description
 #[derive(Debug, Clone)]
 pub struct EventsState {
@@ -39,6 +170,18 @@ pub struct EventsState {
     pub verified_events: Vec<BlockEvent>,
     /// Last watched ethereum block number
     pub last_watched_eth_block_number: u64,
+    /// Current size of the watched blocks window, adapted to the web3 provider limits.
+    /// Never exceeds the configured blocks step, `None` until the first request
+    pub eth_blocks_window: Option<u64>,
+    /// Web3 provider usage made by this events state
+    pub rpc_usage: RpcUsage,
+    /// Lenient decoding flag. In lenient mode, the token events failed to be decoded
+    /// are skipped and collected into `decode_errors` instead of aborting the update.
+    pub lenient_decoding: bool,
+    /// Token events failed to be decoded in the lenient mode
+    pub decode_errors: Vec<DecodeError>,
+    /// Ethereum block number the events are watched up to, if it differs from the chain head
+    pub eth_block_limit: Option<u64>,
 }
 
 impl std::default::Default for EventsState {
@@ -48,6 +191,11 @@ impl std::default::Default for EventsState {
             committed_events: Vec::new(),
             verified_events: Vec::new(),
             last_watched_eth_block_number: 0,
+            eth_blocks_window: None,
+            rpc_usage: RpcUsage::default(),
+            lenient_decoding: false,
+            decode_errors: Vec::new(),
+            eth_block_limit: None,
         }
     }
 }
@@ -78,25 +226,31 @@ impl EventsState {
     /// * `web3` - Web3 provider url
     /// * `zksync_contract` - Rollup contract
     /// * `governance_contract` - Governance contract
+    /// * `contract_topics` - Signatures of the watched events
+    /// * `event_decoder` - Decoder of the watched events
     /// * `eth_blocks_step` - Blocks step for watching
     /// * `end_eth_blocks_offset` - Delta between last eth block and last watched block
     ///
+    #[allow(clippy::too_many_arguments)]
     pub async fn update_events_state<T: Transport>(
         &mut self,
         web3: &Web3<T>,
         zksync_contract: &(ethabi::Contract, Contract<T>),
         governance_contract: &(ethabi::Contract, Contract<T>),
+        contract_topics: &ContractTopics,
+        event_decoder: &dyn EventDecoder,
         eth_blocks_step: u64,
         end_eth_blocks_offset: u64,
     ) -> Result<(Vec<BlockEvent>, Vec<NewTokenEvent>, u64), anyhow::Error> {
         self.remove_verified_events();
 
         let (block_events, token_events, to_block_number): (Vec<Log>, Vec<NewTokenEvent>, u64) =
-            EventsState::get_new_events_and_last_watched_block(
+            self.get_new_events_and_last_watched_block(
                 web3,
                 zksync_contract,
                 governance_contract,
-                self.last_watched_eth_block_number,
+                contract_topics,
+                event_decoder,
                 eth_blocks_step,
                 end_eth_blocks_offset,
             )
@@ -104,7 +258,7 @@ impl EventsState {
 
         self.last_watched_eth_block_number = to_block_number;
 
-        if !self.update_blocks_state(zksync_contract, &block_events) {
+        if !self.update_blocks_state(contract_topics, &block_events)? {
             return Ok((vec![], token_events, self.last_watched_eth_block_number));
         }
 
@@ -128,147 +282,282 @@ impl EventsState {
         Ok(web3.eth().block_number().await.map(|n| n.as_u64())?)
     }
 
-    /// Returns blocks logs, added token logs and the new last watched block number
+    /// Returns the committed and verified blocks events emitted in the ethereum block
+    /// with the specified hash. Unlike the blocks numbers range, the block hash pins
+    /// the exact block, so the events of the reorged out blocks can't be returned
     ///
     /// # Arguments
     ///
     /// * `web3` - Web3 provider url
     /// * `zksync_contract` - Rollup contract
-    /// * `governance_contract` - Governance contract
-    /// * `last_watched_block_number` - the current last watched eth block
-    /// * `eth_blocks_step` - Ethereum blocks delta step
-    /// * `end_eth_blocks_offset` - last block delta
+    /// * `contract_topics` - Signatures of the watched events
+    /// * `block_hash` - Ethereum block hash
     ///
-    async fn get_new_events_and_last_watched_block<T: Transport>(
+    pub async fn get_block_events_in_eth_block<T: Transport>(
+        &mut self,
         web3: &Web3<T>,
         zksync_contract: &(ethabi::Contract, Contract<T>),
-        governance_contract: &(ethabi::Contract, Contract<T>),
-        last_watched_block_number: u64,
-        eth_blocks_step: u64,
-        end_eth_blocks_offset: u64,
-    ) -> Result<(Vec<Log>, Vec<NewTokenEvent>, u64), anyhow::Error> {
-        let latest_eth_block_minus_delta =
-            EventsState::get_last_block_number(web3).await? - end_eth_blocks_offset;
+        contract_topics: &ContractTopics,
+        block_hash: H256,
+    ) -> Result<Vec<BlockEvent>, anyhow::Error> {
+        let topics_vec: Vec<H256> = contract_topics
+            .block_committed
+            .iter()
+            .chain(&contract_topics.block_verified)
+            .copied()
+            .collect();
 
-        if latest_eth_block_minus_delta == last_watched_block_number {
-            return Ok((vec![], vec![], last_watched_block_number)); // No new eth blocks
-        }
-
-        let from_block_number_u64 = last_watched_block_number + 1;
-
-        let to_block_number_u64 =
-        // if (latest eth block < last watched + delta) then choose it
-        if from_block_number_u64 + eth_blocks_step >= latest_eth_block_minus_delta {
-            latest_eth_block_minus_delta
-        } else {
-            from_block_number_u64 + eth_blocks_step
-        };
-
-        let to_block_number = BlockNumber::Number(to_block_number_u64.into());
-
-        let from_block_number = BlockNumber::Number(from_block_number_u64.into());
-
-        let block_logs =
-            EventsState::get_block_logs(web3, zksync_contract, from_block_number, to_block_number)
-                .await?;
+        let filter = FilterBuilder::default()
+            .address(vec![zksync_contract.1.address()])
+            .block_hash(block_hash)
+            .topics(Some(topics_vec), None, None, None)
+            .build();
 
-        let token_logs = EventsState::get_token_added_logs(
-            web3,
-            governance_contract,
-            from_block_number,
-            to_block_number,
-        )
-        .await?;
+        self.rpc_usage.get_logs_calls += 1;
+        let logs = web3
+            .eth()
+            .logs(filter)
+            .await
+            .map_err(|e| format_err!("No logs in block {:?}: {}", block_hash, e))?;
+        self.rpc_usage.add_fetched_logs(&logs);
 
-        Ok((block_logs, token_logs, to_block_number_u64))
+        logs.iter()
+            .map(|log| {
+                let topic = log
+                    .topics
+                    .first()
+                    .ok_or_else(|| format_err!("There is no topic in block event: {:?}", log))?;
+                let block_type = if contract_topics.block_verified.contains(topic) {
+                    EventType::Verified
+                } else {
+                    EventType::Committed
+                };
+                decode_block_event_log(log, block_type, &contract_topics.topic_indices)
+            })
+            .collect()
     }
 
-    /// Returns new added token logs
+    /// Returns the raw logs of the contract event emitted in the ethereum block with
+    /// the specified hash, sorted in the order they appear on chain as they are processed.
+    /// Intended for inspecting what the node returned before the logs are decoded
     ///
     /// # Arguments
     ///
     /// * `web3` - Web3 provider url
-    /// * `contract` - Governance contract
-    /// * `from` - From ethereum block number
-    /// * `to` - To ethereum block number
+    /// * `contract` - Contract emitting the event
+    /// * `block_hash` - Ethereum block hash
+    /// * `event` - Event name declared in the contract ABI
     ///
-    async fn get_token_added_logs<T: Transport>(
+    pub async fn get_raw_logs_in_eth_block<T: Transport>(
+        &mut self,
         web3: &Web3<T>,
         contract: &(ethabi::Contract, Contract<T>),
-        from: BlockNumber,
-        to: BlockNumber,
-    ) -> Result<Vec<NewTokenEvent>, anyhow::Error> {
-        let new_token_event_topic = contract
+        block_hash: H256,
+        event: &str,
+    ) -> Result<Vec<Log>, anyhow::Error> {
+        let topic = contract
             .0
-            .event("NewToken")
-            .expect("Governance contract abi error")
+            .event(event)
+            .map_err(|e| format_err!("Unknown contract event {}: {}", event, e))?
             .signature();
+
         let filter = FilterBuilder::default()
             .address(vec![contract.1.address()])
-            .from_block(from)
-            .to_block(to)
-            .topics(Some(vec![new_token_event_topic]), None, None, None)
+            .block_hash(block_hash)
+            .topics(Some(vec![topic]), None, None, None)
             .build();
 
-        web3.eth()
+        self.rpc_usage.get_logs_calls += 1;
+        let mut logs = web3
+            .eth()
             .logs(filter)
-            .await?
-            .into_iter()
-            .map(|event| {
-                NewTokenEvent::try_from(event)
-                    .map_err(|e| format_err!("Failed to parse NewToken event log from ETH: {}", e))
-            })
-            .collect()
+            .await
+            .map_err(|e| format_err!("No logs in block {:?}: {}", block_hash, e))?;
+        self.rpc_usage.add_fetched_logs(&logs);
+
+        sort_logs(&mut logs);
+        Ok(logs)
+    }
+
+    /// Returns the token events decoded from the `NewToken` event logs.
+    /// In lenient mode, the logs failed to be decoded are skipped and collected into
+    /// the decode errors, otherwise the first such log fails the decoding
+    ///
+    /// # Arguments
+    ///
+    /// * `token_logs` - `NewToken` event logs
+    /// * `event_decoder` - Decoder of the contracts events
+    /// * `topic_indices` - Positions of the indexed events fields
+    ///
+    fn decode_token_events(
+        &mut self,
+        token_logs: Vec<Log>,
+        event_decoder: &dyn EventDecoder,
+        topic_indices: &TopicIndices,
+    ) -> Result<Vec<NewTokenEvent>, anyhow::Error> {
+        let mut token_events = Vec::with_capacity(token_logs.len());
+        for log in token_logs {
+            let context = format!(
+                "NewToken event log {:?} of the transaction {:?}",
+                log.log_index, log.transaction_hash
+            );
+            match event_decoder.decode_new_token(log, topic_indices) {
+                Ok(event) => token_events.push(event),
+                Err(e) if self.lenient_decoding => {
+                    log::warn!("Skipping the {}: {}", context, e);
+                    self.decode_errors.push(DecodeError {
+                        context,
+                        error: e.to_string(),
+                    });
+                }
+                Err(e) => {
+                    return Err(format_err!(
+                        "Failed to parse NewToken event log from ETH: {}",
+                        e
+                    ))
+                }
+            }
+        }
+        Ok(token_events)
     }
 
-    /// Returns the contract logs that occurred on the specified blocks
+    /// Returns blocks logs, added token logs and the new last watched block number.
+    /// Logs of both contracts are fetched by a single request and separated by their topics.
+    ///
+    /// The watched blocks window is halved and the request is retried if the web3 provider
+    /// rejects it because of too many results, and doubled after the sparse windows
+    /// fetched without halving
     ///
     /// # Arguments
     ///
     /// * `web3` - Web3 provider url
-    /// * `contract` - Specified contract
+    /// * `zksync_contract` - Rollup contract
+    /// * `governance_contract` - Governance contract
+    /// * `contract_topics` - Signatures of the watched events
+    /// * `event_decoder` - Decoder of the watched events
+    /// * `eth_blocks_step` - Maximum ethereum blocks delta step
+    /// * `end_eth_blocks_offset` - last block delta
+    ///
+    #[allow(clippy::too_many_arguments)]
+    async fn get_new_events_and_last_watched_block<T: Transport>(
+        &mut self,
+        web3: &Web3<T>,
+        zksync_contract: &(ethabi::Contract, Contract<T>),
+        governance_contract: &(ethabi::Contract, Contract<T>),
+        contract_topics: &ContractTopics,
+        event_decoder: &dyn EventDecoder,
+        eth_blocks_step: u64,
+        end_eth_blocks_offset: u64,
+    ) -> Result<(Vec<Log>, Vec<NewTokenEvent>, u64), anyhow::Error> {
+        let last_watched_block_number = self.last_watched_eth_block_number;
+        let mut latest_eth_block_minus_delta =
+            EventsState::get_last_block_number(web3).await? - end_eth_blocks_offset;
+        if let Some(eth_block_limit) = self.eth_block_limit {
+            latest_eth_block_minus_delta = latest_eth_block_minus_delta.min(eth_block_limit);
+        }
+
+        if latest_eth_block_minus_delta == last_watched_block_number {
+            return Ok((vec![], vec![], last_watched_block_number)); // No new eth blocks
+        }
+
+        let from_block_number_u64 = last_watched_block_number + 1;
+        let mut eth_blocks_window = self
+            .eth_blocks_window
+            .unwrap_or(eth_blocks_step)
+            .min(eth_blocks_step)
+            .max(1);
+        let mut window_reduced = false;
+
+        let (logs, to_block_number_u64) = loop {
+            let to_block_number_u64 =
+            // if (latest eth block < last watched + delta) then choose it
+            if from_block_number_u64 + eth_blocks_window >= latest_eth_block_minus_delta {
+                latest_eth_block_minus_delta
+            } else {
+                from_block_number_u64 + eth_blocks_window
+            };
+
+            match EventsState::get_contracts_logs(
+                web3,
+                zksync_contract,
+                governance_contract,
+                contract_topics,
+                &mut self.rpc_usage,
+                BlockNumber::Number(from_block_number_u64.into()),
+                BlockNumber::Number(to_block_number_u64.into()),
+            )
+            .await
+            {
+                Ok(logs) => break (logs, to_block_number_u64),
+                Err(e) if eth_blocks_window > 1 && is_logs_limit_error(&e) => {
+                    eth_blocks_window /= 2;
+                    window_reduced = true;
+                    log::warn!(
+                        "Too many logs returned, reducing the blocks window to {}: {}",
+                        eth_blocks_window,
+                        e
+                    );
+                }
+                Err(e) => return Err(format_err!("No new logs: {}", e)),
+            }
+        };
+
+        // The window that was just reduced isn't grown back, otherwise it would exceed
+        // the provider limit again on the next request.
+        if !window_reduced && logs.len() < SPARSE_WINDOW_LOGS_COUNT {
+            eth_blocks_window = (eth_blocks_window * 2).min(eth_blocks_step);
+        }
+        self.eth_blocks_window = Some(eth_blocks_window);
+
+        let (token_logs, block_logs): (Vec<Log>, Vec<Log>) = logs.into_iter().partition(|log| {
+            log.topics
+                .first()
+                .map_or(false, |topic| contract_topics.new_token.contains(topic))
+        });
+        let token_events =
+            self.decode_token_events(token_logs, event_decoder, &contract_topics.topic_indices)?;
+
+        Ok((block_logs, token_events, to_block_number_u64))
+    }
+
+    /// Returns the block events logs of the Rollup contract and the `NewToken` logs
+    /// of the Governance contract that occurred on the specified blocks
+    ///
+    /// # Arguments
+    ///
+    /// * `web3` - Web3 provider url
+    /// * `zksync_contract` - Rollup contract
+    /// * `governance_contract` - Governance contract
+    /// * `contract_topics` - Signatures of the watched events
+    /// * `rpc_usage` - Web3 provider usage to be updated
     /// * `from_block_number` - Start ethereum block number
     /// * `to_block_number` - End ethereum block number
     ///
-    async fn get_block_logs<T: Transport>(
+    async fn get_contracts_logs<T: Transport>(
         web3: &Web3<T>,
-        contract: &(ethabi::Contract, Contract<T>),
+        zksync_contract: &(ethabi::Contract, Contract<T>),
+        governance_contract: &(ethabi::Contract, Contract<T>),
+        contract_topics: &ContractTopics,
+        rpc_usage: &mut RpcUsage,
         from_block_number: BlockNumber,
         to_block_number: BlockNumber,
-    ) -> Result<Vec<Log>, anyhow::Error> {
-        let block_verified_topic = contract
-            .0
-            .event("BlockVerification")
-            .expect("Main contract abi error")
-            .signature();
-
-        let block_comitted_topic = contract
-            .0
-            .event("BlockCommit")
-            .expect("Main contract abi error")
-            .signature();
-
-        let reverted_topic = contract
-            .0
-            .event("BlocksRevert")
-            .expect("Main contract abi error")
-            .signature();
-
-        let topics_vec: Vec<H256> =
-            vec![block_verified_topic, block_comitted_topic, reverted_topic];
+    ) -> Result<Vec<Log>, web3::Error> {
+        let mut topics_vec: Vec<H256> = contract_topics.block_events();
+        topics_vec.extend(contract_topics.new_token.iter().cloned());
 
         let filter = FilterBuilder::default()
-            .address(vec![contract.1.address()])
+            .address(vec![
+                zksync_contract.1.address(),
+                governance_contract.1.address(),
+            ])
             .from_block(from_block_number)
             .to_block(to_block_number)
             .topics(Some(topics_vec), None, None, None)
             .build();
 
-        let result = web3
-            .eth()
-            .logs(filter)
-            .await
-            .map_err(|e| format_err!("No new logs: {}", e))?;
+        rpc_usage.get_logs_calls += 1;
+        let result = web3.eth().logs(filter).await?;
+        rpc_usage.add_fetched_logs(&result);
 
         Ok(result)
     }
@@ -278,44 +567,29 @@ impl EventsState {
     ///
     /// # Arguments
     ///
-    /// * `contract` - Specified contract
+    /// * `contract_topics` - Signatures of the watched events
     /// * `logs` - Block events with their info
     ///
-    fn update_blocks_state<T: Transport>(
+    fn update_blocks_state(
         &mut self,
-        contract: &(ethabi::Contract, Contract<T>),
+        contract_topics: &ContractTopics,
         logs: &[Log],
-    ) -> bool {
+    ) -> Result<bool, anyhow::Error> {
         if logs.is_empty() {
-            return false;
+            return Ok(false);
         }
 
-        let block_verified_topic = contract
-            .0
-            .event("BlockVerification")
-            .expect("Main contract abi error")
-            .signature();
-        let block_comitted_topic = contract
-            .0
-            .event("BlockCommit")
-            .expect("Main contract abi error")
-            .signature();
-        let reverted_topic = contract
-            .0
-            .event("BlocksRevert")
-            .expect("Main contract abi error")
-            .signature();
+        // Logs are processed in the order they appear on chain, so the resulting state
+        // doesn't depend on the order in which the node returned them.
+        let mut logs = logs.to_vec();
+        sort_logs(&mut logs);
 
         for log in logs {
             let topic = log.topics[0];
 
             // Remove reverted committed blocks first
-            if topic == reverted_topic {
-                const U256_SIZE: usize = 32;
-                // Fields in `BlocksRevert` are not `indexed`, thus they're located in `data`.
-                assert_eq!(log.data.0.len(), U256_SIZE * 2);
-                let total_verified = U256::from_big_endian(&log.data.0[..U256_SIZE]).as_u32();
-                let total_committed = U256::from_big_endian(&log.data.0[U256_SIZE..]).as_u32();
+            if contract_topics.blocks_reverted.contains(&topic) {
+                let (total_verified, total_committed) = decode_blocks_revert_log(&log)?;
 
                 self.committed_events
                     .retain(|bl| bl.block_num <= total_committed);
@@ -326,28 +600,23 @@ impl EventsState {
             }
 
             // Go into new blocks
-            let mut block: BlockEvent = BlockEvent {
-                block_num: 0,
-                transaction_hash: H256::zero(),
-                block_type: EventType::Committed,
-            };
-
-            let tx_hash = log
-                .transaction_hash
-                .expect("There are no tx hash in block event");
-            let block_num = log.topics[1];
-
-            block.block_num = U256::from(block_num.as_bytes()).as_u32();
-            block.transaction_hash = tx_hash;
-
-            if topic == block_verified_topic {
-                block.block_type = EventType::Verified;
+            if contract_topics.block_verified.contains(&topic) {
+                let block = decode_block_event_log(
+                    &log,
+                    EventType::Verified,
+                    &contract_topics.topic_indices,
+                )?;
                 self.verified_events.push(block);
-            } else if topic == block_comitted_topic {
+            } else if contract_topics.block_committed.contains(&topic) {
+                let block = decode_block_event_log(
+                    &log,
+                    EventType::Committed,
+                    &contract_topics.topic_indices,
+                )?;
                 self.committed_events.push(block);
             }
         }
-        true
+        Ok(true)
     }
 
     /// Removes verified committed blocks events and all verified
@@ -362,44 +631,266 @@ impl EventsState {
         let count_to_get = self.verified_events.len();
         self.committed_events[0..count_to_get].to_vec()
     }
+
+    /// Returns only committed blocks events that are not verified yet
+    pub fn get_only_unverified_committed_events(&self) -> Vec<BlockEvent> {
+        let count_to_skip = self.verified_events.len();
+        self.committed_events[count_to_skip..].to_vec()
+    }
+}
+
+/// Sorts the logs in the order they appear on chain
+fn sort_logs(logs: &mut [Log]) {
+    logs.sort_by_key(|log| (log.block_number, log.transaction_index, log.log_index));
+}
+
+/// Returns the block event decoded from the `BlockCommit` or `BlockVerification` event log
+///
+/// # Arguments
+///
+/// * `log` - Block event log
+/// * `block_type` - Type of the block event
+///
+fn decode_block_event_log(
+    log: &Log,
+    block_type: EventType,
+    topic_indices: &TopicIndices,
+) -> Result<BlockEvent, anyhow::Error> {
+    let block_num = log
+        .topics
+        .get(topic_indices.block_number)
+        .ok_or_else(|| format_err!("There is no block number in block event: {:?}", log))?;
+    let transaction_hash = log
+        .transaction_hash
+        .ok_or_else(|| format_err!("There is no tx hash in block event: {:?}", log))?;
+    Ok(BlockEvent {
+        block_num: U256::from(block_num.as_bytes()).as_u32(),
+        transaction_hash,
+        block_type,
+    })
+}
+
+/// Returns the total verified and committed blocks decoded from the `BlocksRevert` event log
+///
+/// # Arguments
+///
+/// * `log` - `BlocksRevert` event log
+///
+fn decode_blocks_revert_log(log: &Log) -> Result<(u32, u32), anyhow::Error> {
+    const U256_SIZE: usize = 32;
+    // Fields in `BlocksRevert` are not `indexed`, thus they're located in `data`.
+    if log.data.0.len() != U256_SIZE * 2 {
+        return Err(format_err!("Failed to parse BlocksRevert event: {:?}", log));
+    }
+    let total_verified = U256::from_big_endian(&log.data.0[..U256_SIZE]).as_u32();
+    let total_committed = U256::from_big_endian(&log.data.0[U256_SIZE..]).as_u32();
+    Ok((total_verified, total_committed))
+}
+
+/// Checks whether the error is caused by the web3 provider limits on the logs response size.
+/// Infura rejects the responses with more than 10000 logs with the `-32005` code, Alchemy
+/// rejects the large responses with the `-32602` code. The same codes are used for other
+/// errors (e.g. the rate limits), so the messages are checked as well
+fn is_logs_limit_error(error: &web3::Error) -> bool {
+    let error = match error {
+        web3::Error::Rpc(error) => error,
+        _ => return false,
+    };
+    let message = error.message.to_lowercase();
+    match error.code.code() {
+        -32005 => message.contains("query returned more than"),
+        -32602 => message.contains("log response size exceeded"),
+        _ => false,
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::EventsState;
-    use web3::{
-        api::{Eth, Namespace},
-        contract::Contract,
-        types::Bytes,
+    use super::{
+        decode_block_event_log, decode_blocks_revert_log, is_logs_limit_error, ContractTopics,
+        DecodeError, EventsState, NewTokenEvent, RpcUsage, TopicIndices,
     };
-    use zksync_contracts::zksync_contract;
+    use crate::event_decoder::DefaultEventDecoder;
+    use crate::events::EventType;
+    use futures::future;
+    use serde_json::json;
+    use std::convert::TryFrom;
+    use std::sync::{Arc, Mutex};
+    use web3::contract::Contract;
+    use web3::types::{Bytes, Log, H256};
+    use web3::{RequestId, Transport, Web3};
+    use zksync_contracts::{governance_contract, zksync_contract};
+    use zksync_types::Address;
+
+    use crate::tests::utils::{create_log, u32_to_32bytes};
 
-    use crate::tests::utils::{create_log, u32_to_32bytes, FakeTransport};
+    fn contract_topics() -> ContractTopics {
+        ContractTopics::new(&zksync_contract(), &governance_contract())
+    }
 
     #[test]
-    fn event_state() {
+    fn new_token_event_keeps_transaction_hash() {
+        let transaction_hash = H256::from([7u8; 32]);
+        let log = create_log(
+            contract_topics().new_token[0],
+            vec![
+                H256::from(u32_to_32bytes(0xabcd)),
+                H256::from(u32_to_32bytes(3)),
+            ],
+            Bytes(vec![]),
+            1,
+            transaction_hash,
+        );
+        let event = NewTokenEvent::try_from(log).expect("Cant parse NewToken event");
+        assert_eq!(event.id, 3);
+        assert_eq!(event.transaction_hash, Some(transaction_hash));
+    }
+
+    #[test]
+    fn new_token_event_with_missing_topics() {
+        // The token address topic is missing, e.g. the event is indexed differently.
+        let log = create_log(
+            contract_topics().new_token[0],
+            vec![H256::from(u32_to_32bytes(3))],
+            Bytes(vec![]),
+            1,
+            H256::from([7u8; 32]),
+        );
+        let err = NewTokenEvent::try_from(log)
+            .err()
+            .expect("NewToken event with two topics must be rejected");
+        assert!(err.to_string().contains("expected 3 topics, got 2"));
+    }
+
+    #[test]
+    fn new_token_event_with_swapped_topics() {
+        // The contract variant indexes the token id before the token address.
+        let topic_indices = TopicIndices {
+            token_address: 2,
+            token_id: 1,
+            ..Default::default()
+        };
+        let log = create_log(
+            contract_topics().new_token[0],
+            vec![
+                H256::from(u32_to_32bytes(3)),
+                H256::from(u32_to_32bytes(0xabcd)),
+            ],
+            Bytes(vec![]),
+            1,
+            H256::from([7u8; 32]),
+        );
+        let event = NewTokenEvent::from_log(log.clone(), &topic_indices)
+            .expect("Cant parse NewToken event");
+        assert_eq!(event.id, 3);
+        assert_eq!(
+            event.address,
+            Address::from_slice(&u32_to_32bytes(0xabcd)[12..])
+        );
+
+        let event = NewTokenEvent::try_from(log).expect("Cant parse NewToken event");
+        assert_eq!(event.id, 0xabcd);
+    }
+
+    #[test]
+    fn lenient_token_events_decoding() {
+        let token_log = |token_id: u32, log_index: u64| {
+            let mut log = create_log(
+                contract_topics().new_token[0],
+                vec![
+                    H256::from(u32_to_32bytes(0xabcd + token_id)),
+                    H256::from(u32_to_32bytes(token_id)),
+                ],
+                Bytes(vec![]),
+                1,
+                H256::from([7u8; 32]),
+            );
+            log.log_index = Some(log_index.into());
+            log
+        };
+        let mut bad_log = token_log(2, 1);
+        bad_log.topics.pop();
+        let logs = vec![token_log(1, 0), bad_log, token_log(3, 2)];
+
         let mut events_state = EventsState::default();
-        let abi_contract = zksync_contract();
+        assert!(events_state
+            .decode_token_events(logs.clone(), &DefaultEventDecoder, &Default::default())
+            .is_err());
+        assert!(events_state.decode_errors.is_empty());
+
+        events_state.lenient_decoding = true;
+        let token_events = events_state
+            .decode_token_events(logs, &DefaultEventDecoder, &Default::default())
+            .expect("Bad log must be skipped in lenient mode");
+        let token_ids: Vec<_> = token_events.iter().map(|event| event.id).collect();
+        assert_eq!(token_ids, vec![1, 3]);
+        assert_eq!(events_state.decode_errors.len(), 1);
+        let DecodeError { context, error } = &events_state.decode_errors[0];
+        assert!(context.contains("NewToken event log Some(1)"));
+        assert!(error.contains("expected 3 topics, got 2"));
+    }
+
+    #[test]
+    fn decode_block_events() {
+        let topic = contract_topics().block_committed[0];
+        let log = create_log(
+            topic,
+            vec![u32_to_32bytes(5).into()],
+            Bytes(vec![]),
+            1,
+            u32_to_32bytes(3).into(),
+        );
+        let event =
+            decode_block_event_log(&log, EventType::Committed, &Default::default()).unwrap();
+        assert_eq!(event.block_num, 5);
+        assert_eq!(event.transaction_hash, u32_to_32bytes(3).into());
+        assert_eq!(event.block_type, EventType::Committed);
 
-        let contract = (
-            abi_contract.clone(),
-            Contract::new(Eth::new(FakeTransport), [1u8; 20].into(), abi_contract),
+        let mut no_tx_hash_log = log.clone();
+        no_tx_hash_log.transaction_hash = None;
+        assert!(
+            decode_block_event_log(&no_tx_hash_log, EventType::Committed, &Default::default())
+                .is_err()
         );
-        let block_verified_topic = contract
-            .0
-            .event("BlockVerification")
-            .expect("Main contract abi error")
-            .signature();
-        let block_committed_topic = contract
-            .0
-            .event("BlockCommit")
-            .expect("Main contract abi error")
-            .signature();
-        let reverted_topic = contract
-            .0
-            .event("BlocksRevert")
-            .expect("Main contract abi error")
-            .signature();
+
+        let too_few_topics_log =
+            create_log(topic, vec![], Bytes(vec![]), 1, u32_to_32bytes(3).into());
+        assert!(decode_block_event_log(
+            &too_few_topics_log,
+            EventType::Verified,
+            &Default::default()
+        )
+        .is_err());
+
+        let mut revert_data = u32_to_32bytes(2).to_vec();
+        revert_data.extend_from_slice(&u32_to_32bytes(4));
+        let revert_topic = contract_topics().blocks_reverted[0];
+        let revert_log = create_log(
+            revert_topic,
+            vec![],
+            Bytes(revert_data.clone()),
+            1,
+            u32_to_32bytes(3).into(),
+        );
+        assert_eq!(decode_blocks_revert_log(&revert_log).unwrap(), (2, 4));
+
+        let short_revert_log = create_log(
+            revert_topic,
+            vec![],
+            Bytes(revert_data[..40].to_vec()),
+            1,
+            u32_to_32bytes(3).into(),
+        );
+        assert!(decode_blocks_revert_log(&short_revert_log).is_err());
+    }
+
+    #[test]
+    fn event_state() {
+        let mut events_state = EventsState::default();
+        let contract_topics = contract_topics();
+        let block_verified_topic = contract_topics.block_verified[0];
+        let block_committed_topic = contract_topics.block_committed[0];
+        let reverted_topic = contract_topics.blocks_reverted[0];
 
         let mut logs = vec![];
         for i in 0..32 {
@@ -419,7 +910,9 @@ mod test {
             ));
         }
 
-        events_state.update_blocks_state(&contract, &logs);
+        events_state
+            .update_blocks_state(&contract_topics, &logs)
+            .expect("Cant update blocks state");
         assert_eq!(events_state.committed_events.len(), 32);
         assert_eq!(events_state.verified_events.len(), 32);
 
@@ -435,8 +928,408 @@ mod test {
             3,
             u32_to_32bytes(1).into(),
         );
-        events_state.update_blocks_state(&contract, &[log]);
+        events_state
+            .update_blocks_state(&contract_topics, &[log])
+            .expect("Cant update blocks state");
         assert_eq!(events_state.committed_events.len(), 16);
         assert_eq!(events_state.verified_events.len(), 11);
     }
+
+    #[test]
+    fn event_state_is_independent_of_logs_order() {
+        let contract_topics = contract_topics();
+        let block_committed_topic = contract_topics.block_committed[0];
+
+        let logs: Vec<_> = (0..8)
+            .map(|i| {
+                create_log(
+                    block_committed_topic,
+                    vec![u32_to_32bytes(i).into()],
+                    Bytes(vec![]),
+                    i,
+                    u32_to_32bytes(i).into(),
+                )
+            })
+            .collect();
+        let mut shuffled_logs = logs.clone();
+        shuffled_logs.reverse();
+        shuffled_logs.swap(1, 5);
+
+        let mut events_state = EventsState::default();
+        events_state
+            .update_blocks_state(&contract_topics, &logs)
+            .expect("Cant update blocks state");
+        let mut shuffled_events_state = EventsState::default();
+        shuffled_events_state
+            .update_blocks_state(&contract_topics, &shuffled_logs)
+            .expect("Cant update blocks state");
+
+        let block_numbers = |state: &EventsState| -> Vec<u32> {
+            state.committed_events.iter().map(|e| e.block_num).collect()
+        };
+        assert_eq!(block_numbers(&events_state), (0..8).collect::<Vec<_>>());
+        assert_eq!(
+            block_numbers(&events_state),
+            block_numbers(&shuffled_events_state)
+        );
+    }
+
+    #[test]
+    fn event_state_with_multiple_event_versions() {
+        let mut contract_topics = contract_topics();
+        // Signature of the hypothetical upgraded `BlockCommit` event.
+        let upgraded_committed_topic: H256 = [0xab; 32].into();
+        contract_topics
+            .block_committed
+            .push(upgraded_committed_topic);
+        let block_committed_topic = contract_topics.block_committed[0];
+
+        let logs = vec![
+            create_log(
+                block_committed_topic,
+                vec![u32_to_32bytes(1).into()],
+                Bytes(vec![]),
+                1,
+                u32_to_32bytes(1).into(),
+            ),
+            create_log(
+                upgraded_committed_topic,
+                vec![u32_to_32bytes(2).into()],
+                Bytes(u32_to_32bytes(100).to_vec()),
+                2,
+                u32_to_32bytes(2).into(),
+            ),
+        ];
+        assert!(contract_topics
+            .block_events()
+            .contains(&upgraded_committed_topic));
+
+        let mut events_state = EventsState::default();
+        events_state
+            .update_blocks_state(&contract_topics, &logs)
+            .expect("Cant update blocks state");
+        let block_numbers: Vec<u32> = events_state
+            .committed_events
+            .iter()
+            .map(|e| e.block_num)
+            .collect();
+        assert_eq!(block_numbers, vec![1, 2]);
+    }
+
+    /// Transport rejecting the logs requests for the blocks windows larger than the limit
+    #[derive(Debug, Clone)]
+    struct LimitedLogsTransport {
+        max_window: u64,
+        requested_windows: Arc<Mutex<Vec<u64>>>,
+    }
+
+    impl Transport for LimitedLogsTransport {
+        type Out = future::Ready<Result<jsonrpc_core::Value, web3::Error>>;
+
+        fn prepare(
+            &self,
+            method: &str,
+            params: Vec<jsonrpc_core::Value>,
+        ) -> (RequestId, jsonrpc_core::Call) {
+            (
+                1,
+                jsonrpc_core::Call::MethodCall(jsonrpc_core::MethodCall {
+                    jsonrpc: Some(jsonrpc_core::Version::V2),
+                    method: method.to_string(),
+                    params: jsonrpc_core::Params::Array(params),
+                    id: jsonrpc_core::Id::Num(1),
+                }),
+            )
+        }
+
+        fn send(&self, _id: RequestId, request: jsonrpc_core::Call) -> Self::Out {
+            let call = match request {
+                jsonrpc_core::Call::MethodCall(call) => call,
+                _ => return future::ready(Err(web3::Error::Unreachable)),
+            };
+            let response = match (call.method.as_str(), call.params) {
+                ("eth_blockNumber", _) => Ok(json!("0x2710")),
+                ("eth_getLogs", jsonrpc_core::Params::Array(params)) => {
+                    let block_number = |key: &str| {
+                        let hex = params[0][key].as_str().unwrap().trim_start_matches("0x");
+                        u64::from_str_radix(hex, 16).unwrap()
+                    };
+                    let window = block_number("toBlock") - block_number("fromBlock");
+                    self.requested_windows.lock().unwrap().push(window);
+                    if window > self.max_window {
+                        Err(web3::Error::Rpc(jsonrpc_core::Error {
+                            code: jsonrpc_core::ErrorCode::ServerError(-32005),
+                            message: "query returned more than 10000 results".to_string(),
+                            data: None,
+                        }))
+                    } else {
+                        Ok(json!([]))
+                    }
+                }
+                _ => Err(web3::Error::Unreachable),
+            };
+            future::ready(response)
+        }
+    }
+
+    #[test]
+    fn logs_limit_errors() {
+        let rpc_error = |code: i64, message: &str| {
+            web3::Error::Rpc(jsonrpc_core::Error {
+                code: jsonrpc_core::ErrorCode::from(code),
+                message: message.to_string(),
+                data: None,
+            })
+        };
+
+        assert!(is_logs_limit_error(&rpc_error(
+            -32005,
+            "query returned more than 10000 results"
+        )));
+        assert!(is_logs_limit_error(&rpc_error(
+            -32602,
+            "Log response size exceeded. You can make eth_getLogs requests with up to a 2K block range"
+        )));
+        // Rate limits share the code with the logs limit, but the window doesn't affect them.
+        assert!(!is_logs_limit_error(&rpc_error(
+            -32005,
+            "project ID request rate exceeded"
+        )));
+        assert!(!is_logs_limit_error(&rpc_error(
+            -32000,
+            "too many requests, more than allowed"
+        )));
+        assert!(!is_logs_limit_error(&web3::Error::Transport(
+            "response size exceeded".to_string()
+        )));
+    }
+
+    #[tokio::test]
+    async fn adaptive_blocks_window() {
+        let transport = LimitedLogsTransport {
+            max_window: 100,
+            requested_windows: Default::default(),
+        };
+        let web3 = Web3::new(transport.clone());
+        let zksync_contract = (
+            zksync_contract(),
+            Contract::new(web3.eth(), Default::default(), zksync_contract()),
+        );
+        let governance_contract = (
+            governance_contract(),
+            Contract::new(web3.eth(), Default::default(), governance_contract()),
+        );
+
+        let mut events_state = EventsState::default();
+        events_state
+            .update_events_state(
+                &web3,
+                &zksync_contract,
+                &governance_contract,
+                &contract_topics(),
+                &DefaultEventDecoder,
+                1000,
+                0,
+            )
+            .await
+            .expect("Logs must be fetched with the reduced window");
+
+        // The failed requests are retried with the halved window.
+        assert_eq!(
+            *transport.requested_windows.lock().unwrap(),
+            vec![1000, 500, 250, 125, 62]
+        );
+        assert_eq!(events_state.last_watched_eth_block_number, 63);
+        // The window was reduced by this request, so it isn't doubled back over the limit.
+        assert_eq!(events_state.eth_blocks_window, Some(62));
+    }
+
+    /// Transport returning the logs only for the requests pinned to the block hash
+    #[derive(Debug, Clone)]
+    struct BlockHashLogsTransport {
+        block_hash: H256,
+        logs: Vec<Log>,
+        requested_filters: Arc<Mutex<Vec<jsonrpc_core::Value>>>,
+    }
+
+    impl Transport for BlockHashLogsTransport {
+        type Out = future::Ready<Result<jsonrpc_core::Value, web3::Error>>;
+
+        fn prepare(
+            &self,
+            method: &str,
+            params: Vec<jsonrpc_core::Value>,
+        ) -> (RequestId, jsonrpc_core::Call) {
+            (
+                1,
+                jsonrpc_core::Call::MethodCall(jsonrpc_core::MethodCall {
+                    jsonrpc: Some(jsonrpc_core::Version::V2),
+                    method: method.to_string(),
+                    params: jsonrpc_core::Params::Array(params),
+                    id: jsonrpc_core::Id::Num(1),
+                }),
+            )
+        }
+
+        fn send(&self, _id: RequestId, request: jsonrpc_core::Call) -> Self::Out {
+            let response = match request {
+                jsonrpc_core::Call::MethodCall(call) if call.method == "eth_getLogs" => {
+                    match call.params {
+                        jsonrpc_core::Params::Array(params) => {
+                            let filter = params[0].clone();
+                            self.requested_filters.lock().unwrap().push(filter.clone());
+                            if filter["blockHash"] == json!(self.block_hash) {
+                                Ok(json!(self.logs))
+                            } else {
+                                Ok(json!([]))
+                            }
+                        }
+                        _ => Err(web3::Error::Unreachable),
+                    }
+                }
+                _ => Err(web3::Error::Unreachable),
+            };
+            future::ready(response)
+        }
+    }
+
+    #[tokio::test]
+    async fn block_events_in_eth_block() {
+        let contract_topics = contract_topics();
+        let block_hash = H256::from([5u8; 32]);
+        let transport = BlockHashLogsTransport {
+            block_hash,
+            logs: vec![
+                create_log(
+                    contract_topics.block_committed[0],
+                    vec![u32_to_32bytes(3).into()],
+                    Bytes(vec![]),
+                    1,
+                    u32_to_32bytes(1).into(),
+                ),
+                create_log(
+                    contract_topics.block_verified[0],
+                    vec![u32_to_32bytes(2).into()],
+                    Bytes(vec![]),
+                    1,
+                    u32_to_32bytes(2).into(),
+                ),
+            ],
+            requested_filters: Default::default(),
+        };
+        let web3 = Web3::new(transport.clone());
+        let zksync_contract = (
+            zksync_contract(),
+            Contract::new(web3.eth(), Default::default(), zksync_contract()),
+        );
+
+        let mut events_state = EventsState::default();
+        let events = events_state
+            .get_block_events_in_eth_block(&web3, &zksync_contract, &contract_topics, block_hash)
+            .await
+            .expect("Cant get block events");
+        let events: Vec<_> = events
+            .iter()
+            .map(|event| (event.block_num, event.block_type))
+            .collect();
+        assert_eq!(
+            events,
+            vec![(3, EventType::Committed), (2, EventType::Verified)]
+        );
+
+        // The filter is pinned to the block hash instead of the blocks numbers range.
+        let filters = transport.requested_filters.lock().unwrap();
+        assert_eq!(filters.len(), 1);
+        assert_eq!(filters[0]["blockHash"], json!(block_hash));
+        assert!(filters[0].get("fromBlock").is_none());
+        assert!(filters[0].get("toBlock").is_none());
+        assert_eq!(events_state.rpc_usage.get_logs_calls, 1);
+    }
+
+    #[tokio::test]
+    async fn raw_logs_in_eth_block() {
+        let contract_topics = contract_topics();
+        let block_hash = H256::from([5u8; 32]);
+        let log = |block_number: u32, log_index: u64| {
+            let mut log = create_log(
+                contract_topics.block_committed[0],
+                vec![u32_to_32bytes(block_number).into()],
+                Bytes(vec![]),
+                1,
+                u32_to_32bytes(block_number).into(),
+            );
+            log.log_index = Some(log_index.into());
+            log
+        };
+        let transport = BlockHashLogsTransport {
+            block_hash,
+            logs: vec![log(3, 2), log(1, 0), log(2, 1)],
+            requested_filters: Default::default(),
+        };
+        let web3 = Web3::new(transport.clone());
+        let zksync_contract = (
+            zksync_contract(),
+            Contract::new(web3.eth(), Default::default(), zksync_contract()),
+        );
+
+        let mut events_state = EventsState::default();
+        let logs = events_state
+            .get_raw_logs_in_eth_block(&web3, &zksync_contract, block_hash, "BlockCommit")
+            .await
+            .expect("Cant get raw logs");
+        assert_eq!(logs, vec![log(1, 0), log(2, 1), log(3, 2)]);
+        let filter = transport.requested_filters.lock().unwrap()[0].clone();
+        assert!(filter["topics"]
+            .to_string()
+            .contains(&format!("{:?}", contract_topics.block_committed[0])));
+
+        assert!(events_state
+            .get_raw_logs_in_eth_block(&web3, &zksync_contract, block_hash, "NewToken")
+            .await
+            .is_err());
+        assert_eq!(events_state.rpc_usage.get_logs_calls, 1);
+    }
+
+    #[tokio::test]
+    async fn rpc_usage() {
+        let transport = LimitedLogsTransport {
+            max_window: 1000,
+            requested_windows: Default::default(),
+        };
+        let web3 = Web3::new(transport);
+        let zksync_contract = (
+            zksync_contract(),
+            Contract::new(web3.eth(), Default::default(), zksync_contract()),
+        );
+        let governance_contract = (
+            governance_contract(),
+            Contract::new(web3.eth(), Default::default(), governance_contract()),
+        );
+
+        let mut events_state = EventsState::default();
+        for _ in 0..3 {
+            events_state
+                .update_events_state(
+                    &web3,
+                    &zksync_contract,
+                    &governance_contract,
+                    &contract_topics(),
+                    &DefaultEventDecoder,
+                    1000,
+                    0,
+                )
+                .await
+                .expect("Cant update events state");
+        }
+
+        // Block and token logs are requested together, by a single request
+        // for each of the batches, every response is an empty JSON array.
+        assert_eq!(
+            events_state.rpc_usage,
+            RpcUsage {
+                get_logs_calls: 3,
+                fetched_bytes: 6,
+            }
+        );
+    }
 }
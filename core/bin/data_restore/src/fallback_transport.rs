@@ -0,0 +1,178 @@
+// Built-in deps
+use std::future::Future;
+use std::pin::Pin;
+// External deps
+use web3::{RequestId, Transport};
+
+/// Web3 transport that sends requests to the redundant endpoints.
+///
+/// Every request is sent to the first endpoint, and if it fails, to the next one,
+/// until any of them succeeds. Optionally, the fetched logs may be cross-checked:
+/// in this case `eth_getLogs` response is accepted only if two endpoints returned the
+/// identical logs, guarding against a single node returning incomplete data.
+#[derive(Debug, Clone)]
+pub struct FallbackTransport<T> {
+    transports: Vec<T>,
+    cross_check_logs: bool,
+}
+
+impl<T: Transport> FallbackTransport<T> {
+    /// Returns the new transport over the provided endpoints
+    ///
+    /// # Arguments
+    ///
+    /// * `transports` - Endpoints transports, in order of priority
+    /// * `cross_check_logs` - Whether the logs must be confirmed by two endpoints
+    ///
+    pub fn new(transports: Vec<T>, cross_check_logs: bool) -> Self {
+        assert!(
+            !transports.is_empty(),
+            "At least one web3 transport must be provided"
+        );
+        Self {
+            transports,
+            cross_check_logs,
+        }
+    }
+}
+
+impl<T> Transport for FallbackTransport<T>
+where
+    T: Transport + Send + Sync + 'static,
+    T::Out: Send + 'static,
+{
+    type Out =
+        Pin<Box<dyn Future<Output = Result<jsonrpc_core::Value, web3::Error>> + Send + 'static>>;
+
+    fn prepare(
+        &self,
+        method: &str,
+        params: Vec<jsonrpc_core::Value>,
+    ) -> (RequestId, jsonrpc_core::Call) {
+        self.transports[0].prepare(method, params)
+    }
+
+    fn send(&self, id: RequestId, request: jsonrpc_core::Call) -> Self::Out {
+        let transports = self.transports.clone();
+        let is_logs_request = match &request {
+            jsonrpc_core::Call::MethodCall(call) => call.method == "eth_getLogs",
+            _ => false,
+        };
+        let cross_check = self.cross_check_logs && is_logs_request;
+
+        Box::pin(async move {
+            let mut confirmed_response: Option<jsonrpc_core::Value> = None;
+            let mut last_error = None;
+            for (endpoint_id, transport) in transports.iter().enumerate() {
+                let response = match transport.send(id, request.clone()).await {
+                    Ok(response) => response,
+                    Err(error) => {
+                        log::warn!("Web3 endpoint #{} request failed: {}", endpoint_id, error);
+                        last_error = Some(error);
+                        continue;
+                    }
+                };
+                if !cross_check {
+                    return Ok(response);
+                }
+                match confirmed_response.take() {
+                    None => confirmed_response = Some(response),
+                    Some(first_response) if first_response == response => return Ok(response),
+                    Some(_) => {
+                        return Err(web3::Error::InvalidResponse(
+                            "Web3 endpoints returned different logs".into(),
+                        ))
+                    }
+                }
+            }
+            Err(last_error.unwrap_or_else(|| {
+                web3::Error::InvalidResponse(
+                    "Logs can't be cross-checked: only one web3 endpoint responded".into(),
+                )
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FallbackTransport;
+    use futures::future;
+    use serde_json::json;
+    use web3::{RequestId, Transport};
+
+    /// Transport that always returns the same response
+    #[derive(Debug, Clone)]
+    struct StaticTransport(Option<jsonrpc_core::Value>);
+
+    impl Transport for StaticTransport {
+        type Out = future::Ready<Result<jsonrpc_core::Value, web3::Error>>;
+
+        fn prepare(
+            &self,
+            method: &str,
+            params: Vec<jsonrpc_core::Value>,
+        ) -> (RequestId, jsonrpc_core::Call) {
+            (
+                1,
+                jsonrpc_core::Call::MethodCall(jsonrpc_core::MethodCall {
+                    jsonrpc: Some(jsonrpc_core::Version::V2),
+                    method: method.to_string(),
+                    params: jsonrpc_core::Params::Array(params),
+                    id: jsonrpc_core::Id::Num(1),
+                }),
+            )
+        }
+
+        fn send(&self, _id: RequestId, _request: jsonrpc_core::Call) -> Self::Out {
+            future::ready(self.0.clone().ok_or(web3::Error::Unreachable))
+        }
+    }
+
+    #[tokio::test]
+    async fn fallback_to_secondary_endpoint() {
+        let transport = FallbackTransport::new(
+            vec![StaticTransport(None), StaticTransport(Some(json!("0x80")))],
+            false,
+        );
+        let response = transport.execute("eth_blockNumber", vec![]).await;
+        assert_eq!(response.unwrap(), json!("0x80"));
+
+        let transport = FallbackTransport::new(vec![StaticTransport(None)], false);
+        assert!(transport.execute("eth_blockNumber", vec![]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn cross_check_logs() {
+        let logs = json!([{ "data": "0x01" }]);
+        let other_logs = json!([]);
+
+        let transport = FallbackTransport::new(
+            vec![
+                StaticTransport(None),
+                StaticTransport(Some(logs.clone())),
+                StaticTransport(Some(logs.clone())),
+            ],
+            true,
+        );
+        let response = transport.execute("eth_getLogs", vec![]).await;
+        assert_eq!(response.unwrap(), logs);
+
+        let transport = FallbackTransport::new(
+            vec![
+                StaticTransport(Some(logs.clone())),
+                StaticTransport(Some(other_logs)),
+            ],
+            true,
+        );
+        assert!(transport.execute("eth_getLogs", vec![]).await.is_err());
+
+        // Other requests are not cross-checked.
+        let transport = FallbackTransport::new(vec![StaticTransport(Some(logs.clone()))], true);
+        assert!(transport.execute("eth_getLogs", vec![]).await.is_err());
+        assert_eq!(
+            transport.execute("eth_blockNumber", vec![]).await.unwrap(),
+            logs
+        );
+    }
+}
@@ -6,8 +6,9 @@ use web3::types::Address;
 use zksync_types::block::Block;
 use zksync_types::{
     Account, AccountId, AccountMap, AccountUpdate, AccountUpdates, Action, EncodedProofPlonk,
-    Operation, Token, TokenGenesisListItem,
+    Operation, Token, TokenGenesisListItem, TokenId,
 };
+use zksync_utils::format_units;
 
 use crate::{
     data_restore_driver::StorageUpdateState,
@@ -85,7 +86,7 @@ impl StorageInteractor for InMemoryStorageInteractor {
     ) {
         self.events_state = block_events.to_vec();
 
-        for &NewTokenEvent { id, address } in tokens {
+        for &NewTokenEvent { id, address, .. } in tokens {
             self.tokens.insert(
                 id,
                 Token {
@@ -114,6 +115,11 @@ impl StorageInteractor for InMemoryStorageInteractor {
             committed_events,
             verified_events,
             last_watched_eth_block_number: self.last_watched_block,
+            eth_blocks_window: None,
+            rpc_usage: Default::default(),
+            lenient_decoding: false,
+            decode_errors: Vec::new(),
+            eth_block_limit: None,
         }
     }
 
@@ -138,6 +144,22 @@ impl StorageInteractor for InMemoryStorageInteractor {
     async fn get_storage_state(&mut self) -> StorageUpdateState {
         self.storage_state
     }
+
+    async fn set_token_decimals(&mut self, token_id: TokenId, decimals: u8) -> bool {
+        match self.tokens.get_mut(&token_id) {
+            Some(token) => {
+                token.decimals = decimals;
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn get_formatted_balance(&mut self, id: AccountId, token_id: TokenId) -> Option<String> {
+        let token = self.tokens.get(&token_id)?;
+        let account = self.accounts.get(&id)?;
+        Some(format_units(account.get_balance(token_id), token.decimals))
+    }
 }
 
 impl InMemoryStorageInteractor {
@@ -169,6 +191,10 @@ impl InMemoryStorageInteractor {
         accounts.first().cloned()
     }
 
+    pub fn get_token(&self, token_id: TokenId) -> Option<&Token> {
+        self.tokens.get(&token_id)
+    }
+
     fn load_verified_events_state(&self) -> Vec<BlockEvent> {
         self.events_state
             .clone()
@@ -241,3 +267,40 @@ impl InMemoryStorageInteractor {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::InMemoryStorageInteractor;
+    use crate::{events_state::NewTokenEvent, storage_interactor::StorageInteractor};
+    use num::BigUint;
+
+    #[tokio::test]
+    async fn formatted_balance_with_token_decimals() {
+        let mut interactor = InMemoryStorageInteractor::new();
+        let token_event = NewTokenEvent {
+            address: [2u8; 20].into(),
+            id: 1,
+            transaction_hash: None,
+        };
+        interactor.save_events_state(&[], &[token_event], 0).await;
+        interactor.insert_new_account(0, &[7u8; 20].into());
+        interactor
+            .accounts
+            .get_mut(&0)
+            .unwrap()
+            .set_balance(1, BigUint::from(1_500_000u32));
+
+        assert_eq!(
+            interactor.get_formatted_balance(0, 1).await,
+            Some("0.0000000000015".to_string())
+        );
+        assert!(interactor.set_token_decimals(1, 6).await);
+        assert_eq!(
+            interactor.get_formatted_balance(0, 1).await,
+            Some("1.5".to_string())
+        );
+
+        assert!(!interactor.set_token_decimals(2, 6).await);
+        assert_eq!(interactor.get_formatted_balance(0, 2).await, None);
+    }
+}
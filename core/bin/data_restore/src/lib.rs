@@ -1,23 +1,38 @@
+pub mod account_cache;
+pub mod archive_transport;
+pub mod balance_reconciliation;
 pub mod contract_functions;
 pub mod data_restore_driver;
 pub mod database_storage_interactor;
 pub mod eth_tx_helpers;
+pub mod event_decoder;
 pub mod events;
 pub mod events_state;
+pub mod fallback_transport;
 pub mod inmemory_storage_interactor;
+pub mod ops_block_source;
+pub mod ops_blocks_queue;
+pub mod ops_blocks_wal;
+pub mod restore_progress;
 pub mod rollup_ops;
+pub mod snapshot;
 pub mod storage_interactor;
+pub mod transport_pool;
+pub mod tree_export;
 pub mod tree_state;
 
 #[cfg(test)]
 mod tests;
 
 use crate::storage_interactor::StorageInteractor;
+use std::time::Duration;
 use zksync_types::tokens::get_genesis_token_list;
 
 // How many blocks we will process at once.
 pub const ETH_BLOCKS_STEP: u64 = 10_000;
 pub const END_ETH_BLOCKS_OFFSET: u64 = 40;
+// Default interval between the checks for new events once all the known ones are processed.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(5);
 
 pub async fn add_tokens_to_storage<I: StorageInteractor>(interactor: &mut I, eth_network: &str) {
     let genesis_tokens =
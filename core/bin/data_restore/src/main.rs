@@ -1,4 +1,5 @@
 use serde::Deserialize;
+use std::{path::Path, time::Duration};
 use structopt::StructOpt;
 use web3::transports::Http;
 use zksync_config::configs::{ChainConfig, ContractsConfig as EnvContractsConfig, ETHClientConfig};
@@ -7,8 +8,10 @@ use zksync_storage::ConnectionPool;
 use zksync_types::{Address, H256};
 
 use zksync_data_restore::{
-    add_tokens_to_storage, data_restore_driver::DataRestoreDriver,
-    database_storage_interactor::DatabaseStorageInteractor, END_ETH_BLOCKS_OFFSET, ETH_BLOCKS_STEP,
+    add_tokens_to_storage, contract_functions::load_contract_abi,
+    data_restore_driver::DataRestoreDriver, database_storage_interactor::DatabaseStorageInteractor,
+    fallback_transport::FallbackTransport, ops_blocks_wal::OpsBlocksWal, END_ETH_BLOCKS_OFFSET,
+    ETH_BLOCKS_STEP,
 };
 use zksync_types::network::Network;
 
@@ -39,6 +42,28 @@ struct Opt {
     #[structopt(long = "web3", name = "web3")]
     web3_url: Option<String>,
 
+    /// Sets the fallback web3 APIs used if the main one fails
+    #[structopt(long = "fallback_web3", name = "fallback_web3")]
+    fallback_web3_urls: Vec<String>,
+
+    /// Accepts the fetched logs only if they are confirmed by two web3 APIs
+    #[structopt(long)]
+    cross_check_logs: bool,
+
+    /// Interval between the checks for the new events once all the known ones are processed, in seconds
+    #[structopt(long)]
+    poll_interval: Option<u64>,
+
+    /// Records the operations blocks into the write-ahead log file before applying them,
+    /// and recovers the blocks missing in storage from it on startup
+    #[structopt(long = "wal", name = "wal")]
+    wal_path: Option<String>,
+
+    /// Saves the state and the events scan cursor into the snapshot file on the graceful stop,
+    /// and resumes from it on the next start
+    #[structopt(long = "shutdown_snapshot", name = "shutdown_snapshot")]
+    shutdown_snapshot_path: Option<String>,
+
     /// Provides a path to the configuration file for data restore
     #[structopt(long = "config", name = "config")]
     config_path: Option<String>,
@@ -51,6 +76,15 @@ pub struct ContractsConfig {
     genesis_tx_hash: H256,
     contract_addr: Address,
     available_block_chunk_sizes: Vec<usize>,
+    /// Optional path to the Rollup contract ABI, overrides the built-in one
+    #[serde(default)]
+    zksync_contract_abi_path: Option<String>,
+    /// Optional path to the Governance contract ABI, overrides the built-in one
+    #[serde(default)]
+    governance_contract_abi_path: Option<String>,
+    /// Optional id of the Ethereum chain the web3 provider must be connected to
+    #[serde(default)]
+    expected_chain_id: Option<u64>,
 }
 
 impl ContractsConfig {
@@ -70,6 +104,9 @@ impl ContractsConfig {
             genesis_tx_hash: contracts_opts.genesis_tx_hash,
             contract_addr: contracts_opts.contract_addr,
             available_block_chunk_sizes: chain_opts.state_keeper.block_chunk_sizes,
+            zksync_contract_abi_path: None,
+            governance_contract_abi_path: None,
+            expected_chain_id: None,
         }
     }
 }
@@ -85,7 +122,11 @@ async fn main() {
 
     let web3_url = opt.web3_url.unwrap_or(config_opts.web3_url);
 
-    let transport = Http::new(&web3_url).expect("failed to start web3 transport");
+    let transports = std::iter::once(web3_url)
+        .chain(opt.fallback_web3_urls)
+        .map(|url| Http::new(&url).expect("failed to start web3 transport"))
+        .collect();
+    let transport = FallbackTransport::new(transports, opt.cross_check_logs);
 
     let config = opt
         .config_path
@@ -112,6 +153,26 @@ async fn main() {
         final_hash,
     );
 
+    if let Some(path) = &config.zksync_contract_abi_path {
+        driver.set_zksync_contract_abi(
+            load_contract_abi(path).expect("Can't load the zkSync contract ABI"),
+        );
+    }
+    if let Some(path) = &config.governance_contract_abi_path {
+        driver.set_governance_contract_abi(
+            load_contract_abi(path).expect("Can't load the Governance contract ABI"),
+        );
+    }
+
+    driver.expected_chain_id = config.expected_chain_id;
+    if let Some(poll_interval) = opt.poll_interval {
+        driver.poll_interval = Duration::from_secs(poll_interval);
+    }
+    driver
+        .verify_chain_id()
+        .await
+        .expect("Web3 provider is connected to the wrong chain");
+
     let mut interactor = DatabaseStorageInteractor::new(storage);
     // If genesis is argument is present - there will be fetching contracts creation transactions to get first eth block and genesis acc address
     if opt.genesis {
@@ -122,11 +183,64 @@ async fn main() {
         driver
             .set_genesis_state(&mut interactor, config.genesis_tx_hash)
             .await;
+
+        // The state of the previous restores doesn't follow the new genesis state.
+        if let Some(path) = &opt.shutdown_snapshot_path {
+            if Path::new(path).exists() {
+                std::fs::remove_file(path).expect("Can't remove the previous snapshot");
+            }
+        }
     }
 
-    if opt.continue_mode && driver.load_state_from_storage(&mut interactor).await {
+    let snapshot_path = opt
+        .shutdown_snapshot_path
+        .as_ref()
+        .filter(|path| Path::new(path).exists());
+    if let Some(path) = snapshot_path {
+        let finished = driver
+            .resume_from_snapshot(&mut interactor, path)
+            .await
+            .expect("Can't resume the state from the snapshot");
+        // The snapshot is only valid until the next restored block is saved, so it's removed
+        // to not resume the stale one after an abrupt termination.
+        std::fs::remove_file(path).expect("Can't remove the resumed snapshot");
+        if finished {
+            std::process::exit(0);
+        }
+    } else if opt.continue_mode && driver.load_state_from_storage(&mut interactor).await {
         std::process::exit(0);
     }
 
+    if let Some(path) = &opt.wal_path {
+        let mut wal = OpsBlocksWal::open(path).expect("Can't open the operations blocks log");
+        if opt.genesis {
+            // Blocks of the previous restores don't follow the new genesis state.
+            wal.truncate()
+                .expect("Can't truncate the operations blocks log");
+        }
+        driver.ops_blocks_wal = Some(wal);
+        driver
+            .recover_from_wal(&mut interactor)
+            .await
+            .expect("Can't recover the state from the operations blocks log");
+    }
+
+    // Stop gracefully on Ctrl-C, so the restored state remains consistent.
+    let stop_handle = driver.stop_handle();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            log::info!("Received the stop signal");
+            stop_handle.stop();
+        }
+    });
+
     driver.run_state_update(&mut interactor).await;
+
+    if let Some(path) = &opt.shutdown_snapshot_path {
+        if driver.stop_handle().is_stop_requested() {
+            driver
+                .shutdown(path)
+                .expect("Can't save the state on shutdown");
+        }
+    }
 }
@@ -0,0 +1,155 @@
+// Built-in deps
+use std::collections::VecDeque;
+// External deps
+use web3::{Transport, Web3};
+// Workspace deps
+use zksync_types::block::Block;
+use zksync_types::AccountUpdates;
+// Local deps
+use crate::{
+    event_decoder::EventDecoder, events::BlockEvent, rollup_ops::RollupOpsBlock,
+    tree_state::TreeState,
+};
+
+/// Source of the Rollup operations blocks to be applied to the tree state.
+///
+/// Decouples the discovery of the committed blocks from their application, so the blocks
+/// may come from the Ethereum node as well as from storage, a log or a test fixture.
+#[async_trait::async_trait(?Send)]
+pub trait OpBlockSource {
+    /// Returns the next operations block in the order of the block numbers,
+    /// `None` once the source is exhausted
+    async fn next_op_block(&mut self) -> Result<Option<RollupOpsBlock>, anyhow::Error>;
+}
+
+/// Source fetching the operations blocks of the committed block events
+/// from the commitment transactions via web3
+pub struct EventsOpBlockSource<'a, T: Transport> {
+    web3: &'a Web3<T>,
+    event_decoder: &'a dyn EventDecoder,
+    events: VecDeque<BlockEvent>,
+}
+
+impl<'a, T: Transport> EventsOpBlockSource<'a, T> {
+    /// Returns the source of the operations blocks committed by the events
+    ///
+    /// # Arguments
+    ///
+    /// * `web3` - Web3 provider
+    /// * `event_decoder` - Decoder of the commitment transactions input data
+    /// * `events` - Committed block events, in the order of the block numbers
+    ///
+    pub fn new(
+        web3: &'a Web3<T>,
+        event_decoder: &'a dyn EventDecoder,
+        events: impl IntoIterator<Item = BlockEvent>,
+    ) -> Self {
+        Self {
+            web3,
+            event_decoder,
+            events: events.into_iter().collect(),
+        }
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl<T: Transport> OpBlockSource for EventsOpBlockSource<'_, T> {
+    async fn next_op_block(&mut self) -> Result<Option<RollupOpsBlock>, anyhow::Error> {
+        let event = match self.events.pop_front() {
+            Some(event) => event,
+            None => return Ok(None),
+        };
+        let block =
+            RollupOpsBlock::get_rollup_ops_block(self.web3, &event, self.event_decoder).await?;
+        Ok(Some(block))
+    }
+}
+
+/// Source of the operations blocks already loaded into memory, e.g. from storage
+#[derive(Debug, Clone, Default)]
+pub struct VecOpBlockSource {
+    blocks: VecDeque<RollupOpsBlock>,
+}
+
+impl From<Vec<RollupOpsBlock>> for VecOpBlockSource {
+    fn from(blocks: Vec<RollupOpsBlock>) -> Self {
+        Self {
+            blocks: blocks.into(),
+        }
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl OpBlockSource for VecOpBlockSource {
+    async fn next_op_block(&mut self) -> Result<Option<RollupOpsBlock>, anyhow::Error> {
+        Ok(self.blocks.pop_front())
+    }
+}
+
+/// Returns all the remaining operations blocks of the source
+///
+/// # Arguments
+///
+/// * `source` - Operations blocks source
+///
+pub async fn collect_op_blocks(
+    source: &mut impl OpBlockSource,
+) -> Result<Vec<RollupOpsBlock>, anyhow::Error> {
+    let mut blocks = Vec::new();
+    while let Some(block) = source.next_op_block().await? {
+        blocks.push(block);
+    }
+    Ok(blocks)
+}
+
+/// Applies all the remaining operations blocks of the source to the tree state in order.
+/// Returns the restored blocks and updated accounts
+///
+/// # Arguments
+///
+/// * `source` - Operations blocks source
+/// * `tree_state` - Rollup accounts states
+///
+pub async fn apply_op_blocks(
+    source: &mut impl OpBlockSource,
+    tree_state: &mut TreeState,
+) -> Result<Vec<(Block, AccountUpdates)>, anyhow::Error> {
+    let mut restored = Vec::new();
+    while let Some(block) = source.next_op_block().await? {
+        restored.push(tree_state.update_tree_states_from_ops_block(&block)?);
+    }
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply_op_blocks, OpBlockSource, VecOpBlockSource};
+    use crate::{tests::utils::deposit_block, tree_state::TreeState};
+
+    #[tokio::test]
+    async fn apply_blocks_from_vec_source() {
+        let blocks: Vec<_> = (1..=3).map(deposit_block).collect();
+        let mut expected_state = TreeState::new(vec![50]);
+        for block in &blocks {
+            expected_state
+                .update_tree_states_from_ops_block(block)
+                .expect("Cant update state");
+        }
+
+        let mut tree_state = TreeState::new(vec![50]);
+        let mut source = VecOpBlockSource::from(blocks);
+        let restored = apply_op_blocks(&mut source, &mut tree_state)
+            .await
+            .expect("Cant apply blocks");
+        let restored_blocks: Vec<_> = restored
+            .iter()
+            .map(|(block, _)| block.block_number)
+            .collect();
+        assert_eq!(restored_blocks, vec![1, 2, 3]);
+        assert_eq!(tree_state.state.block_number, 3);
+        assert_eq!(tree_state.root_hash(), expected_state.root_hash());
+
+        // The source is exhausted.
+        assert!(source.next_op_block().await.unwrap().is_none());
+    }
+}
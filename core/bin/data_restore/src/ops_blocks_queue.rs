@@ -0,0 +1,232 @@
+// Built-in deps
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+// External deps
+use anyhow::ensure;
+// Workspace deps
+use zksync_types::block::Block;
+use zksync_types::AccountUpdates;
+// Local deps
+use crate::{
+    rollup_ops::RollupOpsBlock,
+    tree_state::{BlockGap, TreeState},
+};
+
+/// Queue of the Rollup operations blocks received out of order, e.g. from the concurrent fetchers.
+///
+/// The blocks are buffered until all the previous blocks are received, and then released
+/// in the order of their numbers. If a block stays missing while the later blocks are
+/// buffered for longer than the gap timeout, the gap is reported as the `BlockGap` error.
+#[derive(Debug)]
+pub struct OpsBlocksQueue {
+    next_block_num: u32,
+    pending_blocks: BTreeMap<u32, RollupOpsBlock>,
+    gap_timeout: Duration,
+    gap_detected_at: Option<Instant>,
+}
+
+impl OpsBlocksQueue {
+    /// Returns the empty queue
+    ///
+    /// # Arguments
+    ///
+    /// * `next_block_num` - Number of the next block to be released
+    /// * `gap_timeout` - Maximum time to wait for the missing block
+    ///
+    pub fn new(next_block_num: u32, gap_timeout: Duration) -> Self {
+        Self {
+            next_block_num,
+            pending_blocks: BTreeMap::new(),
+            gap_timeout,
+            gap_detected_at: None,
+        }
+    }
+
+    /// Adds the block to the queue. Blocks that are already released or queued are rejected
+    pub fn push(&mut self, block: RollupOpsBlock) -> Result<(), anyhow::Error> {
+        ensure!(
+            block.block_num >= self.next_block_num,
+            "Block {} is already released, the next expected block is {}",
+            block.block_num,
+            self.next_block_num
+        );
+        ensure!(
+            !self.pending_blocks.contains_key(&block.block_num),
+            "Block {} is already queued",
+            block.block_num
+        );
+        self.pending_blocks.insert(block.block_num, block);
+        Ok(())
+    }
+
+    /// Returns the consecutive blocks starting from the next expected one, removing them
+    /// from the queue. Fails with `BlockGap` if the next expected block is missing for longer
+    /// than the gap timeout while the later blocks are queued
+    pub fn pop_ready(&mut self) -> Result<Vec<RollupOpsBlock>, anyhow::Error> {
+        let mut ready_blocks = Vec::new();
+        while let Some(block) = self.pending_blocks.remove(&self.next_block_num) {
+            ready_blocks.push(block);
+            self.next_block_num += 1;
+        }
+        self.check_gap(!ready_blocks.is_empty())?;
+        Ok(ready_blocks)
+    }
+
+    /// Applies the ready blocks to the tree state in order.
+    /// Returns the restored blocks and updated accounts.
+    ///
+    /// Each block is released only once it's applied. If a block fails, it's rolled back
+    /// and stays queued with the later blocks, while the blocks applied before it
+    /// stay applied and released
+    ///
+    /// # Arguments
+    ///
+    /// * `tree_state` - Rollup accounts states
+    ///
+    pub fn apply_ready(
+        &mut self,
+        tree_state: &mut TreeState,
+    ) -> Result<Vec<(Block, AccountUpdates)>, anyhow::Error> {
+        let mut restored = Vec::new();
+        while let Some(block) = self.pending_blocks.get(&self.next_block_num) {
+            restored.push(tree_state.try_update_tree_states_from_ops_block(block)?);
+            self.pending_blocks.remove(&self.next_block_num);
+            self.next_block_num += 1;
+        }
+        self.check_gap(!restored.is_empty())?;
+        Ok(restored)
+    }
+
+    /// Tracks how long the next expected block is missing while the later blocks are queued,
+    /// failing with `BlockGap` once it's missing for longer than the gap timeout
+    ///
+    /// # Arguments
+    ///
+    /// * `released` - Whether any block was released by the current call
+    ///
+    fn check_gap(&mut self, released: bool) -> Result<(), anyhow::Error> {
+        let first_queued = match self.pending_blocks.keys().next() {
+            Some(block_num) if !released => *block_num,
+            _ => {
+                self.gap_detected_at = None;
+                return Ok(());
+            }
+        };
+        let gap_detected_at = *self.gap_detected_at.get_or_insert_with(Instant::now);
+        if gap_detected_at.elapsed() > self.gap_timeout {
+            return Err(BlockGap {
+                expected: self.next_block_num,
+                got: first_queued,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Returns the number of the next block to be released
+    pub fn next_block_num(&self) -> u32 {
+        self.next_block_num
+    }
+
+    /// Returns the amount of the queued blocks
+    pub fn len(&self) -> usize {
+        self.pending_blocks.len()
+    }
+
+    /// Returns true if there are no queued blocks
+    pub fn is_empty(&self) -> bool {
+        self.pending_blocks.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::OpsBlocksQueue;
+    use crate::{
+        tests::utils::deposit_block,
+        tree_state::{BlockGap, TreeState},
+    };
+    use num::BigUint;
+    use std::time::Duration;
+    use zksync_types::{Transfer, TransferOp, ZkSyncOp};
+
+    #[test]
+    fn apply_unordered_blocks() {
+        let mut tree_state = TreeState::new(vec![50]);
+        // Deposits account ids are checked, so the blocks must be applied in order.
+        tree_state.strict_mode = true;
+        let mut queue = OpsBlocksQueue::new(1, Duration::from_secs(60));
+
+        queue.push(deposit_block(3)).unwrap();
+        assert!(queue.apply_ready(&mut tree_state).unwrap().is_empty());
+        queue.push(deposit_block(1)).unwrap();
+        let applied = queue.apply_ready(&mut tree_state).unwrap();
+        assert_eq!(applied.len(), 1);
+        assert_eq!(applied[0].0.block_number, 1);
+
+        queue.push(deposit_block(2)).unwrap();
+        let applied: Vec<_> = queue
+            .apply_ready(&mut tree_state)
+            .unwrap()
+            .into_iter()
+            .map(|(block, _)| block.block_number)
+            .collect();
+        assert_eq!(applied, vec![2, 3]);
+        assert!(queue.is_empty());
+        assert_eq!(queue.next_block_num(), 4);
+        assert_eq!(tree_state.get_accounts().len(), 3);
+
+        assert!(queue.push(deposit_block(2)).is_err());
+    }
+
+    #[test]
+    fn missing_block_gap() {
+        let mut queue = OpsBlocksQueue::new(1, Duration::from_millis(0));
+        queue.push(deposit_block(2)).unwrap();
+        assert!(queue.pop_ready().unwrap().is_empty());
+        std::thread::sleep(Duration::from_millis(5));
+        let err = queue.pop_ready().err().expect("Block gap must be reported");
+        assert_eq!(
+            err.downcast_ref::<BlockGap>(),
+            Some(&BlockGap {
+                expected: 1,
+                got: 2
+            })
+        );
+    }
+
+    #[test]
+    fn failed_block_stays_queued() {
+        let mut tree_state = TreeState::new(vec![50]);
+        let mut queue = OpsBlocksQueue::new(1, Duration::from_secs(60));
+        // The block 2 deposit is followed by a transfer from the nonexistent account 5.
+        let mut failing_block = deposit_block(2);
+        failing_block
+            .ops
+            .push(ZkSyncOp::Transfer(Box::new(TransferOp {
+                tx: Transfer::new(
+                    5,
+                    [9u8; 20].into(),
+                    [1u8; 20].into(),
+                    1,
+                    BigUint::from(10u32),
+                    BigUint::from(1u32),
+                    0,
+                    None,
+                ),
+                from: 5,
+                to: 0,
+            })));
+        for block in vec![deposit_block(1), failing_block, deposit_block(3)] {
+            queue.push(block).unwrap();
+        }
+
+        assert!(queue.apply_ready(&mut tree_state).is_err());
+        // The block 1 is applied, the failed block is rolled back and queued with the block 3.
+        assert_eq!(queue.next_block_num(), 2);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(tree_state.state.block_number, 1);
+        assert_eq!(tree_state.get_accounts().len(), 1);
+        assert!(queue.push(deposit_block(3)).is_err());
+    }
+}
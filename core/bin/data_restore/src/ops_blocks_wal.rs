@@ -0,0 +1,208 @@
+// Built-in deps
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+// External deps
+use anyhow::format_err;
+// Workspace deps
+use zksync_types::block::Block;
+use zksync_types::AccountUpdates;
+// Local deps
+use crate::{rollup_ops::RollupOpsBlock, tree_state::TreeState};
+
+/// Append-only write-ahead log of the Rollup operations blocks.
+///
+/// Every operations block is recorded before it is applied to the tree state, so the blocks
+/// applied in memory but not saved in storage by the time of a crash can be replayed on startup.
+/// The log is truncated once its blocks are saved in storage or in a snapshot, so it only holds
+/// the blocks of the latest state update.
+/// Blocks are stored as the newline-delimited JSON feed records, a record without the trailing
+/// newline is the partial write of the crashed process and is discarded.
+#[derive(Debug)]
+pub struct OpsBlocksWal {
+    path: PathBuf,
+    file: File,
+}
+
+impl OpsBlocksWal {
+    /// Opens the log file, creating it if it doesn't exist.
+    /// The partially written last record, if any, is truncated
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Log file path
+    ///
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        let complete_len = content
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map_or(0, |position| position + 1);
+        if complete_len < content.len() {
+            log::warn!(
+                "Discarding the partial record of the operations blocks log {}",
+                path.display()
+            );
+            file.set_len(complete_len as u64)?;
+        }
+
+        Ok(Self { path, file })
+    }
+
+    /// Records the operations block, the record is synced to the disk before returning
+    ///
+    /// # Arguments
+    ///
+    /// * `block` - Rollup operations block to be applied
+    ///
+    pub fn append(&mut self, block: &RollupOpsBlock) -> Result<(), anyhow::Error> {
+        let mut record = block.to_feed_json()?.into_bytes();
+        record.push(b'\n');
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Returns the complete records of the log in the order they were appended
+    pub fn blocks(&self) -> Result<Vec<RollupOpsBlock>, anyhow::Error> {
+        let content = std::fs::read_to_string(&self.path)?;
+        let complete_len = content.rfind('\n').map_or(0, |position| position + 1);
+        content[..complete_len]
+            .lines()
+            .map(|record| {
+                RollupOpsBlock::from_feed_json(record).map_err(|e| {
+                    format_err!(
+                        "Invalid record of the operations blocks log {}: {}",
+                        self.path.display(),
+                        e
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Applies the logged blocks following the last block of the tree state.
+    /// Returns the restored blocks and updated accounts, so they can be saved in storage.
+    ///
+    /// Recovery stops at the first failed block, which is rolled back. The blocks logged
+    /// before it remain applied to the tree state, but they aren't returned and saved,
+    /// so the tree state is ahead of storage and should be reloaded after the error
+    ///
+    /// # Arguments
+    ///
+    /// * `tree_state` - Rollup accounts states
+    ///
+    pub fn recover(
+        &self,
+        tree_state: &mut TreeState,
+    ) -> Result<Vec<(Block, AccountUpdates)>, anyhow::Error> {
+        self.blocks()?
+            .iter()
+            .filter(|block| block.block_num > tree_state.state.block_number)
+            .map(|block| tree_state.try_update_tree_states_from_ops_block(block))
+            .collect()
+    }
+
+    /// Removes all the records, e.g. once the logged blocks are saved in storage
+    pub fn truncate(&mut self) -> Result<(), anyhow::Error> {
+        self.file.set_len(0)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::OpsBlocksWal;
+    use crate::{
+        tests::utils::{deposit_block, temp_path},
+        tree_state::TreeState,
+    };
+    use num::BigUint;
+    use std::io::Write;
+    use zksync_types::{Transfer, TransferOp, ZkSyncOp};
+
+    #[test]
+    fn recover_after_crash() {
+        let path = temp_path("data_restore_ops_blocks.wal");
+
+        let mut tree_state = TreeState::new(vec![50]);
+        let mut wal = OpsBlocksWal::open(&path).expect("Cant open the log");
+        for block_num in 1..=3 {
+            let block = deposit_block(block_num);
+            wal.append(&block).unwrap();
+            tree_state
+                .update_tree_states_from_ops_block(&block)
+                .expect("Cant update state");
+        }
+        // The process crashes while recording the block 4, so it isn't applied.
+        let partial_record = deposit_block(4).to_feed_json().unwrap();
+        wal.file
+            .write_all(&partial_record.as_bytes()[..partial_record.len() / 2])
+            .unwrap();
+        drop(wal);
+
+        // Only the block 1 was saved in storage before the crash.
+        let mut recovered_state = TreeState::new(vec![50]);
+        recovered_state
+            .update_tree_states_from_ops_block(&deposit_block(1))
+            .expect("Cant update state");
+        let mut wal = OpsBlocksWal::open(&path).expect("Cant open the log");
+        let recovered = wal.recover(&mut recovered_state).expect("Cant recover");
+        let recovered_blocks: Vec<_> = recovered
+            .iter()
+            .map(|(block, _)| block.block_number)
+            .collect();
+        assert_eq!(recovered_blocks, vec![2, 3]);
+        assert_eq!(recovered_state.state.block_number, 3);
+        assert_eq!(recovered_state.root_hash(), tree_state.root_hash());
+
+        // The partial record is discarded, so the log can be appended again.
+        wal.append(&deposit_block(4)).unwrap();
+        assert_eq!(wal.blocks().unwrap().len(), 4);
+        wal.truncate().unwrap();
+        assert!(wal.blocks().unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recover_failed_block() {
+        let path = temp_path("data_restore_failed_ops_blocks.wal");
+        let mut wal = OpsBlocksWal::open(&path).expect("Cant open the log");
+        // The block 2 deposit is followed by a transfer from the nonexistent account 5.
+        let mut failing_block = deposit_block(2);
+        failing_block
+            .ops
+            .push(ZkSyncOp::Transfer(Box::new(TransferOp {
+                tx: Transfer::new(
+                    5,
+                    [9u8; 20].into(),
+                    [1u8; 20].into(),
+                    1,
+                    BigUint::from(10u32),
+                    BigUint::from(1u32),
+                    0,
+                    None,
+                ),
+                from: 5,
+                to: 0,
+            })));
+        for block in &[deposit_block(1), failing_block, deposit_block(3)] {
+            wal.append(block).unwrap();
+        }
+
+        let mut tree_state = TreeState::new(vec![50]);
+        assert!(wal.recover(&mut tree_state).is_err());
+        // The block 1 remains applied, while the failed block is rolled back.
+        assert_eq!(tree_state.state.block_number, 1);
+        assert_eq!(tree_state.get_accounts().len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+// Built-in deps
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Amount of the latest samples used to calculate the restore throughput
+const THROUGHPUT_SAMPLES_WINDOW: usize = 10;
+
+/// Estimate of the remaining restore work
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RestoreEstimate {
+    /// Amount of the Ethereum blocks processed since the restore was started
+    pub processed_blocks: u64,
+    /// Total amount of the Ethereum blocks to process up to the chain head
+    pub total_blocks: u64,
+    /// Rough estimate of the remaining time, `None` if there is not enough data yet
+    pub eta: Option<Duration>,
+}
+
+impl RestoreEstimate {
+    /// Returns the percentage of the processed blocks
+    pub fn percentage(&self) -> f64 {
+        if self.total_blocks == 0 {
+            return 100.0;
+        }
+        self.processed_blocks as f64 * 100.0 / self.total_blocks as f64
+    }
+}
+
+/// Tracks the last processed Ethereum block over time to estimate the restore throughput
+#[derive(Debug, Clone, Default)]
+pub struct RestoreProgress {
+    /// The Ethereum block that was processed when the tracking was started
+    start_block: Option<u64>,
+    /// The latest `(time, last processed block)` samples
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RestoreProgress {
+    /// Records the last processed Ethereum block
+    ///
+    /// # Arguments
+    ///
+    /// * `at` - The moment the block was processed
+    /// * `last_processed_block` - The last processed Ethereum block number
+    ///
+    pub fn record(&mut self, at: Instant, last_processed_block: u64) {
+        self.start_block.get_or_insert(last_processed_block);
+        if self.samples.len() == THROUGHPUT_SAMPLES_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((at, last_processed_block));
+    }
+
+    /// Returns the last processed Ethereum block, if any
+    pub fn last_processed_block(&self) -> Option<u64> {
+        self.samples.back().map(|(_, block)| *block)
+    }
+
+    /// Returns the estimate of the remaining restore work
+    ///
+    /// # Arguments
+    ///
+    /// * `head_block` - The current Ethereum chain head block number
+    ///
+    pub fn estimate(&self, head_block: u64) -> RestoreEstimate {
+        let (start_block, last_block) = match (self.start_block, self.last_processed_block()) {
+            (Some(start_block), Some(last_block)) => (start_block, last_block),
+            _ => {
+                return RestoreEstimate {
+                    processed_blocks: 0,
+                    total_blocks: 0,
+                    eta: None,
+                }
+            }
+        };
+        let total_blocks = head_block.saturating_sub(start_block);
+        let processed_blocks = last_block.saturating_sub(start_block).min(total_blocks);
+        let remaining_blocks = total_blocks - processed_blocks;
+
+        let eta = match (self.samples.front(), self.samples.back()) {
+            (Some((first_time, first_block)), Some((last_time, last_block)))
+                if last_block > first_block =>
+            {
+                let elapsed = last_time.duration_since(*first_time);
+                let blocks_per_sec = (last_block - first_block) as f64 / elapsed.as_secs_f64();
+                Some(Duration::from_secs_f64(
+                    remaining_blocks as f64 / blocks_per_sec,
+                ))
+            }
+            _ if remaining_blocks == 0 => Some(Duration::from_secs(0)),
+            _ => None,
+        };
+
+        RestoreEstimate {
+            processed_blocks,
+            total_blocks,
+            eta,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RestoreProgress;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn estimate_remaining_work() {
+        let mut progress = RestoreProgress::default();
+        assert_eq!(progress.estimate(1000).eta, None);
+
+        let start = Instant::now();
+        // 100 blocks per 10 seconds.
+        for i in 0..5 {
+            progress.record(start + Duration::from_secs(i * 10), 1000 + i * 100);
+        }
+
+        let estimate = progress.estimate(2000);
+        assert_eq!(estimate.processed_blocks, 400);
+        assert_eq!(estimate.total_blocks, 1000);
+        assert!((estimate.percentage() - 40.0).abs() < f64::EPSILON);
+        assert_eq!(estimate.eta, Some(Duration::from_secs(60)));
+
+        let estimate = progress.estimate(1400);
+        assert!((estimate.percentage() - 100.0).abs() < f64::EPSILON);
+        assert_eq!(estimate.eta, Some(Duration::from_secs(0)));
+    }
+}
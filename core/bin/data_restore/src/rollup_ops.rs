@@ -1,9 +1,48 @@
 use crate::eth_tx_helpers::{get_ethereum_transaction, get_input_data_from_ethereum_transaction};
+use crate::event_decoder::EventDecoder;
 use crate::events::BlockEvent;
+use anyhow::ensure;
 use ethabi::ParamType;
+use serde::{Deserialize, Serialize};
 use web3::{Transport, Web3};
+use zksync_crypto::{convert::FeConvert, Fr};
 use zksync_types::operations::ZkSyncOp;
 
+/// Block commitment data of the `commitBlock` call besides the operations public data
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockPublicInputs {
+    /// Accounts tree root hash after applying the block, must match the restored tree root hash
+    pub new_root_hash: Fr,
+}
+
+/// Version of the JSON operations blocks feed format
+pub const OPS_BLOCK_FEED_VERSION: u32 = 1;
+
+/// Rollup operations block as stored in the JSON feed
+#[derive(Debug, Serialize, Deserialize)]
+struct OpsBlockFeedRecord {
+    version: u32,
+    block_num: u32,
+    fee_account: u32,
+    ops: Vec<ZkSyncOp>,
+}
+
+/// Header of the feed record, decoded before the record itself
+/// so the records of the other versions are rejected explicitly
+#[derive(Debug, Deserialize)]
+struct OpsBlockFeedHeader {
+    version: u32,
+}
+
+/// Outcome of decoding the operation of the type unknown to the data restore
+#[derive(Debug, Clone)]
+pub enum UnknownOp {
+    /// Operation is decoded as the known one, with the length of its public data
+    Decoded(ZkSyncOp, usize),
+    /// Operation is skipped, with the length of its public data
+    Skipped(usize),
+}
+
 /// Description of a Rollup operations block
 #[derive(Debug, Clone)]
 pub struct RollupOpsBlock {
@@ -22,44 +61,69 @@ impl RollupOpsBlock {
     ///
     /// * `web3` - Web3 provider url
     /// * `event_data` - Rollup contract event description
+    /// * `event_decoder` - Decoder of the commitment transaction input data
     ///
     pub async fn get_rollup_ops_block<T: Transport>(
         web3: &Web3<T>,
         event_data: &BlockEvent,
+        event_decoder: &dyn EventDecoder,
     ) -> Result<Self, anyhow::Error> {
         let transaction = get_ethereum_transaction(web3, &event_data.transaction_hash).await?;
         let input_data = get_input_data_from_ethereum_transaction(&transaction)?;
 
+        event_decoder.decode_ops_block(event_data.block_num, &input_data)
+    }
+
+    /// Returns a Rollup operations block description parsed from the input data
+    /// of the `commitBlock` Rollup contract call (without the function selector)
+    ///
+    /// # Arguments
+    ///
+    /// * `block_num` - Rollup block number
+    /// * `input_data` - Encoded `commitBlock` call parameters
+    ///
+    pub fn from_commit_input_data(
+        block_num: u32,
+        input_data: &[u8],
+    ) -> Result<Self, anyhow::Error> {
+        Self::from_commit_input_data_with_unknown_ops(block_num, input_data, reject_unknown_op)
+    }
+
+    /// Returns a Rollup operations block description parsed from the input data
+    /// of the `commitBlock` Rollup contract call (without the function selector),
+    /// passing the operations of the unknown types to the provided decoder
+    ///
+    /// # Arguments
+    ///
+    /// * `block_num` - Rollup block number
+    /// * `input_data` - Encoded `commitBlock` call parameters
+    /// * `decode_unknown_op` - Decoder of the unknown operations, called with the operation
+    /// type and the public data starting with the operation
+    ///
+    pub fn from_commit_input_data_with_unknown_ops<F>(
+        block_num: u32,
+        input_data: &[u8],
+        decode_unknown_op: F,
+    ) -> Result<Self, anyhow::Error>
+    where
+        F: Fn(u8, &[u8]) -> Result<UnknownOp, anyhow::Error>,
+    {
         let fee_account_argument_id = 1;
         let public_data_argument_id = 3;
-        let decoded_commitment_parameters = ethabi::decode(
-            vec![
-                ParamType::Uint(32),                                   // uint32 _blockNumber,
-                ParamType::Uint(32),                                   // uint32 _feeAccount,
-                ParamType::Array(Box::new(ParamType::FixedBytes(32))), // bytes32[] _newRoots,
-                ParamType::Bytes, // bytes calldata _publicData,
-                ParamType::Bytes, // bytes calldata _ethWitness,
-                ParamType::Array(Box::new(ParamType::Uint(32))), // uint32[] calldata _ethWitnessSizes
-            ]
-            .as_slice(),
-            input_data.as_slice(),
-        )
-        .map_err(|_| {
-            anyhow::Error::from(Box::new(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "can't get decoded parameters from commitment transaction",
-            )))
-        })?;
+        let decoded_commitment_parameters = decode_commit_parameters(input_data)?;
 
         if let (ethabi::Token::Uint(fee_acc), ethabi::Token::Bytes(public_data)) = (
             &decoded_commitment_parameters[fee_account_argument_id],
             &decoded_commitment_parameters[public_data_argument_id],
         ) {
-            let ops = RollupOpsBlock::get_rollup_ops_from_data(public_data.as_slice())?;
+            let ops = RollupOpsBlock::get_rollup_ops_from_data_with_unknown_ops(
+                public_data.as_slice(),
+                decode_unknown_op,
+            )?;
             let fee_account = fee_acc.as_u32();
 
             let block = RollupOpsBlock {
-                block_num: event_data.block_num,
+                block_num,
                 ops,
                 fee_account,
             };
@@ -73,6 +137,47 @@ impl RollupOpsBlock {
         }
     }
 
+    /// Returns the block public data re-encoded from the decoded operations.
+    /// For a correctly decoded block it matches the `_publicData` of the `commitBlock` call
+    pub fn recompute_public_data(&self) -> Vec<u8> {
+        self.ops.iter().flat_map(|op| op.public_data()).collect()
+    }
+
+    /// Returns the block encoded as the JSON feed record, so it can be applied
+    /// by another data restore process
+    pub fn to_feed_json(&self) -> Result<String, anyhow::Error> {
+        let record = OpsBlockFeedRecord {
+            version: OPS_BLOCK_FEED_VERSION,
+            block_num: self.block_num,
+            fee_account: self.fee_account,
+            ops: self.ops.clone(),
+        };
+        Ok(serde_json::to_string(&record)?)
+    }
+
+    /// Returns the block decoded from the JSON feed record.
+    /// Records of the other feed versions are rejected
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - JSON feed record
+    ///
+    pub fn from_feed_json(record: &str) -> Result<Self, anyhow::Error> {
+        let header: OpsBlockFeedHeader = serde_json::from_str(record)?;
+        ensure!(
+            header.version == OPS_BLOCK_FEED_VERSION,
+            "Unsupported operations block feed version {}, expected {}",
+            header.version,
+            OPS_BLOCK_FEED_VERSION
+        );
+        let record: OpsBlockFeedRecord = serde_json::from_str(record)?;
+        Ok(Self {
+            block_num: record.block_num,
+            ops: record.ops,
+            fee_account: record.fee_account,
+        })
+    }
+
     /// Returns a Rollup operations vector
     ///
     /// # Arguments
@@ -80,15 +185,58 @@ impl RollupOpsBlock {
     /// * `data` - ZkSync Contract event input data
     ///
     pub fn get_rollup_ops_from_data(data: &[u8]) -> Result<Vec<ZkSyncOp>, anyhow::Error> {
+        Self::get_rollup_ops_from_data_with_unknown_ops(data, reject_unknown_op)
+    }
+
+    /// Returns a Rollup operations vector, passing the operations of the unknown types
+    /// to the provided decoder. The skipped operations aren't included
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - ZkSync Contract event input data
+    /// * `decode_unknown_op` - Decoder of the unknown operations, called with the operation
+    /// type and the public data starting with the operation
+    ///
+    pub fn get_rollup_ops_from_data_with_unknown_ops<F>(
+        data: &[u8],
+        decode_unknown_op: F,
+    ) -> Result<Vec<ZkSyncOp>, anyhow::Error>
+    where
+        F: Fn(u8, &[u8]) -> Result<UnknownOp, anyhow::Error>,
+    {
         let mut current_pointer = 0;
         let mut ops = vec![];
         while current_pointer < data.len() {
             let op_type: u8 = data[current_pointer];
 
-            let pub_data_size = ZkSyncOp::public_data_length(op_type)?;
+            let pub_data_size = match ZkSyncOp::public_data_length(op_type) {
+                Ok(pub_data_size) => pub_data_size,
+                Err(_) => {
+                    let (op, pub_data_size) =
+                        match decode_unknown_op(op_type, &data[current_pointer..])? {
+                            UnknownOp::Decoded(op, pub_data_size) => (Some(op), pub_data_size),
+                            UnknownOp::Skipped(pub_data_size) => (None, pub_data_size),
+                        };
+                    anyhow::ensure!(
+                        pub_data_size > 0 && current_pointer + pub_data_size <= data.len(),
+                        "Wrong public data length {} of the operation of the type {}",
+                        pub_data_size,
+                        op_type
+                    );
+                    ops.extend(op);
+                    current_pointer += pub_data_size;
+                    continue;
+                }
+            };
 
             let pre = current_pointer;
             let post = pre + pub_data_size;
+            anyhow::ensure!(
+                post <= data.len(),
+                "Operation of the type {} at the offset {} is truncated",
+                op_type,
+                pre
+            );
 
             let op = ZkSyncOp::from_public_data(&data[pre..post])?;
 
@@ -99,17 +247,392 @@ impl RollupOpsBlock {
     }
 }
 
+/// Decoder of the unknown operations rejecting all of them
+fn reject_unknown_op(op_type: u8, _data: &[u8]) -> Result<UnknownOp, anyhow::Error> {
+    Err(anyhow::format_err!("Wrong operation type: {}", op_type))
+}
+
+/// Returns the parameters of the `commitBlock` Rollup contract call decoded from its input data
+/// (without the function selector)
+///
+/// # Arguments
+///
+/// * `input_data` - Encoded `commitBlock` call parameters
+///
+fn decode_commit_parameters(input_data: &[u8]) -> Result<Vec<ethabi::Token>, anyhow::Error> {
+    ethabi::decode(
+        vec![
+            ParamType::Uint(32),                                   // uint32 _blockNumber,
+            ParamType::Uint(32),                                   // uint32 _feeAccount,
+            ParamType::Array(Box::new(ParamType::FixedBytes(32))), // bytes32[] _newRoots,
+            ParamType::Bytes,                                      // bytes calldata _publicData,
+            ParamType::Bytes,                                      // bytes calldata _ethWitness,
+            ParamType::Array(Box::new(ParamType::Uint(32))), // uint32[] calldata _ethWitnessSizes
+        ]
+        .as_slice(),
+        input_data,
+    )
+    .map_err(|_| {
+        anyhow::Error::from(Box::new(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "can't get decoded parameters from commitment transaction",
+        )))
+    })
+}
+
+/// Returns the block commitment data decoded from the `_newBlockInfo` parameter
+/// of the `commitBlock` Rollup contract call input data (without the function selector)
+///
+/// # Arguments
+///
+/// * `input_data` - Encoded `commitBlock` call parameters
+///
+pub fn parse_block_public_inputs(input_data: &[u8]) -> Result<BlockPublicInputs, anyhow::Error> {
+    let new_block_info_argument_id = 2;
+    let decoded_commitment_parameters = decode_commit_parameters(input_data)?;
+
+    match &decoded_commitment_parameters[new_block_info_argument_id] {
+        // This version of the contract expects only the account tree root hash.
+        ethabi::Token::Array(new_block_info) if new_block_info.len() == 1 => {
+            match &new_block_info[0] {
+                ethabi::Token::FixedBytes(new_root_hash) => Ok(BlockPublicInputs {
+                    new_root_hash: Fr::from_bytes(new_root_hash)?,
+                }),
+                _ => anyhow::bail!("can't parse new root hash"),
+            }
+        }
+        _ => anyhow::bail!("can't parse new block info"),
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use super::{parse_block_public_inputs, OPS_BLOCK_FEED_VERSION};
     use crate::rollup_ops::RollupOpsBlock;
+    use crate::tree_state::TreeState;
+    use ethabi::Token;
     use num::BigUint;
-    use zksync_types::operations::ChangePubKeyOp;
+    use serde_json::json;
+    use zksync_crypto::convert::FeConvert;
+    use zksync_types::operations::{ChangePubKeyOp, NoopOp};
     use zksync_types::tx::{ChangePubKey, TxSignature};
     use zksync_types::{
         Close, CloseOp, Deposit, DepositOp, FullExit, FullExitOp, PubKeyHash, Transfer, TransferOp,
         TransferToNewOp, Withdraw, WithdrawOp, ZkSyncOp,
     };
 
+    fn commit_input_data(block_number: u32, fee_account: u32, public_data: Vec<u8>) -> Vec<u8> {
+        ethabi::encode(&[
+            Token::Uint(block_number.into()),
+            Token::Uint(fee_account.into()),
+            Token::Array(vec![Token::FixedBytes(vec![0u8; 32])]),
+            Token::Bytes(public_data),
+            Token::Bytes(vec![]),
+            Token::Array(vec![]),
+        ])
+    }
+
+    #[test]
+    fn test_commit_input_data() {
+        let deposit = ZkSyncOp::Deposit(Box::new(DepositOp {
+            priority_op: Deposit {
+                from: "1111111111111111111111111111111111111111".parse().unwrap(),
+                token: 1,
+                amount: 10u32.into(),
+                to: "7777777777777777777777777777777777777777".parse().unwrap(),
+            },
+            account_id: 6,
+        }));
+        let full_exit = ZkSyncOp::FullExit(Box::new(FullExitOp {
+            priority_op: FullExit {
+                account_id: 6,
+                eth_address: [9u8; 20].into(),
+                token: 1,
+            },
+            withdraw_amount: None,
+        }));
+        let mut public_data = deposit.public_data();
+        public_data.extend(full_exit.public_data());
+
+        let input_data = commit_input_data(3, 5, public_data.clone());
+        let block = RollupOpsBlock::from_commit_input_data(3, &input_data)
+            .expect("cant parse commit input data");
+        assert_eq!(block.block_num, 3);
+        assert_eq!(block.fee_account, 5);
+        assert_eq!(block.ops.len(), 2);
+        let parsed_public_data: Vec<u8> =
+            block.ops.iter().flat_map(|op| op.public_data()).collect();
+        assert_eq!(parsed_public_data, public_data);
+
+        // Truncated input data can't be decoded.
+        assert!(RollupOpsBlock::from_commit_input_data(3, &input_data[..64]).is_err());
+        // Unknown operation in public data.
+        let input_data = commit_input_data(3, 5, vec![0xff; 8]);
+        assert!(RollupOpsBlock::from_commit_input_data(3, &input_data).is_err());
+    }
+
+    #[test]
+    fn test_mixed_ops_public_data() {
+        let noop = ZkSyncOp::Noop(NoopOp {});
+        let transfer = ZkSyncOp::Transfer(Box::new(TransferOp {
+            tx: Transfer::new(
+                12,
+                "8888888888888888888888888888888888888888".parse().unwrap(),
+                "7777777777777777777777777777777777777777".parse().unwrap(),
+                1,
+                5u32.into(),
+                0u32.into(),
+                0,
+                None,
+            ),
+            from: 12,
+            to: 11,
+        }));
+        let deposit = ZkSyncOp::Deposit(Box::new(DepositOp {
+            priority_op: Deposit {
+                from: "1111111111111111111111111111111111111111".parse().unwrap(),
+                token: 1,
+                amount: 10u32.into(),
+                to: "7777777777777777777777777777777777777777".parse().unwrap(),
+            },
+            account_id: 11,
+        }));
+        let ops = vec![
+            transfer.clone(),
+            noop.clone(),
+            deposit,
+            noop.clone(),
+            noop,
+            transfer,
+        ];
+        let public_data: Vec<u8> = ops.iter().flat_map(|op| op.public_data()).collect();
+
+        let decoded_ops =
+            RollupOpsBlock::get_rollup_ops_from_data(&public_data).expect("cant get ops from data");
+        assert_eq!(decoded_ops.len(), ops.len());
+        for (decoded_op, op) in decoded_ops.iter().zip(ops.iter()) {
+            assert_eq!(decoded_op.public_data(), op.public_data());
+        }
+
+        // The last operation is cut in the middle.
+        assert!(
+            RollupOpsBlock::get_rollup_ops_from_data(&public_data[..public_data.len() - 1])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_block_public_inputs() {
+        let mut tree = TreeState::new(vec![50]);
+        let deposit = ZkSyncOp::Deposit(Box::new(DepositOp {
+            priority_op: Deposit {
+                from: "1111111111111111111111111111111111111111".parse().unwrap(),
+                token: 1,
+                amount: 10u32.into(),
+                to: "7777777777777777777777777777777777777777".parse().unwrap(),
+            },
+            account_id: 0,
+        }));
+        let block = RollupOpsBlock {
+            block_num: 1,
+            ops: vec![deposit.clone()],
+            fee_account: 0,
+        };
+        tree.update_tree_states_from_ops_block(&block)
+            .expect("Cant update state from block 1");
+
+        let input_data = ethabi::encode(&[
+            Token::Uint(1.into()),
+            Token::Uint(0.into()),
+            Token::Array(vec![Token::FixedBytes(tree.root_hash().to_bytes())]),
+            Token::Bytes(deposit.public_data()),
+            Token::Bytes(vec![]),
+            Token::Array(vec![]),
+        ]);
+        let public_inputs =
+            parse_block_public_inputs(&input_data).expect("cant parse block public inputs");
+        assert_eq!(public_inputs.new_root_hash, tree.root_hash());
+
+        // The root hash is expected to be the only new block info item.
+        let empty_block_info_input_data = ethabi::encode(&[
+            Token::Uint(1.into()),
+            Token::Uint(0.into()),
+            Token::Array(vec![]),
+            Token::Bytes(deposit.public_data()),
+            Token::Bytes(vec![]),
+            Token::Array(vec![]),
+        ]);
+        assert!(parse_block_public_inputs(&empty_block_info_input_data).is_err());
+        assert!(parse_block_public_inputs(&input_data[..64]).is_err());
+    }
+
+    #[test]
+    fn test_recompute_public_data() {
+        let transfer_to_new = ZkSyncOp::TransferToNew(Box::new(TransferToNewOp {
+            tx: Transfer::new(
+                11,
+                "7777777777777777777777777777777777777777".parse().unwrap(),
+                "8888888888888888888888888888888888888888".parse().unwrap(),
+                1,
+                20u32.into(),
+                10u32.into(),
+                3,
+                None,
+            ),
+            from: 11,
+            to: 12,
+        }));
+        let transfer = ZkSyncOp::Transfer(Box::new(TransferOp {
+            tx: Transfer::new(
+                12,
+                "8888888888888888888888888888888888888888".parse().unwrap(),
+                "7777777777777777777777777777777777777777".parse().unwrap(),
+                1,
+                5u32.into(),
+                0u32.into(),
+                0,
+                None,
+            ),
+            from: 12,
+            to: 11,
+        }));
+        let mut public_data = transfer_to_new.public_data();
+        public_data.extend(transfer.public_data());
+
+        let input_data = commit_input_data(4, 1, public_data.clone());
+        let block = RollupOpsBlock::from_commit_input_data(4, &input_data)
+            .expect("cant parse commit input data");
+        assert_eq!(block.recompute_public_data(), public_data);
+
+        // Decoding the re-encoded data gives the same public data again.
+        let ops = RollupOpsBlock::get_rollup_ops_from_data(&block.recompute_public_data())
+            .expect("cant get ops from data");
+        let block = RollupOpsBlock { ops, ..block };
+        assert_eq!(block.recompute_public_data(), public_data);
+    }
+
+    #[test]
+    fn test_ops_wire_format() {
+        let address = |byte: u8| [byte; 20].into();
+        let amounts = vec![
+            BigUint::from(0u32),
+            BigUint::from(u128::max_value()),
+            (BigUint::from(1u32) << 256) - 1u32,
+        ];
+        for amount in amounts {
+            let deposit = ZkSyncOp::Deposit(Box::new(DepositOp {
+                priority_op: Deposit {
+                    from: address(1),
+                    token: 1,
+                    amount: amount.clone(),
+                    to: address(2),
+                },
+                account_id: 0,
+            }));
+            let full_exit = ZkSyncOp::FullExit(Box::new(FullExitOp {
+                priority_op: FullExit {
+                    account_id: 0,
+                    eth_address: address(2),
+                    token: 1,
+                },
+                withdraw_amount: Some(amount.clone().into()),
+            }));
+            let transfer = ZkSyncOp::Transfer(Box::new(TransferOp {
+                tx: Transfer::new(
+                    0,
+                    address(2),
+                    address(3),
+                    1,
+                    amount.clone(),
+                    amount.clone(),
+                    0,
+                    None,
+                ),
+                from: 0,
+                to: 1,
+            }));
+            let ops = vec![
+                (deposit, "Deposit", "/priority_op/amount"),
+                (full_exit, "FullExit", "/withdraw_amount"),
+                (transfer, "Transfer", "/tx/amount"),
+            ];
+
+            for (op, op_type, amount_pointer) in ops {
+                let value = serde_json::to_value(&op).expect("Cant serialize operation");
+                assert_eq!(value["type"], json!(op_type));
+                // Amounts are encoded as decimal strings, so they don't lose precision.
+                assert_eq!(
+                    value.pointer(amount_pointer),
+                    Some(&json!(amount.to_string()))
+                );
+                let decoded: ZkSyncOp =
+                    serde_json::from_value(value.clone()).expect("Cant deserialize operation");
+                assert_eq!(serde_json::to_value(&decoded).unwrap(), value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_feed_json_round_trip() {
+        let deposit = ZkSyncOp::Deposit(Box::new(DepositOp {
+            priority_op: Deposit {
+                from: [1u8; 20].into(),
+                token: 1,
+                amount: 1000u32.into(),
+                to: [7u8; 20].into(),
+            },
+            account_id: 0,
+        }));
+        let transfer_to_new = ZkSyncOp::TransferToNew(Box::new(TransferToNewOp {
+            tx: Transfer::new(
+                0,
+                [7u8; 20].into(),
+                [8u8; 20].into(),
+                1,
+                100u32.into(),
+                10u32.into(),
+                0,
+                None,
+            ),
+            from: 0,
+            to: 1,
+        }));
+        let block = RollupOpsBlock {
+            block_num: 1,
+            ops: vec![deposit, transfer_to_new],
+            fee_account: 0,
+        };
+
+        let record = block.to_feed_json().expect("cant encode feed record");
+        let decoded = RollupOpsBlock::from_feed_json(&record).expect("cant decode feed record");
+        assert_eq!(decoded.block_num, 1);
+        assert_eq!(
+            decoded.recompute_public_data(),
+            block.recompute_public_data()
+        );
+
+        let mut tree = TreeState::new(vec![50]);
+        tree.update_tree_states_from_ops_block(&block)
+            .expect("Cant update state");
+        let mut feed_tree = TreeState::new(vec![50]);
+        feed_tree
+            .update_tree_states_from_ops_block(&decoded)
+            .expect("Cant update state from the feed");
+        assert_eq!(feed_tree.root_hash(), tree.root_hash());
+
+        let other_version = record.replacen(
+            &format!("\"version\":{}", OPS_BLOCK_FEED_VERSION),
+            &format!("\"version\":{}", OPS_BLOCK_FEED_VERSION + 1),
+            1,
+        );
+        let err = RollupOpsBlock::from_feed_json(&other_version)
+            .err()
+            .expect("Feed record of the other version must be rejected");
+        assert!(err
+            .to_string()
+            .contains("Unsupported operations block feed version"));
+    }
+
     #[test]
     fn test_deposit() {
         let priority_op = Deposit {
@@ -0,0 +1,506 @@
+// Built-in deps
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+// External deps
+use anyhow::{ensure, format_err};
+use num::BigUint;
+use serde::{Deserialize, Serialize};
+use web3::types::{Address, H256};
+// Workspace deps
+use zksync_crypto::convert::FeConvert;
+use zksync_types::account::{Account, PubKeyHash};
+use zksync_types::{AccountId, BlockNumber, Nonce, TokenId};
+// Local deps
+use crate::events::{BlockEvent, EventType};
+use crate::events_state::EventsState;
+use crate::tree_state::TreeState;
+
+/// Magic bytes every binary snapshot file starts with
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"ZKDR";
+/// Version of the binary snapshot format, stored right after the magic bytes
+pub const SNAPSHOT_VERSION: u8 = 3;
+
+/// Account as stored in the binary snapshot.
+///
+/// Account serde representation is built for the human-readable formats
+/// (hex strings, decimal balances), so the raw bytes are stored instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AccountRecord {
+    id: AccountId,
+    address: [u8; 20],
+    pub_key_hash: [u8; 20],
+    nonce: Nonce,
+    /// Nonzero balances in the little-endian bytes representation
+    balances: Vec<(TokenId, Vec<u8>)>,
+}
+
+/// Rollup block event as stored in the binary snapshot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BlockEventRecord {
+    block_num: u32,
+    transaction_hash: [u8; 32],
+    verified: bool,
+}
+
+/// Position of the contract events scan, stored so the restarted process
+/// continues the scan right after the last watched Ethereum block
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ScanCursorRecord {
+    last_watched_eth_block_number: u64,
+    /// Committed and verified block events that are not applied yet
+    events: Vec<BlockEventRecord>,
+}
+
+/// Consistent copy of the rollup state as of a specific block.
+///
+/// Capturing the snapshot only copies the accounts data, while the slow serialization
+/// and writing may be done separately (e.g. in another thread), so the live tree state
+/// can continue applying new blocks. The saved snapshot always reflects the state at
+/// the block it was captured at, regardless of the blocks applied afterwards.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    block_number: BlockNumber,
+    current_unprocessed_priority_op: u64,
+    last_fee_account_address: [u8; 20],
+    /// Tree root hash at the capture time, checked against the rebuilt tree on load
+    root_hash: Vec<u8>,
+    accounts: Vec<AccountRecord>,
+    /// Contract events scan position, if captured along with the tree state
+    scan_cursor: Option<ScanCursorRecord>,
+}
+
+impl StateSnapshot {
+    /// Captures the current tree state
+    ///
+    /// # Arguments
+    ///
+    /// * `tree_state` - Rollup accounts states
+    ///
+    pub fn capture(tree_state: &TreeState) -> Self {
+        let mut accounts: Vec<_> = tree_state
+            .get_accounts()
+            .into_iter()
+            .map(|(id, account)| {
+                let mut balances: Vec<_> = account
+                    .get_nonzero_balances()
+                    .into_iter()
+                    .map(|(token, balance)| (token, balance.0.to_bytes_le()))
+                    .collect();
+                balances.sort_unstable();
+                AccountRecord {
+                    id,
+                    address: account.address.to_fixed_bytes(),
+                    pub_key_hash: account.pub_key_hash.data,
+                    nonce: account.nonce,
+                    balances,
+                }
+            })
+            .collect();
+        accounts.sort_unstable_by_key(|account| account.id);
+
+        Self {
+            block_number: tree_state.state.block_number,
+            current_unprocessed_priority_op: tree_state.current_unprocessed_priority_op,
+            last_fee_account_address: tree_state.last_fee_account_address.to_fixed_bytes(),
+            root_hash: tree_state.root_hash().to_bytes(),
+            accounts,
+            scan_cursor: None,
+        }
+    }
+
+    /// Adds the contract events scan position to the snapshot.
+    /// The events state must be captured at the same time as the tree state,
+    /// so the restored scan neither skips nor repeats any events
+    ///
+    /// # Arguments
+    ///
+    /// * `events_state` - Rollup contract events state
+    ///
+    pub fn with_events_state(mut self, events_state: &EventsState) -> Self {
+        let committed = events_state
+            .committed_events
+            .iter()
+            .map(|event| (event, false));
+        let verified = events_state
+            .verified_events
+            .iter()
+            .map(|event| (event, true));
+        let events = committed
+            .chain(verified)
+            .map(|(event, verified)| BlockEventRecord {
+                block_num: event.block_num,
+                transaction_hash: event.transaction_hash.to_fixed_bytes(),
+                verified,
+            })
+            .collect();
+        self.scan_cursor = Some(ScanCursorRecord {
+            last_watched_eth_block_number: events_state.last_watched_eth_block_number,
+            events,
+        });
+        self
+    }
+
+    /// Returns the rollup block number the snapshot reflects
+    pub fn block_number(&self) -> BlockNumber {
+        self.block_number
+    }
+
+    /// Saves the snapshot into the compact binary snapshot file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Snapshot file path
+    ///
+    pub fn save_binary(&self, path: impl AsRef<Path>) -> Result<(), anyhow::Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&SNAPSHOT_MAGIC)?;
+        writer.write_all(&[SNAPSHOT_VERSION])?;
+        bincode::serialize_into(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Saves the tree state into the compact binary snapshot file
+///
+/// # Arguments
+///
+/// * `tree_state` - Rollup accounts states
+/// * `path` - Snapshot file path
+///
+pub fn save_snapshot_binary(
+    tree_state: &TreeState,
+    path: impl AsRef<Path>,
+) -> Result<(), anyhow::Error> {
+    StateSnapshot::capture(tree_state).save_binary(path)
+}
+
+/// Saves the tree state and the contract events scan position into the binary snapshot file
+///
+/// # Arguments
+///
+/// * `tree_state` - Rollup accounts states
+/// * `events_state` - Rollup contract events state
+/// * `path` - Snapshot file path
+///
+pub fn save_snapshot_binary_with_events(
+    tree_state: &TreeState,
+    events_state: &EventsState,
+    path: impl AsRef<Path>,
+) -> Result<(), anyhow::Error> {
+    StateSnapshot::capture(tree_state)
+        .with_events_state(events_state)
+        .save_binary(path)
+}
+
+/// Loads the tree state from the binary snapshot file.
+/// Files of the other formats or versions are rejected, as well as the snapshots
+/// whose rebuilt tree root hash differs from the one stored at the save time.
+///
+/// # Arguments
+///
+/// * `path` - Snapshot file path
+/// * `available_block_chunk_sizes` - Available block chunk sizes
+///
+pub fn load_snapshot_binary(
+    path: impl AsRef<Path>,
+    available_block_chunk_sizes: Vec<usize>,
+) -> Result<TreeState, anyhow::Error> {
+    load_snapshot_binary_with_events(path, available_block_chunk_sizes)
+        .map(|(tree_state, _)| tree_state)
+}
+
+/// Loads the tree state and the contract events scan position, if it's stored,
+/// from the binary snapshot file. The restored events state continues the scan
+/// right after the last Ethereum block watched before the snapshot was captured
+///
+/// # Arguments
+///
+/// * `path` - Snapshot file path
+/// * `available_block_chunk_sizes` - Available block chunk sizes
+///
+pub fn load_snapshot_binary_with_events(
+    path: impl AsRef<Path>,
+    available_block_chunk_sizes: Vec<usize>,
+) -> Result<(TreeState, Option<EventsState>), anyhow::Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut header = [0u8; 5];
+    reader
+        .read_exact(&mut header)
+        .map_err(|_| format_err!("Snapshot file is too short"))?;
+    ensure!(
+        header[..4] == SNAPSHOT_MAGIC,
+        "File is not a data restore binary snapshot"
+    );
+    ensure!(
+        header[4] == SNAPSHOT_VERSION,
+        "Unsupported snapshot version {}, expected {}",
+        header[4],
+        SNAPSHOT_VERSION
+    );
+    let snapshot: StateSnapshot = bincode::deserialize_from(reader)?;
+
+    let mut accounts = Vec::with_capacity(snapshot.accounts.len());
+    for record in snapshot.accounts {
+        let mut account = Account::default_with_address(&Address::from(record.address));
+        account.pub_key_hash = PubKeyHash {
+            data: record.pub_key_hash,
+        };
+        account.nonce = record.nonce;
+        for (token, balance) in record.balances {
+            account.set_balance(token, BigUint::from_bytes_le(&balance));
+        }
+        accounts.push((record.id, account));
+    }
+
+    let mut tree_state = TreeState::new(available_block_chunk_sizes);
+    tree_state.state.block_number = snapshot.block_number;
+    tree_state.rebuild_tree(accounts)?;
+    ensure!(
+        tree_state.root_hash().to_bytes() == snapshot.root_hash,
+        "Snapshot is corrupted: the rebuilt tree root hash doesn't match the stored one"
+    );
+    tree_state.current_unprocessed_priority_op = snapshot.current_unprocessed_priority_op;
+    tree_state.last_fee_account_address = Address::from(snapshot.last_fee_account_address);
+
+    let events_state = snapshot.scan_cursor.map(|cursor| {
+        let mut events_state = EventsState::default();
+        events_state.last_watched_eth_block_number = cursor.last_watched_eth_block_number;
+        for record in cursor.events {
+            let block_type = if record.verified {
+                EventType::Verified
+            } else {
+                EventType::Committed
+            };
+            let event = BlockEvent {
+                block_num: record.block_num,
+                transaction_hash: H256::from(record.transaction_hash),
+                block_type,
+            };
+            match block_type {
+                EventType::Committed => events_state.committed_events.push(event),
+                EventType::Verified => events_state.verified_events.push(event),
+            }
+        }
+        events_state
+    });
+    Ok((tree_state, events_state))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        load_snapshot_binary, load_snapshot_binary_with_events, save_snapshot_binary,
+        save_snapshot_binary_with_events, StateSnapshot, SNAPSHOT_VERSION,
+    };
+    use crate::events::{BlockEvent, EventType};
+    use crate::events_state::EventsState;
+    use crate::tests::utils::{deposit_block, temp_path};
+    use crate::tree_state::TreeState;
+    use num::BigUint;
+    use std::time::Instant;
+    use zksync_types::account::PubKeyHash;
+    use zksync_types::{Account, AccountMap};
+
+    fn tree_state() -> TreeState {
+        let mut accounts = AccountMap::default();
+        for id in 0..10u32 {
+            let mut account = Account::default_with_address(&[id as u8; 20].into());
+            account.nonce = id * 2;
+            account.pub_key_hash = PubKeyHash {
+                data: [id as u8 + 1; 20],
+            };
+            account.set_balance(0, BigUint::from(id) * BigUint::from(10u64.pow(18)));
+            account.set_balance(id as u16, BigUint::from(u128::max_value()));
+            accounts.insert(id, account);
+        }
+        TreeState::load(5, accounts, 3, 0, vec![50])
+    }
+
+    #[test]
+    fn binary_snapshot_round_trip() {
+        let tree_state = tree_state();
+        let path = temp_path("data_restore_snapshot_round_trip.bin");
+        save_snapshot_binary(&tree_state, &path).expect("Can't save snapshot");
+
+        let loaded = load_snapshot_binary(&path, vec![50]).expect("Can't load snapshot");
+        assert_eq!(loaded.root_hash(), tree_state.root_hash());
+        assert_eq!(loaded.state.block_number, 5);
+        assert_eq!(loaded.current_unprocessed_priority_op, 3);
+        assert_eq!(
+            loaded.last_fee_account_address,
+            tree_state.last_fee_account_address
+        );
+        assert_eq!(loaded.get_accounts().len(), 10);
+        assert_eq!(
+            loaded.get_account(7).unwrap().get_balance(7),
+            BigUint::from(u128::max_value())
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Compares the binary snapshot with the JSON dump of the same accounts.
+    /// Run with `cargo test binary_snapshot_vs_json -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn binary_snapshot_vs_json() {
+        let mut accounts = AccountMap::default();
+        for id in 0..10_000u32 {
+            let mut account = Account::default_with_address(&[id as u8; 20].into());
+            account.nonce = id;
+            account.set_balance(0, BigUint::from(id) * BigUint::from(10u64.pow(18)));
+            account.set_balance(1 + id as u16 % 10, BigUint::from(u64::max_value()));
+            accounts.insert(id, account);
+        }
+        let tree_state = TreeState::load(1, accounts, 0, 0, vec![50]);
+        let snapshot = StateSnapshot::capture(&tree_state);
+        let accounts = tree_state.get_accounts();
+
+        let started = Instant::now();
+        let binary = bincode::serialize(&snapshot).unwrap();
+        let _: StateSnapshot = bincode::deserialize(&binary).unwrap();
+        let binary_time = started.elapsed();
+
+        let started = Instant::now();
+        let json = serde_json::to_vec(&accounts).unwrap();
+        let _: Vec<(u32, Account)> = serde_json::from_slice(&json).unwrap();
+        let json_time = started.elapsed();
+
+        println!(
+            "Binary snapshot: {} bytes, {:?}; JSON: {} bytes, {:?}",
+            binary.len(),
+            binary_time,
+            json.len(),
+            json_time
+        );
+        assert!(binary.len() < json.len());
+    }
+
+    #[test]
+    fn binary_snapshot_version_mismatch() {
+        let path = temp_path("data_restore_snapshot_version.bin");
+        save_snapshot_binary(&tree_state(), &path).expect("Can't save snapshot");
+
+        let mut content = std::fs::read(&path).unwrap();
+        content[4] = SNAPSHOT_VERSION + 1;
+        std::fs::write(&path, &content).unwrap();
+        let err = load_snapshot_binary(&path, vec![50])
+            .err()
+            .expect("Snapshot of the other version must be rejected");
+        assert!(err.to_string().contains("Unsupported snapshot version"));
+
+        std::fs::write(&path, br#"{"accounts":[]}"#).unwrap();
+        assert!(load_snapshot_binary(&path, vec![50]).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupted_binary_snapshot() {
+        let path = temp_path("data_restore_snapshot_corrupted.bin");
+        save_snapshot_binary(&tree_state(), &path).expect("Can't save snapshot");
+
+        // Every account holds the maximum `u128` balance, stored as 16 `0xff` bytes,
+        // while none of the other fields contains such bytes.
+        let mut content = std::fs::read(&path).unwrap();
+        let balance_offset = content
+            .windows(16)
+            .position(|window| window.iter().all(|&byte| byte == 0xff))
+            .expect("Balance must be stored in the snapshot");
+        content[balance_offset] ^= 1;
+        std::fs::write(&path, &content).unwrap();
+        let err = load_snapshot_binary(&path, vec![50])
+            .err()
+            .expect("Corrupted snapshot must be rejected");
+        assert!(err.to_string().contains("Snapshot is corrupted"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn snapshot_while_applying_blocks() {
+        let mut tree_state = TreeState::new(vec![50]);
+        tree_state
+            .update_tree_states_from_ops_block(&deposit_block(1))
+            .expect("Cant update state from block 1");
+        let snapshot_root_hash = tree_state.root_hash();
+
+        let snapshot = StateSnapshot::capture(&tree_state);
+        assert_eq!(snapshot.block_number(), 1);
+        let path = temp_path("data_restore_snapshot_concurrent.bin");
+        let writer = {
+            let path = path.clone();
+            std::thread::spawn(move || snapshot.save_binary(path))
+        };
+        for block_num in 2..5 {
+            tree_state
+                .update_tree_states_from_ops_block(&deposit_block(block_num))
+                .expect("Cant update state");
+        }
+        writer.join().unwrap().expect("Can't save snapshot");
+
+        let loaded = load_snapshot_binary(&path, vec![50]).expect("Can't load snapshot");
+        assert_eq!(loaded.state.block_number, 1);
+        assert_eq!(loaded.root_hash(), snapshot_root_hash);
+        assert_eq!(loaded.get_accounts().len(), 1);
+        assert_eq!(tree_state.state.block_number, 4);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn binary_snapshot_with_scan_cursor() {
+        let event = |block_num: u32, block_type| BlockEvent {
+            block_num,
+            transaction_hash: [block_num as u8; 32].into(),
+            block_type,
+        };
+        // Blocks 1-2 are applied, while the block 3 is committed but not applied yet.
+        let mut events_state = EventsState::default();
+        events_state.last_watched_eth_block_number = 120;
+        events_state.committed_events = vec![
+            event(1, EventType::Committed),
+            event(2, EventType::Committed),
+            event(3, EventType::Committed),
+        ];
+        events_state.verified_events =
+            vec![event(1, EventType::Verified), event(2, EventType::Verified)];
+        let tree_state = tree_state();
+        let path = temp_path("data_restore_snapshot_scan_cursor.bin");
+        save_snapshot_binary_with_events(&tree_state, &events_state, &path)
+            .expect("Can't save snapshot");
+
+        // The restarted process continues the scan from the stored position.
+        let (loaded, loaded_events) =
+            load_snapshot_binary_with_events(&path, vec![50]).expect("Can't load snapshot");
+        assert_eq!(loaded.root_hash(), tree_state.root_hash());
+        let loaded_events = loaded_events.expect("Scan cursor must be stored");
+        assert_eq!(loaded_events.last_watched_eth_block_number, 120);
+        let block_numbers = |events: &[BlockEvent]| -> Vec<u32> {
+            events.iter().map(|event| event.block_num).collect()
+        };
+        assert_eq!(
+            block_numbers(&loaded_events.committed_events),
+            vec![1, 2, 3]
+        );
+        assert_eq!(block_numbers(&loaded_events.verified_events), vec![1, 2]);
+        assert!(loaded_events
+            .committed_events
+            .iter()
+            .all(|event| event.block_type == EventType::Committed));
+        assert_eq!(
+            loaded_events.committed_events[2].transaction_hash,
+            [3u8; 32].into()
+        );
+
+        // Snapshots saved without the events state don't have the scan cursor.
+        save_snapshot_binary(&tree_state, &path).expect("Can't save snapshot");
+        let (_, loaded_events) =
+            load_snapshot_binary_with_events(&path, vec![50]).expect("Can't load snapshot");
+        assert!(loaded_events.is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -6,7 +6,8 @@ use zksync_storage::data_restore::records::{
     NewBlockEvent, StoredBlockEvent, StoredRollupOpsBlock,
 };
 use zksync_types::{
-    block::Block, AccountMap, AccountUpdate, AccountUpdates, TokenGenesisListItem, TokenId,
+    block::Block, AccountId, AccountMap, AccountUpdate, AccountUpdates, TokenGenesisListItem,
+    TokenId,
 };
 
 use crate::{
@@ -88,6 +89,29 @@ pub trait StorageInteractor {
 
     /// Returns last recovery state update step from storage
     async fn get_storage_state(&mut self) -> StorageUpdateState;
+
+    /// Sets the precision of the stored token.
+    /// `NewToken` events don't carry the token precision, so tokens added from
+    /// them have 18 decimals unless configured explicitly.
+    /// Returns `false` if there is no such token
+    ///
+    /// # Arguments
+    ///
+    /// * `token_id` - Token id
+    /// * `decimals` - Token precision
+    ///
+    async fn set_token_decimals(&mut self, token_id: TokenId, decimals: u8) -> bool;
+
+    /// Returns the stored account balance formatted according to the token precision,
+    /// e.g. "1.5" for the balance of 1500000 in a token with 6 decimals.
+    /// Returns `None` if there is no such account or token
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Account id
+    /// * `token_id` - Token id
+    ///
+    async fn get_formatted_balance(&mut self, id: AccountId, token_id: TokenId) -> Option<String>;
 }
 
 /// Returns Rollup contract event from its stored representation
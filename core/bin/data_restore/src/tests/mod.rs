@@ -1,6 +1,9 @@
 pub(crate) mod utils;
 
 use std::cmp::max;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{collections::HashMap, future::Future};
 
 use chrono::Utc;
@@ -23,10 +26,12 @@ use zksync_types::{
 };
 
 use crate::{
+    archive_transport::{ArchiveTransport, RecordingTransport},
     data_restore_driver::DataRestoreDriver,
     database_storage_interactor::DatabaseStorageInteractor,
     inmemory_storage_interactor::InMemoryStorageInteractor,
-    tests::utils::{create_log, u32_to_32bytes},
+    ops_blocks_wal::OpsBlocksWal,
+    tests::utils::{create_log, temp_path, u32_to_32bytes},
     END_ETH_BLOCKS_OFFSET, ETH_BLOCKS_STEP,
 };
 
@@ -53,6 +58,15 @@ fn create_withdraw_operations(
 }
 
 fn create_deposit(from: Address, to: Address, amount: u32) -> ExecutedOperations {
+    create_deposit_to_account(0, from, to, amount)
+}
+
+fn create_deposit_to_account(
+    account_id: u32,
+    from: Address,
+    to: Address,
+    amount: u32,
+) -> ExecutedOperations {
     let deposit_op = ZkSyncOp::Deposit(Box::new(DepositOp {
         priority_op: Deposit {
             from,
@@ -60,7 +74,7 @@ fn create_deposit(from: Address, to: Address, amount: u32) -> ExecutedOperations
             amount: amount.into(),
             to,
         },
-        account_id: 0,
+        account_id,
     }));
     let priority_operation = PriorityOp {
         serial_id: 0,
@@ -201,6 +215,7 @@ impl Transport for Web3Transport {
                 };
                 match req.method.as_str() {
                     "eth_blockNumber" => Ok(json!("0x80")),
+                    "net_version" => Ok(json!("9")),
                     "eth_getLogs" => {
                         let filter = params.pop().unwrap();
                         Ok(json!(self.get_logs(filter)))
@@ -228,6 +243,112 @@ impl Transport for Web3Transport {
     }
 }
 
+/// Transport of the mock chain that can grow while the driver follows it
+#[derive(Debug, Clone)]
+struct FollowTransport {
+    transport: Arc<Mutex<Web3Transport>>,
+    head_block_number: Arc<AtomicU64>,
+}
+
+impl FollowTransport {
+    /// Replaces the logs returned by the mock chain with the logs of the new block
+    fn add_block(&self, block_number: u32) {
+        let contract = zksync_contract();
+        let mut mock = self.transport.lock().unwrap();
+        for event in &["BlockVerification", "BlockCommit"] {
+            let topic = contract
+                .event(event)
+                .expect("Main contract abi error")
+                .signature();
+            mock.insert_logs(
+                format!("{:?}", topic),
+                vec![create_log(
+                    topic,
+                    vec![u32_to_32bytes(block_number).into()],
+                    Bytes(vec![]),
+                    block_number,
+                    u32_to_32bytes(block_number).into(),
+                )],
+            );
+        }
+        mock.push_transactions(vec![create_transaction(
+            block_number,
+            create_block(
+                block_number,
+                vec![create_deposit(Default::default(), Default::default(), 50)],
+            ),
+        )]);
+    }
+}
+
+impl Transport for FollowTransport {
+    type Out = <Web3Transport as Transport>::Out;
+
+    fn prepare(
+        &self,
+        method: &str,
+        params: Vec<jsonrpc_core::Value>,
+    ) -> (RequestId, jsonrpc_core::Call) {
+        self.transport.lock().unwrap().prepare(method, params)
+    }
+
+    fn send(&self, id: RequestId, request: jsonrpc_core::Call) -> Self::Out {
+        match &request {
+            jsonrpc_core::Call::MethodCall(call) if call.method == "eth_blockNumber" => {
+                let head_block_number = self.head_block_number.load(Ordering::SeqCst);
+                Box::new(future::ready(Ok(json!(format!(
+                    "{:#x}",
+                    head_block_number
+                )))))
+            }
+            _ => self.transport.lock().unwrap().send(id, request),
+        }
+    }
+}
+
+/// Transport of the mock chain returning only the logs of the requested blocks range
+#[derive(Debug, Clone)]
+struct BlocksRangeTransport(Web3Transport);
+
+impl Transport for BlocksRangeTransport {
+    type Out = <Web3Transport as Transport>::Out;
+
+    fn prepare(
+        &self,
+        method: &str,
+        params: Vec<jsonrpc_core::Value>,
+    ) -> (RequestId, jsonrpc_core::Call) {
+        self.0.prepare(method, params)
+    }
+
+    fn send(&self, id: RequestId, request: jsonrpc_core::Call) -> Self::Out {
+        let filter = match &request {
+            jsonrpc_core::Call::MethodCall(call) if call.method == "eth_getLogs" => {
+                match &call.params {
+                    Params::Array(params) => params[0].clone(),
+                    _ => unreachable!(),
+                }
+            }
+            _ => return self.0.send(id, request),
+        };
+        let block_number = |key: &str| {
+            let number = filter.get(key).unwrap().as_str().unwrap();
+            u64::from_str_radix(number.trim_start_matches("0x"), 16).unwrap()
+        };
+        let (from_block, to_block) = (block_number("fromBlock"), block_number("toBlock"));
+        let logs: Vec<Log> = self
+            .0
+            .get_logs(filter.clone())
+            .into_iter()
+            .filter(|log| {
+                let block_number = log.block_number.unwrap().as_u64();
+                from_block <= block_number && block_number <= to_block
+            })
+            .collect();
+        Box::new(future::ready(Ok(json!(logs))))
+    }
+}
+
 #[db_test]
 async fn test_run_state_update(mut storage: StorageProcessor<'_>) {
     let mut transport = Web3Transport::new();
@@ -519,3 +640,529 @@ async fn test_with_inmemory_storage() {
     assert_eq!(driver.events_state.committed_events.len(), events.len());
     assert_eq!(driver.tree_state.state.block_number, 2)
 }
+
+#[tokio::test]
+async fn test_stop_state_update() {
+    let mut transport = Web3Transport::new();
+
+    let mut interactor = InMemoryStorageInteractor::new();
+    let contract = zksync_contract();
+
+    let block_verified_topic = contract
+        .event("BlockVerification")
+        .expect("Main contract abi error")
+        .signature();
+    let block_committed_topic = contract
+        .event("BlockCommit")
+        .expect("Main contract abi error")
+        .signature();
+    for topic in &[block_verified_topic, block_committed_topic] {
+        transport.insert_logs(
+            format!("{:?}", topic),
+            vec![create_log(
+                *topic,
+                vec![u32_to_32bytes(1).into()],
+                Bytes(vec![]),
+                1,
+                u32_to_32bytes(1).into(),
+            )],
+        );
+    }
+    transport.push_transactions(vec![create_transaction(
+        1,
+        create_block(
+            1,
+            vec![create_deposit(Default::default(), Default::default(), 50)],
+        ),
+    )]);
+
+    // Driver is not in the finite mode, so it can be only stopped explicitly.
+    let mut driver = DataRestoreDriver::new(
+        transport.clone(),
+        [1u8; 20].into(),
+        [1u8; 20].into(),
+        ETH_BLOCKS_STEP,
+        END_ETH_BLOCKS_OFFSET,
+        vec![6, 30],
+        false,
+        None,
+    );
+    let stop_handle = driver.stop_handle();
+    tokio::spawn(async move {
+        tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+        stop_handle.stop();
+    });
+    driver.run_state_update(&mut interactor).await;
+
+    // The restored block is saved, so the restore can be continued from storage.
+    assert_eq!(driver.tree_state.state.block_number, 1);
+    let (_, account) = interactor
+        .get_account_by_address(&Default::default())
+        .unwrap();
+    assert_eq!(account.get_balance(0), BigUint::from(50u32));
+
+    let mut driver = DataRestoreDriver::new(
+        transport,
+        [1u8; 20].into(),
+        [1u8; 20].into(),
+        ETH_BLOCKS_STEP,
+        END_ETH_BLOCKS_OFFSET,
+        vec![6, 30],
+        true,
+        None,
+    );
+    assert!(driver.load_state_from_storage(&mut interactor).await);
+    assert_eq!(driver.tree_state.state.block_number, 1);
+}
+
+#[tokio::test]
+async fn test_verify_chain_id() {
+    let mut driver: DataRestoreDriver<Web3Transport, InMemoryStorageInteractor> =
+        DataRestoreDriver::new(
+            Web3Transport::new(),
+            [1u8; 20].into(),
+            [1u8; 20].into(),
+            ETH_BLOCKS_STEP,
+            END_ETH_BLOCKS_OFFSET,
+            vec![6, 30],
+            true,
+            None,
+        );
+    // The chain isn't checked unless the expected one is set.
+    assert!(driver.verify_chain_id().await.is_ok());
+
+    driver.expected_chain_id = Some(9);
+    assert!(driver.verify_chain_id().await.is_ok());
+
+    driver.expected_chain_id = Some(1);
+    let err = driver
+        .verify_chain_id()
+        .await
+        .err()
+        .expect("Chain id mismatch must be reported");
+    assert!(err.to_string().contains("the chain 1 is expected"));
+}
+
+#[tokio::test]
+async fn test_lag_behind_head() {
+    let mut driver: DataRestoreDriver<Web3Transport, InMemoryStorageInteractor> =
+        DataRestoreDriver::new(
+            Web3Transport::new(),
+            [1u8; 20].into(),
+            [1u8; 20].into(),
+            ETH_BLOCKS_STEP,
+            END_ETH_BLOCKS_OFFSET,
+            vec![6, 30],
+            true,
+            None,
+        );
+    // The mock chain head is the block 128.
+    driver.events_state.last_watched_eth_block_number = 28;
+    assert_eq!(driver.lag_behind_head().await.unwrap(), 100);
+
+    driver.events_state.last_watched_eth_block_number = 128;
+    assert_eq!(driver.lag_behind_head().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_follow_head_polling() {
+    let transport = FollowTransport {
+        transport: Arc::new(Mutex::new(Web3Transport::new())),
+        head_block_number: Arc::new(AtomicU64::new(0x80)),
+    };
+    transport.add_block(1);
+
+    let mut interactor = InMemoryStorageInteractor::new();
+    let mut driver = DataRestoreDriver::new(
+        transport.clone(),
+        [1u8; 20].into(),
+        [1u8; 20].into(),
+        ETH_BLOCKS_STEP,
+        END_ETH_BLOCKS_OFFSET,
+        vec![6, 30],
+        false,
+        None,
+    );
+    driver.poll_interval = Duration::from_millis(10);
+    let stop_handle = driver.stop_handle();
+
+    let chain = async {
+        // The first poll restores the block 1, the next block appears on the chain later.
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        transport.add_block(2);
+        transport.head_block_number.store(0x100, Ordering::SeqCst);
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        stop_handle.stop();
+    };
+    future::join(driver.run_state_update(&mut interactor), chain).await;
+
+    assert_eq!(driver.tree_state.state.block_number, 2);
+    let (_, account) = interactor
+        .get_account_by_address(&Default::default())
+        .unwrap();
+    assert_eq!(account.get_balance(0), BigUint::from(100u32));
+}
+
+#[tokio::test]
+async fn test_graceful_shutdown() {
+    let transport = FollowTransport {
+        transport: Arc::new(Mutex::new(Web3Transport::new())),
+        head_block_number: Arc::new(AtomicU64::new(0x80)),
+    };
+    transport.add_block(1);
+    let snapshot_path = temp_path("data_restore_shutdown.snapshot");
+    let wal_path = temp_path("data_restore_shutdown.wal");
+
+    let mut interactor = InMemoryStorageInteractor::new();
+    let mut driver = DataRestoreDriver::new(
+        transport.clone(),
+        [1u8; 20].into(),
+        [1u8; 20].into(),
+        ETH_BLOCKS_STEP,
+        END_ETH_BLOCKS_OFFSET,
+        vec![6, 30],
+        false,
+        None,
+    );
+    driver.poll_interval = Duration::from_millis(10);
+    driver.ops_blocks_wal = Some(OpsBlocksWal::open(&wal_path).unwrap());
+    let stop_handle = driver.stop_handle();
+    let stop = async {
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        stop_handle.stop();
+    };
+    future::join(driver.run_state_update(&mut interactor), stop).await;
+    // The restored blocks are saved in storage, so there is nothing to replay.
+    assert!(driver
+        .ops_blocks_wal
+        .as_ref()
+        .unwrap()
+        .blocks()
+        .unwrap()
+        .is_empty());
+    driver
+        .shutdown(&snapshot_path)
+        .expect("Cant save the state on shutdown");
+
+    transport.add_block(2);
+    transport.head_block_number.store(0x100, Ordering::SeqCst);
+
+    let mut resumed_driver = DataRestoreDriver::new(
+        transport.clone(),
+        [1u8; 20].into(),
+        [1u8; 20].into(),
+        ETH_BLOCKS_STEP,
+        END_ETH_BLOCKS_OFFSET,
+        vec![6, 30],
+        true,
+        None,
+    );
+    // The block 2 is verified on the contract, so the finite mode restore isn't completed.
+    assert!(!resumed_driver
+        .resume_from_snapshot(&mut interactor, &snapshot_path)
+        .await
+        .expect("Cant resume from the snapshot"));
+    assert_eq!(resumed_driver.tree_state.state.block_number, 1);
+    assert_eq!(
+        resumed_driver.tree_state.root_hash(),
+        driver.tree_state.root_hash()
+    );
+    assert_eq!(
+        resumed_driver.events_state.last_watched_eth_block_number,
+        driver.events_state.last_watched_eth_block_number
+    );
+
+    // The resumed driver continues with the block 2, the block 1 isn't applied twice.
+    resumed_driver.run_state_update(&mut interactor).await;
+    assert_eq!(resumed_driver.tree_state.state.block_number, 2);
+    let (_, account) = resumed_driver
+        .tree_state
+        .get_account_by_address(&Default::default())
+        .unwrap();
+    assert_eq!(account.get_balance(0), BigUint::from(100u32));
+
+    // The snapshot is stale once the block 2 is stored, so the state is loaded from storage.
+    let mut stale_driver = DataRestoreDriver::new(
+        transport,
+        [1u8; 20].into(),
+        [1u8; 20].into(),
+        ETH_BLOCKS_STEP,
+        END_ETH_BLOCKS_OFFSET,
+        vec![6, 30],
+        true,
+        None,
+    );
+    assert!(stale_driver
+        .resume_from_snapshot(&mut interactor, &snapshot_path)
+        .await
+        .expect("Cant resume from the stale snapshot"));
+    assert_eq!(stale_driver.tree_state.state.block_number, 2);
+    assert_eq!(
+        stale_driver.tree_state.root_hash(),
+        resumed_driver.tree_state.root_hash()
+    );
+
+    std::fs::remove_file(&snapshot_path).unwrap();
+    std::fs::remove_file(&wal_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_restore_as_of() {
+    let mut transport = Web3Transport::new();
+    let contract = zksync_contract();
+    // Rollup blocks 1 and 2 are committed and verified in the ethereum blocks 10 and 50.
+    for event in &["BlockCommit", "BlockVerification"] {
+        let topic = contract
+            .event(event)
+            .expect("Main contract abi error")
+            .signature();
+        transport.insert_logs(
+            format!("{:?}", topic),
+            vec![
+                create_log(
+                    topic,
+                    vec![u32_to_32bytes(1).into()],
+                    Bytes(vec![]),
+                    10,
+                    u32_to_32bytes(1).into(),
+                ),
+                create_log(
+                    topic,
+                    vec![u32_to_32bytes(2).into()],
+                    Bytes(vec![]),
+                    50,
+                    u32_to_32bytes(2).into(),
+                ),
+            ],
+        );
+    }
+    transport.push_transactions(vec![
+        create_transaction(
+            1,
+            create_block(
+                1,
+                vec![create_deposit(Default::default(), Default::default(), 50)],
+            ),
+        ),
+        create_transaction(
+            2,
+            create_block(
+                2,
+                vec![create_deposit(Default::default(), Default::default(), 50)],
+            ),
+        ),
+    ]);
+    let transport = BlocksRangeTransport(transport);
+
+    let restore_as_of = |eth_block_number| {
+        let transport = transport.clone();
+        async move {
+            let mut driver = DataRestoreDriver::new(
+                transport,
+                [1u8; 20].into(),
+                [1u8; 20].into(),
+                ETH_BLOCKS_STEP,
+                END_ETH_BLOCKS_OFFSET,
+                vec![6, 30],
+                true,
+                None,
+            );
+            let mut interactor = InMemoryStorageInteractor::new();
+            driver
+                .restore_as_of(&mut interactor, eth_block_number)
+                .await
+                .expect("Cant restore the state");
+            assert_eq!(
+                driver.events_state.last_watched_eth_block_number,
+                eth_block_number
+            );
+            driver
+        }
+    };
+
+    let first_driver = restore_as_of(20).await;
+    assert_eq!(first_driver.tree_state.state.block_number, 1);
+    let second_driver = restore_as_of(60).await;
+    assert_eq!(second_driver.tree_state.state.block_number, 2);
+    assert_ne!(
+        first_driver.tree_state.root_hash(),
+        second_driver.tree_state.root_hash()
+    );
+
+    // The state can't be restored back in time.
+    let mut driver = restore_as_of(60).await;
+    assert!(driver
+        .restore_as_of(&mut InMemoryStorageInteractor::new(), 20)
+        .await
+        .is_err());
+    // The mock chain head is the block 128, so the block 100 isn't reached yet.
+    assert!(driver
+        .restore_as_of(&mut InMemoryStorageInteractor::new(), 100)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_pending_priority_accounts() {
+    let mut transport = Web3Transport::new();
+    let contract = zksync_contract();
+    let event_log = |event, block_number: u32, eth_block_number| {
+        let topic = contract
+            .event(event)
+            .expect("Main contract abi error")
+            .signature();
+        create_log(
+            topic,
+            vec![u32_to_32bytes(block_number).into()],
+            Bytes(vec![]),
+            eth_block_number,
+            u32_to_32bytes(block_number).into(),
+        )
+    };
+    // Rollup blocks 1 and 2 are committed, but only the block 1 is verified.
+    for (event, logs) in vec![
+        (
+            "BlockCommit",
+            vec![
+                event_log("BlockCommit", 1, 10),
+                event_log("BlockCommit", 2, 50),
+            ],
+        ),
+        (
+            "BlockVerification",
+            vec![event_log("BlockVerification", 1, 10)],
+        ),
+    ] {
+        let topic = contract
+            .event(event)
+            .expect("Main contract abi error")
+            .signature();
+        transport.insert_logs(format!("{:?}", topic), logs);
+    }
+    transport.push_transactions(vec![
+        create_transaction(
+            1,
+            create_block(
+                1,
+                vec![create_deposit_to_account(
+                    0,
+                    Default::default(),
+                    [1u8; 20].into(),
+                    50,
+                )],
+            ),
+        ),
+        create_transaction(
+            2,
+            create_block(
+                2,
+                vec![create_deposit_to_account(
+                    1,
+                    Default::default(),
+                    [2u8; 20].into(),
+                    50,
+                )],
+            ),
+        ),
+    ]);
+
+    let mut driver = DataRestoreDriver::new(
+        BlocksRangeTransport(transport),
+        [1u8; 20].into(),
+        [1u8; 20].into(),
+        ETH_BLOCKS_STEP,
+        END_ETH_BLOCKS_OFFSET,
+        vec![6, 30],
+        true,
+        None,
+    );
+    let mut interactor = InMemoryStorageInteractor::new();
+    driver
+        .restore_as_of(&mut interactor, 60)
+        .await
+        .expect("Cant restore the state");
+    assert_eq!(driver.tree_state.state.block_number, 1);
+
+    // Only the deposit of the unverified block is pending.
+    let pending_accounts = driver
+        .pending_priority_accounts()
+        .await
+        .expect("Cant get the pending accounts");
+    assert_eq!(pending_accounts.into_iter().collect::<Vec<_>>(), vec![1]);
+}
+
+#[tokio::test]
+async fn test_restore_from_archive() {
+    let mut transport = Web3Transport::new();
+    let contract = zksync_contract();
+    for event in &["BlockCommit", "BlockVerification"] {
+        let topic = contract
+            .event(event)
+            .expect("Main contract abi error")
+            .signature();
+        transport.insert_logs(
+            format!("{:?}", topic),
+            vec![create_log(
+                topic,
+                vec![u32_to_32bytes(1).into()],
+                Bytes(vec![]),
+                1,
+                u32_to_32bytes(1).into(),
+            )],
+        );
+    }
+    transport.push_transactions(vec![create_transaction(
+        1,
+        create_block(
+            1,
+            vec![create_deposit(Default::default(), Default::default(), 50)],
+        ),
+    )]);
+
+    // Restore the state once, recording all the node responses.
+    let recording_transport = RecordingTransport::new(transport);
+    let mut driver = DataRestoreDriver::new(
+        recording_transport.clone(),
+        [1u8; 20].into(),
+        [1u8; 20].into(),
+        ETH_BLOCKS_STEP,
+        END_ETH_BLOCKS_OFFSET,
+        vec![6, 30],
+        true,
+        None,
+    );
+    driver
+        .run_state_update(&mut InMemoryStorageInteractor::new())
+        .await;
+    let path = temp_path("data_restore_archive.ndjson");
+    recording_transport
+        .save(&path)
+        .expect("Can't save the archive");
+
+    // Restore the state again from the archive only.
+    let archive_transport = ArchiveTransport::load(&path).expect("Can't load the archive");
+    let mut archive_driver = DataRestoreDriver::new(
+        archive_transport,
+        [1u8; 20].into(),
+        [1u8; 20].into(),
+        ETH_BLOCKS_STEP,
+        END_ETH_BLOCKS_OFFSET,
+        vec![6, 30],
+        true,
+        None,
+    );
+    let mut interactor = InMemoryStorageInteractor::new();
+    archive_driver.run_state_update(&mut interactor).await;
+
+    assert_eq!(archive_driver.tree_state.state.block_number, 1);
+    assert_eq!(
+        archive_driver.tree_state.root_hash(),
+        driver.tree_state.root_hash()
+    );
+    let (_, account) = interactor
+        .get_account_by_address(&Default::default())
+        .unwrap();
+    assert_eq!(account.get_balance(0), BigUint::from(50u32));
+
+    std::fs::remove_file(&path).unwrap();
+}
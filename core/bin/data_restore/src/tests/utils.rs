@@ -1,28 +1,17 @@
-use std::future::Future;
+use num::BigUint;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use web3::types::{Bytes, Log, H256};
+use zksync_types::{Deposit, DepositOp, ZkSyncOp};
 
-use web3::{
-    types::H256,
-    types::{Bytes, Log},
-    RequestId, Transport,
-};
+use crate::rollup_ops::RollupOpsBlock;
 
-#[derive(Debug, Clone)]
-pub(crate) struct FakeTransport;
-
-impl Transport for FakeTransport {
-    type Out = Box<dyn Future<Output = Result<jsonrpc_core::Value, web3::Error>> + Send + Unpin>;
-
-    fn prepare(
-        &self,
-        _method: &str,
-        _params: Vec<jsonrpc_core::Value>,
-    ) -> (RequestId, jsonrpc_core::Call) {
-        unreachable!()
-    }
-
-    fn send(&self, _id: RequestId, _request: jsonrpc_core::Call) -> Self::Out {
-        unreachable!()
-    }
+/// Returns the path in the temporary directory that is unique for every call,
+/// so the tests running in parallel or in several processes don't share files
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let id = COUNTER.fetch_add(1, Ordering::SeqCst);
+    std::env::temp_dir().join(format!("{}_{}_{}", std::process::id(), id, name))
 }
 
 pub(crate) fn u32_to_32bytes(value: u32) -> [u8; 32] {
@@ -56,3 +45,21 @@ pub(crate) fn create_log(
         removed: None,
     }
 }
+
+/// Returns the block with a deposit to the new account `block_num - 1`,
+/// so the blocks are applied in order starting with the block 1
+pub(crate) fn deposit_block(block_num: u32) -> RollupOpsBlock {
+    RollupOpsBlock {
+        block_num,
+        ops: vec![ZkSyncOp::Deposit(Box::new(DepositOp {
+            priority_op: Deposit {
+                from: [1u8; 20].into(),
+                token: 1,
+                amount: BigUint::from(1000u32),
+                to: [block_num as u8; 20].into(),
+            },
+            account_id: block_num - 1,
+        }))],
+        fee_account: 0,
+    }
+}
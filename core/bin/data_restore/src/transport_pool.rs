@@ -0,0 +1,174 @@
+// Built-in deps
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+// External deps
+use tokio::sync::Semaphore;
+use web3::{RequestId, Transport};
+
+/// Pool of the web3 transport shared by several restores running in one process.
+///
+/// All the transports borrowed from the pool share the same underlying transport
+/// (and thus its connections), and the amount of the requests they have in flight
+/// together is limited, so the restores can't exhaust the connections of the process.
+#[derive(Debug, Clone)]
+pub struct TransportPool<T> {
+    transport: T,
+    requests_limit: Arc<Semaphore>,
+}
+
+impl<T: Transport> TransportPool<T> {
+    /// Returns the new pool of the provided transport
+    ///
+    /// # Arguments
+    ///
+    /// * `transport` - Shared web3 transport
+    /// * `max_concurrent_requests` - Maximum amount of the requests in flight for all the borrowed transports
+    ///
+    pub fn new(transport: T, max_concurrent_requests: usize) -> Self {
+        assert!(
+            max_concurrent_requests > 0,
+            "At least one concurrent request must be allowed"
+        );
+        Self {
+            transport,
+            requests_limit: Arc::new(Semaphore::new(max_concurrent_requests)),
+        }
+    }
+
+    /// Returns the transport sharing the pool connections and requests limit
+    pub fn transport(&self) -> PooledTransport<T> {
+        PooledTransport {
+            transport: self.transport.clone(),
+            requests_limit: self.requests_limit.clone(),
+        }
+    }
+
+    /// Returns the amount of the requests that can be sent without waiting
+    pub fn available_requests(&self) -> usize {
+        self.requests_limit.available_permits()
+    }
+}
+
+/// Web3 transport borrowed from the `TransportPool`
+#[derive(Debug, Clone)]
+pub struct PooledTransport<T> {
+    transport: T,
+    requests_limit: Arc<Semaphore>,
+}
+
+impl<T> Transport for PooledTransport<T>
+where
+    T: Transport + Send + Sync + 'static,
+    T::Out: Send + 'static,
+{
+    type Out =
+        Pin<Box<dyn Future<Output = Result<jsonrpc_core::Value, web3::Error>> + Send + 'static>>;
+
+    fn prepare(
+        &self,
+        method: &str,
+        params: Vec<jsonrpc_core::Value>,
+    ) -> (RequestId, jsonrpc_core::Call) {
+        self.transport.prepare(method, params)
+    }
+
+    fn send(&self, id: RequestId, request: jsonrpc_core::Call) -> Self::Out {
+        let transport = self.transport.clone();
+        let requests_limit = self.requests_limit.clone();
+
+        Box::pin(async move {
+            let _permit = requests_limit.acquire().await;
+            transport.send(id, request).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TransportPool;
+    use futures::future;
+    use serde_json::json;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use web3::{RequestId, Transport};
+
+    /// Transport counting the requests it has in flight
+    #[derive(Debug, Clone, Default)]
+    struct CountingTransport {
+        requests: Arc<AtomicUsize>,
+        in_flight: Arc<AtomicUsize>,
+        max_in_flight: Arc<AtomicUsize>,
+    }
+
+    impl Transport for CountingTransport {
+        type Out = Pin<
+            Box<dyn Future<Output = Result<jsonrpc_core::Value, web3::Error>> + Send + 'static>,
+        >;
+
+        fn prepare(
+            &self,
+            method: &str,
+            params: Vec<jsonrpc_core::Value>,
+        ) -> (RequestId, jsonrpc_core::Call) {
+            (
+                1,
+                jsonrpc_core::Call::MethodCall(jsonrpc_core::MethodCall {
+                    jsonrpc: Some(jsonrpc_core::Version::V2),
+                    method: method.to_string(),
+                    params: jsonrpc_core::Params::Array(params),
+                    id: jsonrpc_core::Id::Num(1),
+                }),
+            )
+        }
+
+        fn send(&self, _id: RequestId, _request: jsonrpc_core::Call) -> Self::Out {
+            let transport = self.clone();
+            Box::pin(async move {
+                transport.requests.fetch_add(1, Ordering::SeqCst);
+                let in_flight = transport.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                transport
+                    .max_in_flight
+                    .fetch_max(in_flight, Ordering::SeqCst);
+                for _ in 0..3 {
+                    tokio::task::yield_now().await;
+                }
+                transport.in_flight.fetch_sub(1, Ordering::SeqCst);
+                Ok(json!("0x80"))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_requests_limit() {
+        let transport = CountingTransport::default();
+        let pool = TransportPool::new(transport.clone(), 1);
+        let first_restore_transport = pool.transport();
+        let second_restore_transport = pool.transport();
+
+        let (first, second) = future::join(
+            first_restore_transport.execute("eth_blockNumber", vec![]),
+            second_restore_transport.execute("eth_blockNumber", vec![]),
+        )
+        .await;
+        assert_eq!(first.unwrap(), json!("0x80"));
+        assert_eq!(second.unwrap(), json!("0x80"));
+
+        // Both restores use the same underlying transport, one request at a time.
+        assert_eq!(transport.requests.load(Ordering::SeqCst), 2);
+        assert_eq!(transport.max_in_flight.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.available_requests(), 1);
+
+        let transport = CountingTransport::default();
+        let pool = TransportPool::new(transport.clone(), 2);
+        let (first, second) = future::join(
+            pool.transport().execute("eth_blockNumber", vec![]),
+            pool.transport().execute("eth_blockNumber", vec![]),
+        )
+        .await;
+        assert!(first.is_ok() && second.is_ok());
+        assert_eq!(transport.max_in_flight.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,267 @@
+// Built-in deps
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+// External deps
+use anyhow::{ensure, format_err};
+use serde::{Deserialize, Serialize};
+// Workspace deps
+use zksync_crypto::convert::FeConvert;
+use zksync_crypto::merkle_tree::{hasher::Hasher, RescueHasher};
+use zksync_crypto::params::account_tree_depth;
+use zksync_crypto::primitives::GetBits;
+use zksync_crypto::{Engine, Fr};
+use zksync_types::Account;
+// Local deps
+use crate::tree_state::TreeState;
+
+/// Accounts tree as stored in the export
+#[derive(Debug, Serialize, Deserialize)]
+struct TreeExportRecord {
+    /// Hashes of the empty subtrees roots of each level, starting from the leaves
+    empty_hashes: Vec<Vec<u8>>,
+    /// Indices and hashes of the non-empty nodes of each level, starting from the leaves
+    levels: Vec<Vec<(u32, Vec<u8>)>>,
+}
+
+/// Accounts tree with the hashes of all the non-empty nodes, including the internal ones,
+/// so the external verifiers can read any node without recomputing the tree.
+///
+/// Level 0 holds the accounts leaves, the last level holds the root. Nodes missing
+/// in a level are the roots of the empty subtrees.
+#[derive(Debug, Clone)]
+pub struct ExportedTree {
+    empty_hashes: Vec<Fr>,
+    levels: Vec<BTreeMap<u32, Fr>>,
+}
+
+impl ExportedTree {
+    /// Returns the tree with all the nodes hashes computed from the tree state accounts
+    ///
+    /// # Arguments
+    ///
+    /// * `tree_state` - Rollup accounts states
+    ///
+    pub fn from_tree_state(tree_state: &TreeState) -> Self {
+        let hasher = RescueHasher::<Engine>::default();
+        let depth = account_tree_depth();
+
+        let mut empty_hashes = Vec::with_capacity(depth + 1);
+        empty_hashes.push(hasher.hash_bits(Account::default().get_bits_le()));
+        for level in 0..depth {
+            let empty_hash = hasher.compress(&empty_hashes[level], &empty_hashes[level], level);
+            empty_hashes.push(empty_hash);
+        }
+
+        let leaves = tree_state
+            .get_accounts()
+            .into_iter()
+            .map(|(id, account)| (id, hasher.hash_bits(account.get_bits_le())))
+            .collect();
+        let mut levels: Vec<BTreeMap<u32, Fr>> = vec![leaves];
+        for level in 0..depth {
+            let nodes = &levels[level];
+            let node = |index| nodes.get(&index).copied().unwrap_or(empty_hashes[level]);
+            let mut parents = BTreeMap::new();
+            for index in nodes.keys() {
+                let parent_index = index / 2;
+                parents.entry(parent_index).or_insert_with(|| {
+                    hasher.compress(&node(parent_index * 2), &node(parent_index * 2 + 1), level)
+                });
+            }
+            levels.push(parents);
+        }
+
+        Self {
+            empty_hashes,
+            levels,
+        }
+    }
+
+    /// Returns the hash of the tree node, `None` if there is no such level
+    ///
+    /// # Arguments
+    ///
+    /// * `level` - Node level, 0 for the leaves
+    /// * `index` - Node index within the level
+    ///
+    pub fn node_hash(&self, level: usize, index: u32) -> Option<Fr> {
+        let nodes = self.levels.get(level)?;
+        Some(
+            nodes
+                .get(&index)
+                .copied()
+                .unwrap_or(self.empty_hashes[level]),
+        )
+    }
+
+    /// Returns the tree root hash
+    pub fn root_hash(&self) -> Fr {
+        self.node_hash(self.levels.len() - 1, 0)
+            .expect("Exported tree has no levels")
+    }
+
+    /// Writes the nodes hashes level by level, from the leaves to the root
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - Export destination
+    ///
+    pub fn write(&self, mut writer: impl Write) -> Result<(), anyhow::Error> {
+        let record = TreeExportRecord {
+            empty_hashes: self.empty_hashes.iter().map(FeConvert::to_bytes).collect(),
+            levels: self
+                .levels
+                .iter()
+                .map(|nodes| {
+                    nodes
+                        .iter()
+                        .map(|(index, hash)| (*index, hash.to_bytes()))
+                        .collect()
+                })
+                .collect(),
+        };
+        bincode::serialize_into(&mut writer, &record)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Reads the exported tree, checking every non-empty internal node and empty subtree
+    /// root against the hash of its children. The tree is rejected if any node doesn't match
+    /// or its root doesn't match the expected one.
+    ///
+    /// Only the nodes hashes are exported, so the tree state can't be rebuilt from the tree
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - Export source
+    /// * `expected_root_hash` - Trusted tree root hash
+    ///
+    pub fn read(reader: impl Read, expected_root_hash: Fr) -> Result<Self, anyhow::Error> {
+        let record: TreeExportRecord = bincode::deserialize_from(reader)?;
+        let depth = account_tree_depth();
+        ensure!(
+            record.levels.len() == depth + 1 && record.empty_hashes.len() == depth + 1,
+            "Exported tree has {} levels, expected {}",
+            record.levels.len(),
+            depth + 1
+        );
+
+        let hash = |bytes: &[u8]| {
+            Fr::from_bytes(bytes).map_err(|e| format_err!("Invalid exported node hash: {}", e))
+        };
+        let empty_hashes = record
+            .empty_hashes
+            .iter()
+            .map(|bytes| hash(bytes))
+            .collect::<Result<Vec<_>, _>>()?;
+        let levels = record
+            .levels
+            .iter()
+            .map(|nodes| {
+                nodes
+                    .iter()
+                    .map(|(index, bytes)| Ok((*index, hash(bytes)?)))
+                    .collect::<Result<BTreeMap<_, _>, anyhow::Error>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let tree = Self {
+            empty_hashes,
+            levels,
+        };
+        tree.verify_nodes()?;
+        ensure!(
+            tree.root_hash() == expected_root_hash,
+            "Exported tree root {:?} doesn't match the expected root {:?}",
+            tree.root_hash(),
+            expected_root_hash
+        );
+        Ok(tree)
+    }
+
+    /// Checks that each non-empty node has a non-empty parent, and each non-empty parent
+    /// and empty subtree root hash is the hash of its children
+    fn verify_nodes(&self) -> Result<(), anyhow::Error> {
+        let hasher = RescueHasher::<Engine>::default();
+        for level in 0..self.levels.len() - 1 {
+            let empty_hash = self.empty_hashes[level];
+            ensure!(
+                self.empty_hashes[level + 1] == hasher.compress(&empty_hash, &empty_hash, level),
+                "Exported tree empty subtree root of the level {} doesn't match its children",
+                level + 1
+            );
+
+            let nodes = &self.levels[level];
+            let parents = &self.levels[level + 1];
+            let node = |index| nodes.get(&index).copied().unwrap_or(empty_hash);
+            for index in nodes.keys() {
+                ensure!(
+                    parents.contains_key(&(index / 2)),
+                    "Exported tree node {} of the level {} has no parent",
+                    index,
+                    level
+                );
+            }
+            for (index, hash) in parents {
+                ensure!(
+                    *hash == hasher.compress(&node(index * 2), &node(index * 2 + 1), level),
+                    "Exported tree node {} of the level {} doesn't match its children",
+                    index,
+                    level + 1
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ExportedTree, TreeExportRecord};
+    use crate::tree_state::TreeState;
+    use num::BigUint;
+    use zksync_crypto::params::account_tree_depth;
+    use zksync_types::{Account, AccountMap};
+
+    #[test]
+    fn export_tree_round_trip() {
+        let mut accounts = AccountMap::default();
+        for id in &[0u32, 1, 5, 1000] {
+            let mut account = Account::default_with_address(&[*id as u8; 20].into());
+            account.set_balance(1, BigUint::from(id * 100));
+            accounts.insert(*id, account);
+        }
+        let tree = TreeState::load(1, accounts, 0, 0, vec![50]);
+
+        let mut export = Vec::new();
+        tree.export_tree(&mut export).expect("Cant export the tree");
+        let exported_tree =
+            ExportedTree::read(export.as_slice(), tree.root_hash()).expect("Cant import the tree");
+        assert_eq!(exported_tree.root_hash(), tree.root_hash());
+
+        // Internal nodes match the siblings of the tree merkle paths.
+        let (_, proof) = tree.account_with_proof(5).unwrap();
+        assert_eq!(proof.len(), account_tree_depth());
+        for (level, (sibling_hash, _)) in proof.into_iter().enumerate() {
+            assert_eq!(
+                exported_tree.node_hash(level, (5 >> level) ^ 1),
+                Some(sibling_hash)
+            );
+        }
+        assert!(exported_tree
+            .node_hash(account_tree_depth() + 1, 0)
+            .is_none());
+
+        let other_root = TreeState::new(vec![50]).root_hash();
+        assert!(ExportedTree::read(export.as_slice(), other_root).is_err());
+
+        // The tampered internal node is rejected, although the root still matches.
+        let mut record: TreeExportRecord = bincode::deserialize(&export).unwrap();
+        record.levels[1][0].1 = record.levels[0][0].1.clone();
+        let tampered_export = bincode::serialize(&record).unwrap();
+        let err = ExportedTree::read(tampered_export.as_slice(), tree.root_hash())
+            .err()
+            .expect("Tampered node must be detected");
+        assert!(err.to_string().contains("doesn't match its children"));
+    }
+}
@@ -1,18 +1,213 @@
+use crate::account_cache::AccountCache;
 use crate::rollup_ops::RollupOpsBlock;
-use anyhow::format_err;
+use crate::tree_export::ExportedTree;
+use anyhow::{ensure, format_err};
+use num::{BigInt, BigUint};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Mutex;
 use web3::types::{Address, H256};
-use zksync_crypto::Fr;
+use zksync_crypto::merkle_tree::{hasher::Hasher, RescueHasher};
+use zksync_crypto::primitives::GetBits;
+use zksync_crypto::{params::account_tree_depth, Engine, Fr, PublicKey};
 use zksync_state::{
     handler::TxHandler,
     state::{CollectedFee, OpSuccess, TransferOutcome, ZkSyncState},
 };
-use zksync_types::account::Account;
+use zksync_types::account::{Account, PubKeyHash};
 use zksync_types::block::{Block, ExecutedOperations, ExecutedPriorityOp, ExecutedTx};
-use zksync_types::operations::ZkSyncOp;
+use zksync_types::operations::{TransferOp, ZkSyncOp};
 use zksync_types::priority_ops::PriorityOp;
 use zksync_types::priority_ops::ZkSyncPriorityOp;
 use zksync_types::tx::{ChangePubKey, Close, ForcedExit, Transfer, Withdraw, ZkSyncTx};
-use zksync_types::{AccountId, AccountMap, AccountUpdates};
+use zksync_types::{AccountId, AccountMap, AccountUpdate, AccountUpdates, BlockNumber, TokenId};
+
+/// Transfer from the Rollup operations block with the accounts addresses resolved
+///
+/// Transfer pubdata doesn't contain any validity window, so there is no expiry block
+/// to be decoded and validated here: only the fields listed below are committed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedTransfer {
+    /// Sender account id
+    pub from: AccountId,
+    /// Sender address, `None` if the account is unknown
+    pub from_address: Option<Address>,
+    /// Recipient account id
+    pub to: AccountId,
+    /// Recipient address, `None` if the account is unknown
+    pub to_address: Option<Address>,
+    /// Transferred token
+    pub token: TokenId,
+    /// Transferred amount
+    pub amount: BigUint,
+    /// Transfer fee
+    pub fee: BigUint,
+    /// Flag indicating whether the recipient account is created by this transfer
+    pub to_new_account: bool,
+}
+
+/// Full exit from the Rollup operations block that withdrew the account balance
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExitedAccount {
+    /// Exited account id
+    pub account_id: AccountId,
+    /// Ethereum address receiving the withdrawn balance
+    pub eth_address: Address,
+    /// Withdrawn token
+    pub token: TokenId,
+    /// Withdrawn amount
+    pub amount: BigUint,
+}
+
+/// Handling of the transfers to the account with id 0.
+///
+/// Only the transfers have a Rollup account as the recipient: deposits are priority
+/// operations the operator can't refuse, withdrawals and exits credit an Ethereum address,
+/// and the fees are collected by the block fee account, which is the account 0 in the
+/// genesis blocks. There is no policy to burn the transferred amount, since the contract
+/// credits the account 0 as any other account, and the restored root would diverge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZeroAccountPolicy {
+    /// Transfers to the account 0 are applied as any other transfers
+    TreatAsNormal,
+    /// Blocks containing transfers to the account 0, including `TransferToNew`, are rejected
+    Reject,
+}
+
+impl Default for ZeroAccountPolicy {
+    fn default() -> Self {
+        Self::TreatAsNormal
+    }
+}
+
+/// Handling of the withdrawals and forced exits of the nonexistent accounts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonexistentExitPolicy {
+    /// Blocks containing exits of the nonexistent accounts are rejected
+    Reject,
+    /// Exits of the nonexistent accounts are skipped and reported in `skipped_exits`
+    Skip,
+}
+
+impl Default for NonexistentExitPolicy {
+    fn default() -> Self {
+        Self::Reject
+    }
+}
+
+/// Exit of the nonexistent account skipped by the `NonexistentExitPolicy::Skip` policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SkippedExit {
+    /// Number of the block containing the exit
+    pub block_number: BlockNumber,
+    /// Nonexistent account id
+    pub account_id: AccountId,
+}
+
+/// Error of applying the block that doesn't follow the last applied one.
+/// Can be downcasted from the block application error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockGap {
+    /// Number of the block following the last applied one
+    pub expected: BlockNumber,
+    /// Number of the applied block
+    pub got: BlockNumber,
+}
+
+impl std::fmt::Display for BlockGap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Block gap: block {} is expected, got the block {}",
+            self.expected, self.got
+        )
+    }
+}
+
+impl std::error::Error for BlockGap {}
+
+/// Account changed by the applied blocks, as emitted by the incremental export
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountChange {
+    /// Account id
+    pub id: AccountId,
+    /// Number of the last block that changed the account
+    pub block_number: BlockNumber,
+    /// Account state, `None` if the account is deleted
+    pub account: Option<Account>,
+}
+
+/// Amount of the operations of each kind that touched the account
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccountActivity {
+    /// Deposits to the account
+    pub deposits: u64,
+    /// Transfers sent by the account
+    pub transfers_sent: u64,
+    /// Transfers received by the account
+    pub transfers_received: u64,
+    /// Withdrawals, full exits and forced exits of the account
+    pub exits: u64,
+}
+
+impl AccountActivity {
+    fn add(&mut self, other: &AccountActivity) {
+        self.deposits += other.deposits;
+        self.transfers_sent += other.transfers_sent;
+        self.transfers_received += other.transfers_received;
+        self.exits += other.exits;
+    }
+
+    fn sub(&mut self, other: &AccountActivity) {
+        self.deposits = self.deposits.saturating_sub(other.deposits);
+        self.transfers_sent = self.transfers_sent.saturating_sub(other.transfers_sent);
+        self.transfers_received = self
+            .transfers_received
+            .saturating_sub(other.transfers_received);
+        self.exits = self.exits.saturating_sub(other.exits);
+    }
+}
+
+/// Account token balance change made by the applied operation or the fee collection
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceChange {
+    /// Account id
+    pub account_id: AccountId,
+    /// Token id
+    pub token: TokenId,
+    /// Balance difference, negative for the debits
+    pub delta: BigInt,
+    /// Number of the block being applied
+    pub block_number: BlockNumber,
+}
+
+/// Estimated heap size of the accounts tree nodes and cached hashes per account
+const TREE_BYTES_PER_ACCOUNT: usize = 1024;
+/// Estimated heap size of the account token balance, including the hash map entry overhead
+const BYTES_PER_BALANCE: usize = 64;
+
+/// Merkle path of the account from its leaf to the tree root: the sibling hashes
+/// with the flags set if the sibling is the left child
+pub type MerkleProof = Vec<(Fr, bool)>;
+
+/// Callback invoked for each balance change of the applied blocks
+pub type BalanceChangeCallback = Box<dyn Fn(&BalanceChange) + Send + Sync>;
+
+/// Cursor of the Rollup operations block applied in chunks
+#[derive(Debug)]
+pub struct OpsBlockCursor {
+    /// Index of the next operation to apply
+    pub next_op: usize,
+    accounts_updated: AccountUpdates,
+    fees: Vec<CollectedFee>,
+    ops: Vec<ExecutedOperations>,
+    current_op_block_index: u32,
+    last_unprocessed_prior_op: u64,
+    transfer_roots: Vec<Fr>,
+    #[cfg(feature = "strict_invariants")]
+    accounts_count_before: usize,
+}
 
 /// Rollup accounts states
 pub struct TreeState {
@@ -24,6 +219,33 @@ pub struct TreeState {
     pub last_fee_account_address: Address,
     /// Available block chunk sizes
     pub available_block_chunk_sizes: Vec<usize>,
+    /// Strict mode flag. In strict mode, suspicious operations that are likely
+    /// caused by corrupted data are rejected instead of being logged.
+    pub strict_mode: bool,
+    /// Policy for the transfers to the account 0
+    pub zero_account_policy: ZeroAccountPolicy,
+    /// Policy for the withdrawals and forced exits of the nonexistent accounts
+    pub nonexistent_exit_policy: NonexistentExitPolicy,
+    /// Flag to record the tree root hash after each transfer of the applied blocks.
+    /// Computing the root hash is expensive, so it's disabled by default
+    pub record_transfer_roots: bool,
+    /// Tree root hashes after each transfer of the last applied block,
+    /// recorded if `record_transfer_roots` is set
+    pub last_block_transfer_roots: Vec<Fr>,
+    /// Read cache of the accounts, disabled by default
+    account_cache: Option<Mutex<AccountCache>>,
+    /// Total fees collected by the applied blocks in each token
+    fees_collected: HashMap<TokenId, BigUint>,
+    /// Fees collected by the applied blocks in each token, for each block fee account
+    operator_fees: HashMap<AccountId, HashMap<TokenId, BigUint>>,
+    /// Number of the last applied block that changed the account, for each changed account
+    accounts_changed_at: HashMap<AccountId, BlockNumber>,
+    /// Operations counters of the accounts touched by the applied blocks
+    account_activity: HashMap<AccountId, AccountActivity>,
+    /// Callback for the balance changes, not set by default
+    balance_change_callback: Option<BalanceChangeCallback>,
+    /// Exits of the nonexistent accounts skipped by the applied blocks
+    skipped_exits: Vec<SkippedExit>,
 }
 
 impl TreeState {
@@ -34,6 +256,18 @@ impl TreeState {
             current_unprocessed_priority_op: 0,
             last_fee_account_address: Address::default(),
             available_block_chunk_sizes,
+            strict_mode: false,
+            zero_account_policy: ZeroAccountPolicy::default(),
+            nonexistent_exit_policy: NonexistentExitPolicy::default(),
+            record_transfer_roots: false,
+            last_block_transfer_roots: Vec::new(),
+            account_cache: None,
+            fees_collected: HashMap::new(),
+            operator_fees: HashMap::new(),
+            accounts_changed_at: HashMap::new(),
+            account_activity: HashMap::new(),
+            balance_change_callback: None,
+            skipped_exits: Vec::new(),
         }
     }
 
@@ -63,6 +297,148 @@ impl TreeState {
             current_unprocessed_priority_op,
             last_fee_account_address,
             available_block_chunk_sizes,
+            strict_mode: false,
+            zero_account_policy: ZeroAccountPolicy::default(),
+            nonexistent_exit_policy: NonexistentExitPolicy::default(),
+            record_transfer_roots: false,
+            last_block_transfer_roots: Vec::new(),
+            account_cache: None,
+            fees_collected: HashMap::new(),
+            operator_fees: HashMap::new(),
+            accounts_changed_at: HashMap::new(),
+            account_activity: HashMap::new(),
+            balance_change_callback: None,
+            skipped_exits: Vec::new(),
+        }
+    }
+
+    /// Resets the accounts states and counters back to the empty state,
+    /// keeping the available block chunk sizes and the validation settings
+    pub fn reset(&mut self) {
+        self.state = ZkSyncState::empty();
+        self.current_unprocessed_priority_op = 0;
+        self.last_fee_account_address = Address::default();
+        self.last_block_transfer_roots.clear();
+        self.clear_account_cache();
+        self.fees_collected.clear();
+        self.operator_fees.clear();
+        self.accounts_changed_at.clear();
+        self.account_activity.clear();
+        self.skipped_exits.clear();
+    }
+
+    /// Moves the settings and the hooks of the replaced tree state into this one, e.g. once
+    /// the state is loaded from storage or a snapshot: the validation policies, the transfer
+    /// roots recording flag, the balance change callback and the accounts read cache,
+    /// which is cleared since it holds the accounts of the replaced state
+    ///
+    /// # Arguments
+    ///
+    /// * `replaced` - Tree state being replaced
+    ///
+    pub fn take_settings_from(&mut self, replaced: &mut TreeState) {
+        self.strict_mode = replaced.strict_mode;
+        self.zero_account_policy = replaced.zero_account_policy;
+        self.nonexistent_exit_policy = replaced.nonexistent_exit_policy;
+        self.record_transfer_roots = replaced.record_transfer_roots;
+        self.balance_change_callback = replaced.balance_change_callback.take();
+        self.account_cache = replaced.account_cache.take();
+        self.clear_account_cache();
+    }
+
+    /// Replaces the accounts tree with the one built from the accounts list,
+    /// e.g. the accounts loaded from a snapshot. The tree root hash is computed
+    /// once all the accounts are inserted
+    ///
+    /// # Arguments
+    ///
+    /// * `accounts` - Accounts with their ids
+    ///
+    pub fn rebuild_tree(
+        &mut self,
+        accounts: Vec<(AccountId, Account)>,
+    ) -> Result<(), anyhow::Error> {
+        let accounts_count = accounts.len();
+        let account_map: AccountMap = accounts.into_iter().collect();
+        ensure!(
+            account_map.len() == accounts_count,
+            "Accounts list contains {} duplicated account ids",
+            accounts_count - account_map.len()
+        );
+        self.state = ZkSyncState::from_acc_map(account_map, self.state.block_number);
+        self.clear_account_cache();
+        Ok(())
+    }
+
+    /// Enables the least recently used read cache of the accounts returned by `get_account`.
+    ///
+    /// The accounts updated by the applied blocks are invalidated automatically, but
+    /// the accounts changed directly through the `state` must be invalidated with
+    /// `invalidate_cached_account`, otherwise the outdated accounts will be returned.
+    ///
+    /// The in-memory tree lookup is a hash map access as well, so the cache doesn't speed up
+    /// the reads of the tree itself, see the `account_cache_vs_tree_reads` benchmark
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - Maximum amount of the cached accounts
+    ///
+    pub fn enable_account_cache(&mut self, capacity: usize) {
+        self.account_cache = Some(Mutex::new(AccountCache::new(capacity)));
+    }
+
+    /// Removes the account from the accounts read cache
+    pub fn invalidate_cached_account(&self, account_id: AccountId) {
+        if let Some(cache) = &self.account_cache {
+            cache.lock().unwrap().invalidate(account_id);
+        }
+    }
+
+    /// Removes all the accounts from the accounts read cache
+    pub fn clear_account_cache(&self) {
+        if let Some(cache) = &self.account_cache {
+            cache.lock().unwrap().clear();
+        }
+    }
+
+    /// Removes the updated accounts from the accounts read cache
+    fn invalidate_cached_accounts(&self, updates: &[(AccountId, AccountUpdate)]) {
+        for (account_id, _) in updates {
+            self.invalidate_cached_account(*account_id);
+        }
+    }
+
+    /// Sets the callback invoked for each balance change made by the blocks applied afterwards
+    pub fn set_balance_change_callback(&mut self, callback: BalanceChangeCallback) {
+        self.balance_change_callback = Some(callback);
+    }
+
+    /// Invokes the balance change callback, if any, for the balance updates
+    fn notify_balance_changes(
+        &self,
+        updates: &[(AccountId, AccountUpdate)],
+        block_number: BlockNumber,
+    ) {
+        let callback = match &self.balance_change_callback {
+            Some(callback) => callback,
+            None => return,
+        };
+        for (account_id, update) in updates {
+            if let AccountUpdate::UpdateBalance {
+                balance_update: (token, old_balance, new_balance),
+                ..
+            } = update
+            {
+                if old_balance != new_balance {
+                    callback(&BalanceChange {
+                        account_id: *account_id,
+                        token: *token,
+                        delta: BigInt::from(new_balance.clone())
+                            - BigInt::from(old_balance.clone()),
+                        block_number,
+                    });
+                }
+            }
         }
     }
 
@@ -77,222 +453,366 @@ impl TreeState {
         &mut self,
         ops_block: &RollupOpsBlock,
     ) -> Result<(Block, AccountUpdates), anyhow::Error> {
-        let operations = ops_block.ops.clone();
+        let mut cursor = self.start_ops_block();
+        self.apply_ops_block_chunk(ops_block, &mut cursor, ops_block.ops.len())?;
+        self.finish_ops_block(ops_block, cursor)
+    }
 
-        let mut accounts_updated = Vec::new();
-        let mut fees = Vec::new();
-        let mut ops = Vec::new();
-        let mut current_op_block_index = 0u32;
-        let last_unprocessed_prior_op = self.current_unprocessed_priority_op;
+    /// Updates Rollup accounts states from Rollup operations block after checking
+    /// that the tree root hash matches the root the block was committed on top of,
+    /// so the out of order or corrupted blocks are rejected before changing the state.
+    /// Returns current rollup block and updated accounts
+    ///
+    /// # Arguments
+    ///
+    /// * `ops_block` - Rollup operations blocks
+    /// * `old_root_hash` - Root hash of the previous block, e.g. its committed `new_root_hash`
+    ///
+    pub fn update_tree_states_from_ops_block_with_old_root(
+        &mut self,
+        ops_block: &RollupOpsBlock,
+        old_root_hash: Fr,
+    ) -> Result<(Block, AccountUpdates), anyhow::Error> {
+        let root_hash = self.root_hash();
+        ensure!(
+            root_hash == old_root_hash,
+            "Root mismatch: block {} is committed on top of the root {:?}, while the tree root is {:?}",
+            ops_block.block_num,
+            old_root_hash,
+            root_hash
+        );
+        self.update_tree_states_from_ops_block(ops_block)
+    }
 
-        for operation in operations {
-            match operation {
-                ZkSyncOp::Deposit(op) => {
-                    let priority_op = ZkSyncPriorityOp::Deposit(op.priority_op);
-                    let op_result = self.state.execute_priority_op(priority_op.clone());
-                    current_op_block_index = self.update_from_priority_operation(
-                        priority_op,
-                        op_result,
-                        &mut fees,
-                        &mut accounts_updated,
-                        current_op_block_index,
-                        &mut ops,
-                    );
-                }
-                ZkSyncOp::TransferToNew(mut op) => {
-                    let from = self
-                        .state
-                        .get_account(op.from)
-                        .ok_or_else(|| format_err!("TransferToNew fail: Nonexistent account"))?;
-                    op.tx.from = from.address;
-                    op.tx.nonce = from.nonce;
-                    let tx = ZkSyncTx::Transfer(Box::new(op.tx.clone()));
-
-                    let raw_op = TransferOutcome::TransferToNew(*op.clone());
-
-                    let (fee, updates) =
-                        <ZkSyncState as TxHandler<Transfer>>::apply_op(&mut self.state, &raw_op)
-                            .map_err(|e| format_err!("TransferToNew fail: {}", e))?;
-                    let tx_result = OpSuccess {
-                        fee,
-                        updates,
-                        executed_op: ZkSyncOp::TransferToNew(op),
-                    };
+    /// Reverts the applied transfer: credits the sender with the amount and the fee,
+    /// debits the recipient with the amount and the block fee account with the fee,
+    /// and decrements the sender nonce. The collected fees and the activity counters are
+    /// rolled back, the touched accounts are reported as changed at the current block and
+    /// the balance changes are passed to the balance change callback.
+    /// Returns the updated accounts
+    ///
+    /// # Arguments
+    ///
+    /// * `op` - Applied transfer operation
+    /// * `fee_account` - Fee account of the block the transfer was applied in
+    ///
+    pub fn revert_transfer(
+        &mut self,
+        op: &TransferOp,
+        fee_account: AccountId,
+    ) -> Result<AccountUpdates, anyhow::Error> {
+        let token = op.tx.token;
+        let sender = self.state.get_account(op.from).ok_or_else(|| {
+            format_err!(
+                "Transfer revert fail: Nonexistent sender account {}",
+                op.from
+            )
+        })?;
+        ensure!(
+            sender.nonce > 0,
+            "Transfer revert fail: sender account {} has zero nonce",
+            op.from
+        );
 
-                    current_op_block_index = self.update_from_tx(
-                        tx,
-                        tx_result,
-                        &mut fees,
-                        &mut accounts_updated,
-                        current_op_block_index,
-                        &mut ops,
-                    );
-                }
-                ZkSyncOp::Transfer(mut op) => {
-                    let from = self
-                        .state
-                        .get_account(op.from)
-                        .ok_or_else(|| format_err!("Nonexistent account"))?;
-                    let to = self
-                        .state
-                        .get_account(op.to)
-                        .ok_or_else(|| format_err!("Nonexistent account"))?;
-                    op.tx.from = from.address;
-                    op.tx.to = to.address;
-                    op.tx.nonce = from.nonce;
-
-                    let raw_op = TransferOutcome::Transfer(*op.clone());
-
-                    let tx = ZkSyncTx::Transfer(Box::new(op.tx.clone()));
-                    let (fee, updates) =
-                        <ZkSyncState as TxHandler<Transfer>>::apply_op(&mut self.state, &raw_op)
-                            .map_err(|e| format_err!("Withdraw fail: {}", e))?;
-                    let tx_result = OpSuccess {
-                        fee,
-                        updates,
-                        executed_op: ZkSyncOp::Transfer(op),
-                    };
-                    current_op_block_index = self.update_from_tx(
-                        tx,
-                        tx_result,
-                        &mut fees,
-                        &mut accounts_updated,
-                        current_op_block_index,
-                        &mut ops,
-                    );
-                }
-                ZkSyncOp::Withdraw(mut op) => {
-                    // Withdraw op comes with empty Account Address and Nonce fields
-                    let account = self
-                        .state
-                        .get_account(op.account_id)
-                        .ok_or_else(|| format_err!("Withdraw fail: Nonexistent account"))?;
-                    op.tx.from = account.address;
-                    op.tx.nonce = account.nonce;
-
-                    let tx = ZkSyncTx::Withdraw(Box::new(op.tx.clone()));
-                    let (fee, updates) =
-                        <ZkSyncState as TxHandler<Withdraw>>::apply_op(&mut self.state, &op)
-                            .map_err(|e| format_err!("Withdraw fail: {}", e))?;
-                    let tx_result = OpSuccess {
-                        fee,
-                        updates,
-                        executed_op: ZkSyncOp::Withdraw(op),
-                    };
-                    current_op_block_index = self.update_from_tx(
-                        tx,
-                        tx_result,
-                        &mut fees,
-                        &mut accounts_updated,
-                        current_op_block_index,
-                        &mut ops,
-                    );
-                }
-                ZkSyncOp::ForcedExit(mut op) => {
-                    // Withdraw op comes with empty Account Address and Nonce fields
-                    let initiator_account = self
-                        .state
-                        .get_account(op.tx.initiator_account_id)
-                        .ok_or_else(|| {
-                            format_err!("ForcedExit fail: Nonexistent initiator account")
-                        })?;
-
-                    // Set the fields unknown from the pubdata.
-                    op.tx.nonce = initiator_account.nonce;
-
-                    let tx = ZkSyncTx::ForcedExit(Box::new(op.tx.clone()));
-                    let (fee, updates) =
-                        <ZkSyncState as TxHandler<ForcedExit>>::apply_op(&mut self.state, &op)
-                            .map_err(|e| format_err!("ForcedExit fail: {}", e))?;
-                    let tx_result = OpSuccess {
-                        fee,
-                        updates,
-                        executed_op: ZkSyncOp::ForcedExit(op),
-                    };
-                    current_op_block_index = self.update_from_tx(
-                        tx,
-                        tx_result,
-                        &mut fees,
-                        &mut accounts_updated,
-                        current_op_block_index,
-                        &mut ops,
-                    );
-                }
-                ZkSyncOp::Close(mut op) => {
-                    // Close op comes with empty Account Address and Nonce fields
-                    let account = self
-                        .state
-                        .get_account(op.account_id)
-                        .ok_or_else(|| format_err!("Close fail: Nonexistent account"))?;
-                    op.tx.account = account.address;
-                    op.tx.nonce = account.nonce;
-
-                    let tx = ZkSyncTx::Close(Box::new(op.tx.clone()));
-                    let (fee, updates) =
-                        <ZkSyncState as TxHandler<Close>>::apply_op(&mut self.state, &op)
-                            .map_err(|e| format_err!("Close fail: {}", e))?;
-                    let tx_result = OpSuccess {
-                        fee,
-                        updates,
-                        executed_op: ZkSyncOp::Close(op),
-                    };
-                    current_op_block_index = self.update_from_tx(
-                        tx,
-                        tx_result,
-                        &mut fees,
-                        &mut accounts_updated,
-                        current_op_block_index,
-                        &mut ops,
-                    );
+        let sender_balance = sender.get_balance(token);
+        let mut updates = vec![(
+            op.from,
+            AccountUpdate::UpdateBalance {
+                old_nonce: sender.nonce,
+                new_nonce: sender.nonce - 1,
+                balance_update: (
+                    token,
+                    sender_balance.clone(),
+                    sender_balance + &op.tx.amount + &op.tx.fee,
+                ),
+            },
+        )];
+        self.state.apply_account_updates(updates.clone());
+
+        // The accounts are read after the previous updates, since they may be the same account.
+        let debits = vec![
+            (op.to, &op.tx.amount, "recipient"),
+            (fee_account, &op.tx.fee, "fee"),
+        ];
+        for (account_id, amount, role) in debits {
+            if *amount == BigUint::from(0u32) {
+                continue;
+            }
+            let account = match self.state.get_account(account_id) {
+                Some(account) if account.get_balance(token) >= *amount => account,
+                account => {
+                    self.revert_account_updates(&updates);
+                    return Err(match account {
+                        Some(_) => format_err!(
+                            "Transfer revert fail: {} account {} balance is lower than the reverted amount",
+                            role,
+                            account_id
+                        ),
+                        None => format_err!(
+                            "Transfer revert fail: Nonexistent {} account {}",
+                            role,
+                            account_id
+                        ),
+                    });
                 }
-                ZkSyncOp::FullExit(op) => {
-                    let priority_op = ZkSyncPriorityOp::FullExit(op.priority_op);
-                    let op_result = self.state.execute_priority_op(priority_op.clone());
-                    current_op_block_index = self.update_from_priority_operation(
-                        priority_op,
-                        op_result,
-                        &mut fees,
-                        &mut accounts_updated,
-                        current_op_block_index,
-                        &mut ops,
-                    );
+            };
+            let balance = account.get_balance(token);
+            let update = (
+                account_id,
+                AccountUpdate::UpdateBalance {
+                    old_nonce: account.nonce,
+                    new_nonce: account.nonce,
+                    balance_update: (token, balance.clone(), balance - amount),
+                },
+            );
+            self.state.apply_account_updates(vec![update.clone()]);
+            updates.push(update);
+        }
+
+        if op.tx.fee != BigUint::from(0u32) {
+            subtract_fee(&mut self.fees_collected, token, &op.tx.fee);
+            if let Some(fees) = self.operator_fees.get_mut(&fee_account) {
+                subtract_fee(fees, token, &op.tx.fee);
+                if fees.is_empty() {
+                    self.operator_fees.remove(&fee_account);
                 }
-                ZkSyncOp::ChangePubKeyOffchain(mut op) => {
-                    let account = self.state.get_account(op.account_id).ok_or_else(|| {
-                        format_err!("ChangePubKeyOffChain fail: Nonexistent account")
-                    })?;
-                    op.tx.account = account.address;
-                    op.tx.nonce = account.nonce;
-
-                    let tx = ZkSyncTx::ChangePubKey(Box::new(op.tx.clone()));
-                    let (fee, updates) =
-                        <ZkSyncState as TxHandler<ChangePubKey>>::apply_op(&mut self.state, &op)
-                            .map_err(|e| format_err!("ChangePubKeyOffChain fail: {}", e))?;
-                    let tx_result = OpSuccess {
-                        fee,
-                        updates,
-                        executed_op: ZkSyncOp::ChangePubKeyOffchain(op),
-                    };
-                    current_op_block_index = self.update_from_tx(
-                        tx,
-                        tx_result,
-                        &mut fees,
-                        &mut accounts_updated,
-                        current_op_block_index,
-                        &mut ops,
-                    );
+            }
+        }
+        for (account_id, delta) in operation_activity(&ZkSyncOp::Transfer(Box::new(op.clone()))) {
+            if let Some(activity) = self.account_activity.get_mut(&account_id) {
+                activity.sub(&delta);
+            }
+        }
+        for (account_id, _) in &updates {
+            self.accounts_changed_at
+                .insert(*account_id, self.state.block_number);
+        }
+        self.invalidate_cached_accounts(&updates);
+        self.notify_balance_changes(&updates, self.state.block_number);
+        Ok(updates)
+    }
+
+    /// Reverts the applied accounts updates in the reverse order
+    ///
+    /// # Arguments
+    ///
+    /// * `updates` - Applied accounts updates
+    ///
+    fn revert_account_updates(&mut self, updates: &[(AccountId, AccountUpdate)]) {
+        let reversed_updates = updates
+            .iter()
+            .rev()
+            .map(|(account_id, update)| (*account_id, update.reversed_update()))
+            .collect();
+        self.state.apply_account_updates(reversed_updates);
+        self.invalidate_cached_accounts(updates);
+    }
+
+    /// Updates Rollup accounts states from Rollup operations block and checks that
+    /// the resulting tree root hash matches the expected one, e.g. the committed `new_root_hash`.
+    /// If any operation of the block fails or the root doesn't match, the block is rolled back,
+    /// so the tree state is left unchanged, although the balance change callback has already
+    /// seen the changes of the applied operations.
+    /// Returns current rollup block and updated accounts
+    ///
+    /// # Arguments
+    ///
+    /// * `ops_block` - Rollup operations blocks
+    /// * `expected_root_hash` - Expected tree root hash after applying the block
+    ///
+    pub fn apply_ops_block_checked(
+        &mut self,
+        ops_block: &RollupOpsBlock,
+        expected_root_hash: Fr,
+    ) -> Result<(Block, AccountUpdates), anyhow::Error> {
+        self.apply_ops_block_or_roll_back(ops_block, Some(expected_root_hash))
+    }
+
+    /// Updates Rollup accounts states from Rollup operations block. If any operation
+    /// of the block fails, the block is rolled back, so the tree state is left unchanged,
+    /// although the balance change callback has already seen the changes of the applied
+    /// operations. Returns current rollup block and updated accounts
+    ///
+    /// # Arguments
+    ///
+    /// * `ops_block` - Rollup operations blocks
+    ///
+    pub fn try_update_tree_states_from_ops_block(
+        &mut self,
+        ops_block: &RollupOpsBlock,
+    ) -> Result<(Block, AccountUpdates), anyhow::Error> {
+        self.apply_ops_block_or_roll_back(ops_block, None)
+    }
+
+    /// Applies the Rollup operations block, rolling it back if any operation fails
+    /// or the resulting root doesn't match the expected one, if provided
+    ///
+    /// # Arguments
+    ///
+    /// * `ops_block` - Rollup operations blocks
+    /// * `expected_root_hash` - Expected tree root hash after applying the block, if any
+    ///
+    fn apply_ops_block_or_roll_back(
+        &mut self,
+        ops_block: &RollupOpsBlock,
+        expected_root_hash: Option<Fr>,
+    ) -> Result<(Block, AccountUpdates), anyhow::Error> {
+        let block_number = self.state.block_number;
+        let current_unprocessed_priority_op = self.current_unprocessed_priority_op;
+        let last_fee_account_address = self.last_fee_account_address;
+        let last_block_transfer_roots = self.last_block_transfer_roots.clone();
+        let fees_collected = self.fees_collected.clone();
+        let operator_fees = self.operator_fees.clone();
+        let accounts_changed_at = self.accounts_changed_at.clone();
+        let account_activity = self.account_activity.clone();
+        let skipped_exits_count = self.skipped_exits.len();
+
+        let mut cursor = self.start_ops_block();
+        let result = self
+            .apply_ops_block_chunk(ops_block, &mut cursor, ops_block.ops.len())
+            .and_then(|_| self.complete_ops_block(ops_block, &mut cursor));
+        let error = match (result, expected_root_hash) {
+            (Ok(result), None) => return Ok(result),
+            (Ok(result), Some(expected_root_hash)) => {
+                let root_hash = self.root_hash();
+                if root_hash == expected_root_hash {
+                    return Ok(result);
                 }
-                ZkSyncOp::Noop(_) => {}
+                format_err!(
+                    "Root mismatch: block {} results in the root {:?}, while the root {:?} is expected",
+                    ops_block.block_num,
+                    root_hash,
+                    expected_root_hash
+                )
+            }
+            (Err(e), _) => e,
+        };
+
+        // The cursor holds the updates of all the applied operations, including the fees.
+        self.revert_account_updates(&cursor.accounts_updated);
+        self.state.block_number = block_number;
+        self.current_unprocessed_priority_op = current_unprocessed_priority_op;
+        self.last_fee_account_address = last_fee_account_address;
+        self.last_block_transfer_roots = last_block_transfer_roots;
+        self.fees_collected = fees_collected;
+        self.operator_fees = operator_fees;
+        self.accounts_changed_at = accounts_changed_at;
+        self.account_activity = account_activity;
+        self.skipped_exits.truncate(skipped_exits_count);
+        Err(error)
+    }
+
+    /// Returns the cursor to apply the Rollup operations block in chunks.
+    /// The block must be applied with `apply_ops_block_chunk` and then completed
+    /// with `finish_ops_block` before any other block is applied
+    pub fn start_ops_block(&self) -> OpsBlockCursor {
+        OpsBlockCursor {
+            next_op: 0,
+            accounts_updated: Vec::new(),
+            fees: Vec::new(),
+            ops: Vec::new(),
+            current_op_block_index: 0,
+            last_unprocessed_prior_op: self.current_unprocessed_priority_op,
+            transfer_roots: Vec::new(),
+            #[cfg(feature = "strict_invariants")]
+            accounts_count_before: self.state.get_accounts().len(),
+        }
+    }
+
+    /// Applies the next operations of the Rollup operations block
+    /// Returns flag that indicates if all the block operations are applied
+    ///
+    /// # Arguments
+    ///
+    /// * `ops_block` - Rollup operations block
+    /// * `cursor` - Cursor of the block application
+    /// * `max_ops` - Maximum amount of the operations to apply
+    ///
+    pub fn apply_ops_block_chunk(
+        &mut self,
+        ops_block: &RollupOpsBlock,
+        cursor: &mut OpsBlockCursor,
+        max_ops: usize,
+    ) -> Result<bool, anyhow::Error> {
+        let expected_block_num = self.state.block_number + 1;
+        if cursor.next_op == 0 && ops_block.block_num != expected_block_num {
+            return Err(BlockGap {
+                expected: expected_block_num,
+                got: ops_block.block_num,
             }
+            .into());
         }
+        let chunk_end = ops_block.ops.len().min(cursor.next_op + max_ops);
+        for operation in ops_block.ops[cursor.next_op..chunk_end].iter().cloned() {
+            self.apply_operation(ops_block, operation, cursor)?;
+            cursor.next_op += 1;
+        }
+        Ok(cursor.next_op == ops_block.ops.len())
+    }
+
+    /// Completes the Rollup operations block application: collects the fees
+    /// and returns current rollup block and updated accounts
+    ///
+    /// # Arguments
+    ///
+    /// * `ops_block` - Rollup operations block
+    /// * `cursor` - Cursor of the block application
+    ///
+    pub fn finish_ops_block(
+        &mut self,
+        ops_block: &RollupOpsBlock,
+        mut cursor: OpsBlockCursor,
+    ) -> Result<(Block, AccountUpdates), anyhow::Error> {
+        self.complete_ops_block(ops_block, &mut cursor)
+    }
+
+    /// Completes the Rollup operations block application, leaving all the accounts updates
+    /// of the block, including the collected fees, in the cursor, so the block can be
+    /// rolled back if the completion fails
+    ///
+    /// # Arguments
+    ///
+    /// * `ops_block` - Rollup operations block
+    /// * `cursor` - Cursor of the block application
+    ///
+    fn complete_ops_block(
+        &mut self,
+        ops_block: &RollupOpsBlock,
+        cursor: &mut OpsBlockCursor,
+    ) -> Result<(Block, AccountUpdates), anyhow::Error> {
+        ensure!(
+            cursor.next_op == ops_block.ops.len(),
+            "Block {} is not applied completely: {} of {} operations applied",
+            ops_block.block_num,
+            cursor.next_op,
+            ops_block.ops.len()
+        );
 
         let fee_account_address = self
             .get_account(ops_block.fee_account)
             .ok_or_else(|| format_err!("Nonexistent account"))?
             .address;
 
-        let fee_updates = self.state.collect_fee(&fees, ops_block.fee_account);
-        accounts_updated.extend(fee_updates.into_iter());
+        for fee in cursor
+            .fees
+            .iter()
+            .filter(|fee| fee.amount != BigUint::from(0u32))
+        {
+            *self
+                .fees_collected
+                .entry(fee.token)
+                .or_insert_with(|| BigUint::from(0u32)) += &fee.amount;
+            *self
+                .operator_fees
+                .entry(ops_block.fee_account)
+                .or_default()
+                .entry(fee.token)
+                .or_insert_with(|| BigUint::from(0u32)) += &fee.amount;
+        }
+        let fee_updates = self.state.collect_fee(&cursor.fees, ops_block.fee_account);
+        self.invalidate_cached_accounts(&fee_updates);
+        self.notify_balance_changes(&fee_updates, ops_block.block_num);
+        cursor.accounts_updated.extend(fee_updates.into_iter());
 
         self.last_fee_account_address = fee_account_address;
 
@@ -303,9 +823,9 @@ impl TreeState {
             ops_block.block_num,
             self.state.root_hash(),
             ops_block.fee_account,
-            ops,
+            std::mem::take(&mut cursor.ops),
             (
-                last_unprocessed_prior_op,
+                cursor.last_unprocessed_prior_op,
                 self.current_unprocessed_priority_op,
             ),
             &self.available_block_chunk_sizes,
@@ -313,65 +833,429 @@ impl TreeState {
             gas_limit,
         );
 
+        #[cfg(feature = "strict_invariants")]
+        check_block_invariants(
+            cursor.accounts_count_before,
+            self.state.get_accounts().len(),
+            &cursor.accounts_updated,
+        )
+        .map_err(|e| format_err!("Block {} invariant violated: {}", ops_block.block_num, e))?;
+
         self.state.block_number += 1;
+        self.last_block_transfer_roots = std::mem::take(&mut cursor.transfer_roots);
+        for (account_id, _) in &cursor.accounts_updated {
+            self.accounts_changed_at
+                .insert(*account_id, ops_block.block_num);
+        }
 
-        Ok((block, accounts_updated))
+        Ok((block, cursor.accounts_updated.clone()))
     }
 
-    /// Updates the list of accounts that has been updated, aggregates fees, updates blocks operations list from Rollup priority operation
-    /// Returns current operation index
+    /// Applies the Rollup operation, updating the block application cursor
     ///
     /// # Arguments
     ///
-    /// * `priority_op` - Priority operation
-    /// * `op_result` - Rollup priority operation execution result
-    /// * `fees` - Rollup operation fees
-    /// * `accounts_updated` - Updated accounts
-    /// * `current_op_block_index` - Current operation index
-    /// * `ops` - Current block operations list
+    /// * `ops_block` - Rollup operations block the operation belongs to
+    /// * `operation` - Rollup operation
+    /// * `cursor` - Cursor of the block application
     ///
-    fn update_from_priority_operation(
+    fn apply_operation(
         &mut self,
-        priority_op: ZkSyncPriorityOp,
-        op_result: OpSuccess,
-        fees: &mut Vec<CollectedFee>,
-        accounts_updated: &mut AccountUpdates,
-        current_op_block_index: u32,
-        ops: &mut Vec<ExecutedOperations>,
-    ) -> u32 {
-        accounts_updated.append(&mut op_result.updates.clone());
-        if let Some(fee) = op_result.fee {
-            fees.push(fee);
+        ops_block: &RollupOpsBlock,
+        operation: ZkSyncOp,
+        cursor: &mut OpsBlockCursor,
+    ) -> Result<(), anyhow::Error> {
+        let updates_count_before = cursor.accounts_updated.len();
+        if self.skip_nonexistent_exit(ops_block, &operation) {
+            return Ok(());
         }
-        let block_index = current_op_block_index;
-        let exec_result = ExecutedPriorityOp {
-            op: op_result.executed_op,
-            priority_op: PriorityOp {
-                serial_id: 0,
-                data: priority_op,
-                deadline_block: 0,
-                eth_hash: H256::zero(),
-                eth_block: 0,
-            },
-            block_index,
-            created_at: chrono::Utc::now(),
-        };
-        ops.push(ExecutedOperations::PriorityOp(Box::new(exec_result)));
-        self.current_unprocessed_priority_op += 1;
-        current_op_block_index + 1
-    }
-
-    /// Updates the list of accounts that has been updated, aggregates fees, updates blocks operations list from Rollup transaction
-    /// Returns current operation index
-    ///
-    /// # Arguments
-    ///
-    /// * `tx` - Rollup transaction
-    /// * `op_result` - Rollup transaction execution result
-    /// * `fees` - Rollup operation fees
-    /// * `accounts_updated` - Updated accounts
-    /// * `current_op_block_index` - Current operation index
-    /// * `ops` - Current block operations list
+        let activity = operation_activity(&operation);
+        let is_transfer = matches!(
+            operation,
+            ZkSyncOp::Transfer(_) | ZkSyncOp::TransferToNew(_)
+        );
+        match operation {
+            ZkSyncOp::Deposit(op) => {
+                if op.priority_op.amount == BigUint::from(0u32) {
+                    log::warn!(
+                        "Zero amount deposit to the account {} in block {}",
+                        op.account_id,
+                        ops_block.block_num
+                    );
+                    ensure!(
+                        !self.strict_mode,
+                        "Deposit fail: zero amount deposit to the account {} in block {}",
+                        op.account_id,
+                        ops_block.block_num
+                    );
+                }
+                let pubdata_account_id = op.account_id;
+                let priority_op = ZkSyncPriorityOp::Deposit(op.priority_op);
+                let op_result = self.state.execute_priority_op(priority_op.clone());
+                let restored_account_id = match &op_result.executed_op {
+                    ZkSyncOp::Deposit(executed_op) => executed_op.account_id,
+                    _ => pubdata_account_id,
+                };
+                // The deposit is already executed, so its updates are recorded
+                // before the mismatch is reported to allow rolling it back.
+                cursor.current_op_block_index = self.update_from_priority_operation(
+                    priority_op,
+                    op_result,
+                    &mut cursor.fees,
+                    &mut cursor.accounts_updated,
+                    cursor.current_op_block_index,
+                    &mut cursor.ops,
+                );
+                if restored_account_id != pubdata_account_id {
+                    log::warn!(
+                        "Deposit account id mismatch: {} in pubdata, {} in restored state",
+                        pubdata_account_id,
+                        restored_account_id
+                    );
+                    ensure!(
+                        !self.strict_mode,
+                        "Deposit fail: account id {} from pubdata doesn't match restored account id {}",
+                        pubdata_account_id,
+                        restored_account_id
+                    );
+                }
+            }
+            ZkSyncOp::TransferToNew(mut op) => {
+                self.check_zero_account_recipient(op.to, ops_block)?;
+                if self.state.get_account(op.to).is_some() {
+                    log::warn!(
+                        "TransferToNew reuses an existing account id {} in block {}",
+                        op.to,
+                        ops_block.block_num
+                    );
+                    ensure!(
+                        !self.strict_mode,
+                        "TransferToNew fail: account {} already exists in block {}",
+                        op.to,
+                        ops_block.block_num
+                    );
+                    // The restored state keeps the existing account, like for the deposit
+                    // account id mismatch, so the amount is transferred to it.
+                    let transfer = TransferOp {
+                        tx: op.tx,
+                        from: op.from,
+                        to: op.to,
+                    };
+                    return self.apply_operation(
+                        ops_block,
+                        ZkSyncOp::Transfer(Box::new(transfer)),
+                        cursor,
+                    );
+                }
+                let from = self
+                    .state
+                    .get_account(op.from)
+                    .ok_or_else(|| format_err!("TransferToNew fail: Nonexistent account"))?;
+                op.tx.from = from.address;
+                op.tx.nonce = from.nonce;
+                let tx = ZkSyncTx::Transfer(Box::new(op.tx.clone()));
+
+                let raw_op = TransferOutcome::TransferToNew(*op.clone());
+
+                let (fee, updates) =
+                    <ZkSyncState as TxHandler<Transfer>>::apply_op(&mut self.state, &raw_op)
+                        .map_err(|e| format_err!("TransferToNew fail: {}", e))?;
+                let tx_result = OpSuccess {
+                    fee,
+                    updates,
+                    executed_op: ZkSyncOp::TransferToNew(op),
+                };
+
+                cursor.current_op_block_index = self.update_from_tx(
+                    tx,
+                    tx_result,
+                    &mut cursor.fees,
+                    &mut cursor.accounts_updated,
+                    cursor.current_op_block_index,
+                    &mut cursor.ops,
+                );
+            }
+            ZkSyncOp::Transfer(mut op) => {
+                self.check_zero_account_recipient(op.to, ops_block)?;
+                let from = self
+                    .state
+                    .get_account(op.from)
+                    .ok_or_else(|| format_err!("Nonexistent account"))?;
+                let to = self
+                    .state
+                    .get_account(op.to)
+                    .ok_or_else(|| format_err!("Nonexistent account"))?;
+                // Transfer pubdata carries neither the nonce nor the validity window,
+                // so the nonce is taken from the state and the expiry can't be checked.
+                op.tx.from = from.address;
+                op.tx.to = to.address;
+                op.tx.nonce = from.nonce;
+
+                let raw_op = TransferOutcome::Transfer(*op.clone());
+
+                let tx = ZkSyncTx::Transfer(Box::new(op.tx.clone()));
+                let (fee, updates) =
+                    <ZkSyncState as TxHandler<Transfer>>::apply_op(&mut self.state, &raw_op)
+                        .map_err(|e| format_err!("Withdraw fail: {}", e))?;
+                let tx_result = OpSuccess {
+                    fee,
+                    updates,
+                    executed_op: ZkSyncOp::Transfer(op),
+                };
+                cursor.current_op_block_index = self.update_from_tx(
+                    tx,
+                    tx_result,
+                    &mut cursor.fees,
+                    &mut cursor.accounts_updated,
+                    cursor.current_op_block_index,
+                    &mut cursor.ops,
+                );
+            }
+            ZkSyncOp::Withdraw(mut op) => {
+                // Withdraw op comes with empty Account Address and Nonce fields
+                let account = self
+                    .state
+                    .get_account(op.account_id)
+                    .ok_or_else(|| format_err!("Withdraw fail: Nonexistent account"))?;
+                op.tx.from = account.address;
+                op.tx.nonce = account.nonce;
+
+                let tx = ZkSyncTx::Withdraw(Box::new(op.tx.clone()));
+                let (fee, updates) =
+                    <ZkSyncState as TxHandler<Withdraw>>::apply_op(&mut self.state, &op)
+                        .map_err(|e| format_err!("Withdraw fail: {}", e))?;
+                let tx_result = OpSuccess {
+                    fee,
+                    updates,
+                    executed_op: ZkSyncOp::Withdraw(op),
+                };
+                cursor.current_op_block_index = self.update_from_tx(
+                    tx,
+                    tx_result,
+                    &mut cursor.fees,
+                    &mut cursor.accounts_updated,
+                    cursor.current_op_block_index,
+                    &mut cursor.ops,
+                );
+            }
+            ZkSyncOp::ForcedExit(mut op) => {
+                // Withdraw op comes with empty Account Address and Nonce fields
+                let initiator_account = self
+                    .state
+                    .get_account(op.tx.initiator_account_id)
+                    .ok_or_else(|| format_err!("ForcedExit fail: Nonexistent initiator account"))?;
+
+                // Set the fields unknown from the pubdata.
+                op.tx.nonce = initiator_account.nonce;
+
+                let tx = ZkSyncTx::ForcedExit(Box::new(op.tx.clone()));
+                let (fee, updates) =
+                    <ZkSyncState as TxHandler<ForcedExit>>::apply_op(&mut self.state, &op)
+                        .map_err(|e| format_err!("ForcedExit fail: {}", e))?;
+                let tx_result = OpSuccess {
+                    fee,
+                    updates,
+                    executed_op: ZkSyncOp::ForcedExit(op),
+                };
+                cursor.current_op_block_index = self.update_from_tx(
+                    tx,
+                    tx_result,
+                    &mut cursor.fees,
+                    &mut cursor.accounts_updated,
+                    cursor.current_op_block_index,
+                    &mut cursor.ops,
+                );
+            }
+            ZkSyncOp::Close(mut op) => {
+                // Close op comes with empty Account Address and Nonce fields
+                let account = self
+                    .state
+                    .get_account(op.account_id)
+                    .ok_or_else(|| format_err!("Close fail: Nonexistent account"))?;
+                op.tx.account = account.address;
+                op.tx.nonce = account.nonce;
+
+                let tx = ZkSyncTx::Close(Box::new(op.tx.clone()));
+                let (fee, updates) =
+                    <ZkSyncState as TxHandler<Close>>::apply_op(&mut self.state, &op)
+                        .map_err(|e| format_err!("Close fail: {}", e))?;
+                let tx_result = OpSuccess {
+                    fee,
+                    updates,
+                    executed_op: ZkSyncOp::Close(op),
+                };
+                cursor.current_op_block_index = self.update_from_tx(
+                    tx,
+                    tx_result,
+                    &mut cursor.fees,
+                    &mut cursor.accounts_updated,
+                    cursor.current_op_block_index,
+                    &mut cursor.ops,
+                );
+            }
+            ZkSyncOp::FullExit(op) => {
+                let priority_op = ZkSyncPriorityOp::FullExit(op.priority_op);
+                let op_result = self.state.execute_priority_op(priority_op.clone());
+                cursor.current_op_block_index = self.update_from_priority_operation(
+                    priority_op,
+                    op_result,
+                    &mut cursor.fees,
+                    &mut cursor.accounts_updated,
+                    cursor.current_op_block_index,
+                    &mut cursor.ops,
+                );
+            }
+            ZkSyncOp::ChangePubKeyOffchain(mut op) => {
+                let account = self
+                    .state
+                    .get_account(op.account_id)
+                    .ok_or_else(|| format_err!("ChangePubKeyOffChain fail: Nonexistent account"))?;
+                op.tx.account = account.address;
+                op.tx.nonce = account.nonce;
+
+                let tx = ZkSyncTx::ChangePubKey(Box::new(op.tx.clone()));
+                let (fee, updates) =
+                    <ZkSyncState as TxHandler<ChangePubKey>>::apply_op(&mut self.state, &op)
+                        .map_err(|e| format_err!("ChangePubKeyOffChain fail: {}", e))?;
+                let tx_result = OpSuccess {
+                    fee,
+                    updates,
+                    executed_op: ZkSyncOp::ChangePubKeyOffchain(op),
+                };
+                cursor.current_op_block_index = self.update_from_tx(
+                    tx,
+                    tx_result,
+                    &mut cursor.fees,
+                    &mut cursor.accounts_updated,
+                    cursor.current_op_block_index,
+                    &mut cursor.ops,
+                );
+            }
+            ZkSyncOp::Noop(_) => {}
+        }
+        self.invalidate_cached_accounts(&cursor.accounts_updated[updates_count_before..]);
+        self.notify_balance_changes(
+            &cursor.accounts_updated[updates_count_before..],
+            ops_block.block_num,
+        );
+        for (account_id, delta) in activity {
+            self.account_activity
+                .entry(account_id)
+                .or_default()
+                .add(&delta);
+        }
+        if is_transfer && self.record_transfer_roots {
+            cursor.transfer_roots.push(self.state.root_hash());
+        }
+        Ok(())
+    }
+
+    /// Checks that the transfer recipient is allowed by the zero account policy
+    ///
+    /// # Arguments
+    ///
+    /// * `recipient` - Transfer recipient account id
+    /// * `ops_block` - Rollup operations block the transfer belongs to
+    ///
+    fn check_zero_account_recipient(
+        &self,
+        recipient: AccountId,
+        ops_block: &RollupOpsBlock,
+    ) -> Result<(), anyhow::Error> {
+        ensure!(
+            recipient != 0 || self.zero_account_policy != ZeroAccountPolicy::Reject,
+            "Transfer fail: transfers to the account 0 are rejected in block {}",
+            ops_block.block_num
+        );
+        Ok(())
+    }
+
+    /// Checks whether the exit must be skipped since its account doesn't exist,
+    /// recording the skipped exit
+    ///
+    /// # Arguments
+    ///
+    /// * `ops_block` - Rollup operations block the operation belongs to
+    /// * `operation` - Withdraw or forced exit operation
+    ///
+    fn skip_nonexistent_exit(&mut self, ops_block: &RollupOpsBlock, operation: &ZkSyncOp) -> bool {
+        if self.nonexistent_exit_policy != NonexistentExitPolicy::Skip {
+            return false;
+        }
+        let account_id = match operation {
+            ZkSyncOp::Withdraw(op) => op.account_id,
+            ZkSyncOp::ForcedExit(op) => op.target_account_id,
+            _ => return false,
+        };
+        if self.state.get_account(account_id).is_some() {
+            return false;
+        }
+        log::warn!(
+            "Skipping the exit of the nonexistent account {} in block {}",
+            account_id,
+            ops_block.block_num
+        );
+        self.skipped_exits.push(SkippedExit {
+            block_number: ops_block.block_num,
+            account_id,
+        });
+        true
+    }
+
+    /// Updates the list of accounts that has been updated, aggregates fees, updates blocks operations list from Rollup priority operation
+    /// Returns current operation index
+    ///
+    /// # Arguments
+    ///
+    /// * `priority_op` - Priority operation
+    /// * `op_result` - Rollup priority operation execution result
+    /// * `fees` - Rollup operation fees
+    /// * `accounts_updated` - Updated accounts
+    /// * `current_op_block_index` - Current operation index
+    /// * `ops` - Current block operations list
+    ///
+    fn update_from_priority_operation(
+        &mut self,
+        priority_op: ZkSyncPriorityOp,
+        op_result: OpSuccess,
+        fees: &mut Vec<CollectedFee>,
+        accounts_updated: &mut AccountUpdates,
+        current_op_block_index: u32,
+        ops: &mut Vec<ExecutedOperations>,
+    ) -> u32 {
+        accounts_updated.append(&mut op_result.updates.clone());
+        if let Some(fee) = op_result.fee {
+            fees.push(fee);
+        }
+        let block_index = current_op_block_index;
+        let exec_result = ExecutedPriorityOp {
+            op: op_result.executed_op,
+            priority_op: PriorityOp {
+                serial_id: 0,
+                data: priority_op,
+                deadline_block: 0,
+                eth_hash: H256::zero(),
+                eth_block: 0,
+            },
+            block_index,
+            created_at: chrono::Utc::now(),
+        };
+        ops.push(ExecutedOperations::PriorityOp(Box::new(exec_result)));
+        self.current_unprocessed_priority_op += 1;
+        current_op_block_index + 1
+    }
+
+    /// Updates the list of accounts that has been updated, aggregates fees, updates blocks operations list from Rollup transaction
+    /// Returns current operation index
+    ///
+    /// # Arguments
+    ///
+    /// * `tx` - Rollup transaction
+    /// * `op_result` - Rollup transaction execution result
+    /// * `fees` - Rollup operation fees
+    /// * `accounts_updated` - Updated accounts
+    /// * `current_op_block_index` - Current operation index
+    /// * `ops` - Current block operations list
     ///
     fn update_from_tx(
         &mut self,
@@ -406,11 +1290,292 @@ impl TreeState {
         current_op_block_index + 1
     }
 
+    /// Returns transfers from the Rollup operations block with the accounts addresses resolved.
+    /// Block must be not applied yet, accounts created within the block are resolved
+    /// from the block operations.
+    ///
+    /// # Arguments
+    ///
+    /// * `ops_block` - Rollup operations block
+    ///
+    pub fn decode_block_transfers(&self, ops_block: &RollupOpsBlock) -> Vec<DecodedTransfer> {
+        let mut created_accounts: HashMap<AccountId, Address> = HashMap::new();
+        let mut transfers = Vec::new();
+        for operation in &ops_block.ops {
+            match operation {
+                ZkSyncOp::Deposit(op) => {
+                    if self.state.get_account(op.account_id).is_none() {
+                        created_accounts.insert(op.account_id, op.priority_op.to);
+                    }
+                }
+                ZkSyncOp::TransferToNew(op) => {
+                    created_accounts.insert(op.to, op.tx.to);
+                    transfers.push(DecodedTransfer {
+                        from: op.from,
+                        from_address: self.resolve_address(op.from, &created_accounts),
+                        to: op.to,
+                        to_address: Some(op.tx.to),
+                        token: op.tx.token,
+                        amount: op.tx.amount.clone(),
+                        fee: op.tx.fee.clone(),
+                        to_new_account: true,
+                    });
+                }
+                ZkSyncOp::Transfer(op) => transfers.push(DecodedTransfer {
+                    from: op.from,
+                    from_address: self.resolve_address(op.from, &created_accounts),
+                    to: op.to,
+                    to_address: self.resolve_address(op.to, &created_accounts),
+                    token: op.tx.token,
+                    amount: op.tx.amount.clone(),
+                    fee: op.tx.fee.clone(),
+                    to_new_account: false,
+                }),
+                _ => {}
+            }
+        }
+        transfers
+    }
+
+    fn resolve_address(
+        &self,
+        account_id: AccountId,
+        created_accounts: &HashMap<AccountId, Address>,
+    ) -> Option<Address> {
+        self.state
+            .get_account(account_id)
+            .map(|account| account.address)
+            .or_else(|| created_accounts.get(&account_id).copied())
+    }
+
+    /// Returns the accounts that exited in the Rollup operations block along with
+    /// the withdrawn amounts, so the withdrawals on L1 can be reconciled.
+    /// Failed full exits don't withdraw anything and are not reported
+    ///
+    /// # Arguments
+    ///
+    /// * `ops_block` - Rollup operations block
+    ///
+    pub fn decode_block_exits(ops_block: &RollupOpsBlock) -> Vec<ExitedAccount> {
+        ops_block
+            .ops
+            .iter()
+            .filter_map(|operation| match operation {
+                ZkSyncOp::FullExit(op) => op
+                    .withdraw_amount
+                    .as_ref()
+                    .filter(|amount| amount.0 != BigUint::from(0u32))
+                    .map(|amount| ExitedAccount {
+                        account_id: op.priority_op.account_id,
+                        eth_address: op.priority_op.eth_address,
+                        token: op.priority_op.token,
+                        amount: amount.0.clone(),
+                    }),
+                _ => None,
+            })
+            .collect()
+    }
+
     /// Returns map of ZkSync accounts ids and their descriptions
     pub fn get_accounts(&self) -> Vec<(u32, Account)> {
         self.state.get_accounts()
     }
 
+    /// Returns ZkSync accounts that have at least one nonzero balance
+    pub fn active_accounts(&self) -> Vec<(AccountId, Account)> {
+        self.state
+            .get_accounts()
+            .into_iter()
+            .filter(|(_, account)| !account.get_nonzero_balances().is_empty())
+            .collect()
+    }
+
+    /// Returns ids of the accounts that were created but never used, in the ascending order:
+    /// the accounts with no nonzero balances, zero nonce and no public key hash set,
+    /// e.g. the recipients of the zero amount transfers to the new accounts
+    pub fn empty_accounts(&self) -> Vec<AccountId> {
+        let mut empty_accounts: Vec<_> = self
+            .state
+            .get_accounts()
+            .into_iter()
+            .filter(|(_, account)| {
+                account.get_nonzero_balances().is_empty()
+                    && account.nonce == 0
+                    && account.pub_key_hash == PubKeyHash::default()
+            })
+            .map(|(id, _)| id)
+            .collect();
+        empty_accounts.sort_unstable();
+        empty_accounts
+    }
+
+    /// Returns ids of all the accounts owned by the public key, in the ascending order.
+    /// Accounts store only the public key hash, so the same key may own several accounts
+    ///
+    /// # Arguments
+    ///
+    /// * `public_key` - Account owner public key
+    ///
+    pub fn accounts_by_pubkey(&self, public_key: &PublicKey) -> Vec<AccountId> {
+        let pub_key_hash = PubKeyHash::from_pubkey(public_key);
+        let mut account_ids: Vec<_> = self
+            .state
+            .get_accounts()
+            .into_iter()
+            .filter(|(_, account)| account.pub_key_hash == pub_key_hash)
+            .map(|(id, _)| id)
+            .collect();
+        account_ids.sort_unstable();
+        account_ids
+    }
+
+    /// Returns the amounts of accounts per token balance bucket.
+    /// The `n`-th element counts the balances in the `[buckets[n - 1], buckets[n])` range,
+    /// so the first one counts balances below the first edge, and the last one counts
+    /// balances above or equal to the last edge
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - Token id
+    /// * `buckets` - Sorted buckets edges in the token base units
+    ///
+    pub fn balance_histogram(&self, token: TokenId, buckets: &[BigUint]) -> Vec<usize> {
+        let mut histogram = vec![0; buckets.len() + 1];
+        for (_, account) in self.get_accounts() {
+            let balance = account.get_balance(token);
+            let bucket = buckets.iter().take_while(|edge| **edge <= balance).count();
+            histogram[bucket] += 1;
+        }
+        histogram
+    }
+
+    /// Returns the rough estimate of the memory used by the accounts tree, in bytes.
+    /// The estimate is based on the accounts count and their balances count, so it's
+    /// intended for the capacity planning rather than the precise accounting
+    pub fn estimated_memory_bytes(&self) -> usize {
+        let address_index_entry_size =
+            std::mem::size_of::<Address>() + std::mem::size_of::<AccountId>();
+        self.get_accounts()
+            .iter()
+            .map(|(_, account)| {
+                std::mem::size_of::<Account>()
+                    + TREE_BYTES_PER_ACCOUNT
+                    + address_index_entry_size
+                    + account.get_nonzero_balances().len() * BYTES_PER_BALANCE
+            })
+            .sum()
+    }
+
+    /// Returns the accounts with the smallest and the largest nonzero token balances
+    /// as `(min account id, min balance, max account id, max balance)`, preferring
+    /// the lower account ids among the equal balances.
+    /// Returns `None` if no account has a nonzero balance of the token
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - Token id
+    ///
+    pub fn balance_extremes(
+        &self,
+        token: TokenId,
+    ) -> Option<(AccountId, BigUint, AccountId, BigUint)> {
+        let mut extremes: Option<(AccountId, BigUint, AccountId, BigUint)> = None;
+        for (id, account) in self.get_accounts() {
+            let balance = account.get_balance(token);
+            if balance == BigUint::from(0u32) {
+                continue;
+            }
+            extremes = Some(match extremes {
+                None => (id, balance.clone(), id, balance),
+                Some((min_id, min, max_id, max)) => {
+                    let (min_id, min) = if balance < min || (balance == min && id < min_id) {
+                        (id, balance.clone())
+                    } else {
+                        (min_id, min)
+                    };
+                    let (max_id, max) = if balance > max || (balance == max && id < max_id) {
+                        (id, balance)
+                    } else {
+                        (max_id, max)
+                    };
+                    (min_id, min, max_id, max)
+                }
+            });
+        }
+        extremes
+    }
+
+    /// Returns the total fees collected in each token by the blocks applied
+    /// since the tree state was created or loaded
+    pub fn fees_collected(&self) -> &HashMap<TokenId, BigUint> {
+        &self.fees_collected
+    }
+
+    /// Returns the fees collected in each token by each fee account of the blocks applied
+    /// since the tree state was created or loaded
+    pub fn operator_fees(&self) -> &HashMap<AccountId, HashMap<TokenId, BigUint>> {
+        &self.operator_fees
+    }
+
+    /// Returns the exits of the nonexistent accounts skipped by the blocks applied
+    /// since the tree state was created or loaded
+    pub fn skipped_exits(&self) -> &[SkippedExit] {
+        &self.skipped_exits
+    }
+
+    /// Returns the operations counters of each account touched by the blocks applied
+    /// since the tree state was created or loaded
+    pub fn account_activity(&self) -> HashMap<AccountId, AccountActivity> {
+        self.account_activity.clone()
+    }
+
+    /// Writes the accounts changed by the blocks applied after the specified one
+    /// as newline-delimited JSON `AccountChange` records, ordered by the account id.
+    /// Only the blocks applied since the tree state was created or loaded are tracked
+    ///
+    /// # Arguments
+    ///
+    /// * `block_number` - Last block number already known by the export consumer
+    /// * `writer` - Export destination
+    ///
+    pub fn export_changes_since(
+        &self,
+        block_number: BlockNumber,
+        mut writer: impl Write,
+    ) -> Result<(), anyhow::Error> {
+        let mut changed_accounts: Vec<_> = self
+            .accounts_changed_at
+            .iter()
+            .filter(|(_, changed_at)| **changed_at > block_number)
+            .map(|(id, changed_at)| (*id, *changed_at))
+            .collect();
+        changed_accounts.sort_unstable();
+
+        for (id, changed_at) in changed_accounts {
+            let change = AccountChange {
+                id,
+                block_number: changed_at,
+                account: self.state.get_account(id),
+            };
+            serde_json::to_writer(&mut writer, &change)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the hashes of all the non-empty accounts tree nodes, including the internal ones,
+    /// level by level from the leaves to the root. The export can be read back with
+    /// `ExportedTree::read` without recomputing the tree
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - Export destination
+    ///
+    pub fn export_tree(&self, writer: impl Write) -> Result<(), anyhow::Error> {
+        ExportedTree::from_tree_state(self).write(writer)
+    }
+
     /// Returns sparse Merkle tree root hash
     pub fn root_hash(&self) -> Fr {
         self.state.root_hash()
@@ -423,19 +1588,227 @@ impl TreeState {
 
     /// Returns ZkSync Account description by its id
     pub fn get_account(&self, account_id: AccountId) -> Option<Account> {
-        self.state.get_account(account_id)
+        let mut cache = match &self.account_cache {
+            Some(cache) => cache.lock().unwrap(),
+            None => return self.state.get_account(account_id),
+        };
+        if let Some(account) = cache.get(account_id) {
+            return Some(account);
+        }
+        let account = self.state.get_account(account_id)?;
+        cache.insert(account_id, account.clone());
+        Some(account)
+    }
+
+    /// Returns the account with its inclusion proof against the current tree root hash
+    ///
+    /// # Arguments
+    ///
+    /// * `account_id` - Account id
+    ///
+    pub fn account_with_proof(&self, account_id: AccountId) -> Option<(Account, MerkleProof)> {
+        let account = self.get_account(account_id)?;
+        Some((account, self.state.merkle_path(account_id)))
+    }
+
+    /// Returns the accounts with the provided ids, in the same order.
+    /// Each id is paired with `None` if the account doesn't exist
+    ///
+    /// # Arguments
+    ///
+    /// * `ids` - Accounts ids
+    ///
+    pub fn get_accounts_by_ids(&self, ids: &[AccountId]) -> Vec<(AccountId, Option<Account>)> {
+        ids.iter().map(|id| (*id, self.get_account(*id))).collect()
+    }
+
+    /// Returns ZkSync Account description by its id, `Ok(None)` if there is no such account.
+    /// The in-memory tree can't fail, the error is reserved for the fallible tree backends
+    pub fn try_get_account(&self, account_id: AccountId) -> Result<Option<Account>, anyhow::Error> {
+        Ok(self.get_account(account_id))
+    }
+
+    /// Checks that the accounts stored in the tree items, the accounts addresses index
+    /// and the tree root are consistent with each other:
+    /// - every stored account is found by its address;
+    /// - the root recomputed from the stored accounts matches the tree root.
+    pub fn verify_tree_consistency(&self) -> Result<(), anyhow::Error> {
+        let accounts = self.get_accounts();
+        for (id, account) in &accounts {
+            let indexed_id = self
+                .get_account_by_address(&account.address)
+                .map(|(indexed_id, _)| indexed_id);
+            ensure!(
+                indexed_id == Some(*id),
+                "Account {} address {:x} is indexed as the account {:?}",
+                id,
+                account.address,
+                indexed_id
+            );
+        }
+
+        let recomputed_root_hash =
+            ZkSyncState::from_acc_map(accounts.into_iter().collect(), self.state.block_number)
+                .root_hash();
+        ensure!(
+            recomputed_root_hash == self.root_hash(),
+            "Tree root hash {:?} doesn't match the root hash {:?} recomputed from the accounts",
+            self.root_hash(),
+            recomputed_root_hash
+        );
+        Ok(())
+    }
+}
+
+/// Verifies the accounts proofs against the same tree root hash, sharing the hasher
+/// between the proofs. Returns the verification result of each proof, in the same order
+///
+/// # Arguments
+///
+/// * `proofs` - Accounts ids, accounts and their merkle paths, e.g. from `account_with_proof`
+/// * `root` - Trusted tree root hash
+///
+pub fn verify_proofs(proofs: &[(AccountId, Account, MerkleProof)], root: Fr) -> Vec<bool> {
+    let hasher = RescueHasher::<Engine>::default();
+    proofs
+        .iter()
+        .map(|(account_id, account, proof)| {
+            if proof.len() != account_tree_depth() {
+                return false;
+            }
+            let mut proof_index: AccountId = 0;
+            let mut aggregated_hash = hasher.hash_bits(account.get_bits_le());
+            for (level, (hash, dir)) in proof.iter().enumerate() {
+                let (lhs, rhs) = if *dir {
+                    proof_index |= 1 << level;
+                    (hash, &aggregated_hash)
+                } else {
+                    (&aggregated_hash, hash)
+                };
+                aggregated_hash = hasher.compress(lhs, rhs, level);
+            }
+            proof_index == *account_id && aggregated_hash == root
+        })
+        .collect()
+}
+
+/// Subtracts the reverted fee from the collected fees, removing the token once nothing is left
+///
+/// # Arguments
+///
+/// * `fees` - Collected fees in each token
+/// * `token` - Fee token id
+/// * `fee` - Reverted fee amount
+///
+fn subtract_fee(fees: &mut HashMap<TokenId, BigUint>, token: TokenId, fee: &BigUint) {
+    if let Some(collected) = fees.get_mut(&token) {
+        if *collected > *fee {
+            *collected -= fee;
+        } else {
+            fees.remove(&token);
+        }
+    }
+}
+
+/// Returns the activity counters increments of the accounts touched by the operation
+fn operation_activity(op: &ZkSyncOp) -> Vec<(AccountId, AccountActivity)> {
+    let sent = AccountActivity {
+        transfers_sent: 1,
+        ..Default::default()
+    };
+    let received = AccountActivity {
+        transfers_received: 1,
+        ..Default::default()
+    };
+    let exit = AccountActivity {
+        exits: 1,
+        ..Default::default()
+    };
+    match op {
+        ZkSyncOp::Deposit(op) => vec![(
+            op.account_id,
+            AccountActivity {
+                deposits: 1,
+                ..Default::default()
+            },
+        )],
+        ZkSyncOp::Transfer(op) => vec![(op.from, sent), (op.to, received)],
+        ZkSyncOp::TransferToNew(op) => vec![(op.from, sent), (op.to, received)],
+        ZkSyncOp::Withdraw(op) => vec![(op.account_id, exit)],
+        ZkSyncOp::FullExit(op) => vec![(op.priority_op.account_id, exit)],
+        ZkSyncOp::ForcedExit(op) => vec![(op.target_account_id, exit)],
+        _ => Vec::new(),
+    }
+}
+
+/// Checks the invariants that must hold after applying a Rollup operations block:
+/// - accounts nonces never decrease;
+/// - accounts count changes according to the accounts creations and deletions.
+///
+/// # Arguments
+///
+/// * `accounts_count_before` - Amount of accounts before applying the block
+/// * `accounts_count_after` - Amount of accounts after applying the block
+/// * `accounts_updated` - Accounts updates made by the block
+///
+#[cfg(feature = "strict_invariants")]
+fn check_block_invariants(
+    accounts_count_before: usize,
+    accounts_count_after: usize,
+    accounts_updated: &[(AccountId, AccountUpdate)],
+) -> Result<(), anyhow::Error> {
+    let mut expected_accounts_count = accounts_count_before as i64;
+    for (account_id, update) in accounts_updated {
+        match update {
+            AccountUpdate::Create { .. } => expected_accounts_count += 1,
+            AccountUpdate::Delete { .. } => expected_accounts_count -= 1,
+            AccountUpdate::UpdateBalance {
+                old_nonce,
+                new_nonce,
+                ..
+            }
+            | AccountUpdate::ChangePubKeyHash {
+                old_nonce,
+                new_nonce,
+                ..
+            } => ensure!(
+                new_nonce >= old_nonce,
+                "nonce monotonicity: nonce of the account {} decreased from {} to {}",
+                account_id,
+                old_nonce,
+                new_nonce
+            ),
+        }
     }
+    ensure!(
+        expected_accounts_count == accounts_count_after as i64,
+        "accounts count consistency: expected {} accounts, found {}",
+        expected_accounts_count,
+        accounts_count_after
+    );
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use crate::rollup_ops::RollupOpsBlock;
-    use crate::tree_state::TreeState;
-    use num::BigUint;
+    use crate::tests::utils::deposit_block;
+    use crate::tree_state::{
+        verify_proofs, AccountActivity, AccountChange, BalanceChange, BlockGap, DecodedTransfer,
+        ExitedAccount, NonexistentExitPolicy, SkippedExit, TreeState, ZeroAccountPolicy,
+    };
+    use num::{BigInt, BigUint};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+    use zksync_crypto::rand::{Rng, SeedableRng, XorShiftRng};
+    use zksync_crypto::{params::account_tree_depth, public_key_from_private, PrivateKey};
+    use zksync_state::state::ZkSyncState;
     use zksync_types::tx::ChangePubKey;
     use zksync_types::{
-        ChangePubKeyOp, Deposit, DepositOp, ForcedExit, ForcedExitOp, FullExit, FullExitOp,
-        PubKeyHash, Transfer, TransferOp, TransferToNewOp, Withdraw, WithdrawOp, ZkSyncOp,
+        Account, AccountMap, AccountTree, ChangePubKeyOp, Deposit, DepositOp, ForcedExit,
+        ForcedExitOp, FullExit, FullExitOp, PubKeyHash, Transfer, TransferOp, TransferToNewOp,
+        Withdraw, WithdrawOp, ZkSyncOp,
     };
 
     #[test]
@@ -573,7 +1946,7 @@ mod test {
         let ops6 =
             RollupOpsBlock::get_rollup_ops_from_data(&pub_data6).expect("cant get ops from data 5");
         let block6 = RollupOpsBlock {
-            block_num: 5,
+            block_num: 6,
             ops: ops6,
             fee_account: 0,
         };
@@ -663,6 +2036,1471 @@ mod test {
         assert_eq!(first_acc.get_balance(1), BigUint::from(1u32));
     }
 
+    #[test]
+    fn test_chunked_ops_block_application() {
+        let ops = (0..100u32)
+            .map(|account_id| {
+                ZkSyncOp::Deposit(Box::new(DepositOp {
+                    priority_op: Deposit {
+                        from: [1u8; 20].into(),
+                        token: 1,
+                        amount: BigUint::from(1000u32 + account_id),
+                        to: [(account_id % 50) as u8; 20].into(),
+                    },
+                    account_id: account_id % 50,
+                }))
+            })
+            .collect();
+        let block = RollupOpsBlock {
+            block_num: 1,
+            ops,
+            fee_account: 0,
+        };
+
+        let mut tree = TreeState::new(vec![600]);
+        let (expected_block, expected_updates) = tree
+            .update_tree_states_from_ops_block(&block)
+            .expect("Cant update state from block");
+
+        let mut chunked_tree = TreeState::new(vec![600]);
+        let mut cursor = chunked_tree.start_ops_block();
+        let finished = chunked_tree
+            .apply_ops_block_chunk(&block, &mut cursor, 60)
+            .expect("Cant apply the first chunk");
+        assert!(!finished);
+        assert_eq!(cursor.next_op, 60);
+        assert!(chunked_tree.finish_ops_block(&block, cursor).is_err());
+
+        let mut chunked_tree = TreeState::new(vec![600]);
+        let mut cursor = chunked_tree.start_ops_block();
+        chunked_tree
+            .apply_ops_block_chunk(&block, &mut cursor, 60)
+            .expect("Cant apply the first chunk");
+        let finished = chunked_tree
+            .apply_ops_block_chunk(&block, &mut cursor, 60)
+            .expect("Cant apply the second chunk");
+        assert!(finished);
+        let (chunked_block, chunked_updates) = chunked_tree
+            .finish_ops_block(&block, cursor)
+            .expect("Cant finish the block");
+
+        assert_eq!(chunked_tree.root_hash(), tree.root_hash());
+        assert_eq!(chunked_tree.state.block_number, tree.state.block_number);
+        assert_eq!(chunked_tree.current_unprocessed_priority_op, 100);
+        assert_eq!(chunked_block.new_root_hash, expected_block.new_root_hash);
+        assert_eq!(
+            chunked_block.processed_priority_ops,
+            expected_block.processed_priority_ops
+        );
+        assert_eq!(chunked_updates.len(), expected_updates.len());
+        let account = chunked_tree.get_account(49).expect("Cant get account");
+        assert_eq!(account.get_balance(1), BigUint::from(1049u32 + 1099u32));
+    }
+
+    #[test]
+    fn test_zero_account_policy() {
+        let deposit = |to: u8, account_id| {
+            ZkSyncOp::Deposit(Box::new(DepositOp {
+                priority_op: Deposit {
+                    from: [1u8; 20].into(),
+                    token: 1,
+                    amount: BigUint::from(1000u32),
+                    to: [to; 20].into(),
+                },
+                account_id,
+            }))
+        };
+        let tx = Transfer::new(
+            1,
+            [8u8; 20].into(),
+            [7u8; 20].into(),
+            1,
+            BigUint::from(40u32),
+            BigUint::from(0u32),
+            0,
+            None,
+        );
+        let transfer = ZkSyncOp::Transfer(Box::new(TransferOp { tx, from: 1, to: 0 }));
+        let block = RollupOpsBlock {
+            block_num: 1,
+            ops: vec![deposit(7, 0), deposit(8, 1), transfer],
+            fee_account: 0,
+        };
+
+        let mut tree = TreeState::new(vec![50]);
+        assert_eq!(tree.zero_account_policy, ZeroAccountPolicy::TreatAsNormal);
+        tree.update_tree_states_from_ops_block(&block)
+            .expect("Transfer to the account 0 must be applied");
+        let zero_acc = tree.get_account(0).expect("Cant get 0 account");
+        assert_eq!(zero_acc.get_balance(1), BigUint::from(1040u32));
+
+        let mut tree = TreeState::new(vec![50]);
+        tree.zero_account_policy = ZeroAccountPolicy::Reject;
+        assert!(tree.update_tree_states_from_ops_block(&block).is_err());
+
+        // The account 0 can't be created by the transfer either.
+        let mut accounts = AccountMap::default();
+        let mut sender = Account::default_with_address(&[8u8; 20].into());
+        sender.set_balance(1, BigUint::from(1000u32));
+        accounts.insert(1, sender);
+        let mut tree = TreeState::load(0, accounts, 0, 1, vec![50]);
+        tree.zero_account_policy = ZeroAccountPolicy::Reject;
+        let tx = Transfer::new(
+            1,
+            [8u8; 20].into(),
+            [7u8; 20].into(),
+            1,
+            BigUint::from(40u32),
+            BigUint::from(0u32),
+            0,
+            None,
+        );
+        let transfer_to_new = RollupOpsBlock {
+            block_num: 1,
+            ops: vec![ZkSyncOp::TransferToNew(Box::new(TransferToNewOp {
+                tx,
+                from: 1,
+                to: 0,
+            }))],
+            fee_account: 1,
+        };
+        let err = tree
+            .update_tree_states_from_ops_block(&transfer_to_new)
+            .err()
+            .expect("TransferToNew to the account 0 must be rejected");
+        assert!(err
+            .to_string()
+            .contains("transfers to the account 0 are rejected"));
+    }
+
+    #[test]
+    fn test_nonexistent_exit_policy() {
+        let deposit = ZkSyncOp::Deposit(Box::new(DepositOp {
+            priority_op: Deposit {
+                from: [1u8; 20].into(),
+                token: 1,
+                amount: BigUint::from(1000u32),
+                to: [7u8; 20].into(),
+            },
+            account_id: 0,
+        }));
+        let withdraw = ZkSyncOp::Withdraw(Box::new(WithdrawOp {
+            tx: Withdraw::new(
+                5,
+                [9u8; 20].into(),
+                [9u8; 20].into(),
+                1,
+                BigUint::from(10u32),
+                BigUint::from(0u32),
+                0,
+                None,
+            ),
+            account_id: 5,
+        }));
+        let block = RollupOpsBlock {
+            block_num: 1,
+            ops: vec![deposit, withdraw],
+            fee_account: 0,
+        };
+
+        let mut tree = TreeState::new(vec![50]);
+        assert!(tree.update_tree_states_from_ops_block(&block).is_err());
+
+        let mut tree = TreeState::new(vec![50]);
+        tree.nonexistent_exit_policy = NonexistentExitPolicy::Skip;
+        tree.update_tree_states_from_ops_block(&block)
+            .expect("Exit of the nonexistent account must be skipped");
+        assert_eq!(
+            tree.skipped_exits(),
+            &[SkippedExit {
+                block_number: 1,
+                account_id: 5,
+            }]
+        );
+        assert_eq!(tree.get_accounts().len(), 1);
+        assert_eq!(
+            tree.get_account(0).unwrap().get_balance(1),
+            BigUint::from(1000u32)
+        );
+    }
+
+    #[test]
+    fn test_fees_collected() {
+        let deposit = |to: u8, account_id, token| {
+            ZkSyncOp::Deposit(Box::new(DepositOp {
+                priority_op: Deposit {
+                    from: [1u8; 20].into(),
+                    token,
+                    amount: BigUint::from(1000u32),
+                    to: [to; 20].into(),
+                },
+                account_id,
+            }))
+        };
+        let transfer = |from, to, token, fee: u32| {
+            let tx = Transfer::new(
+                from,
+                [8u8; 20].into(),
+                [7u8; 20].into(),
+                token,
+                BigUint::from(10u32),
+                BigUint::from(fee),
+                0,
+                None,
+            );
+            ZkSyncOp::Transfer(Box::new(TransferOp { tx, from, to }))
+        };
+        let blocks = vec![
+            RollupOpsBlock {
+                block_num: 1,
+                ops: vec![deposit(7, 0, 1), deposit(8, 1, 1), deposit(8, 1, 2)],
+                fee_account: 0,
+            },
+            RollupOpsBlock {
+                block_num: 2,
+                ops: vec![transfer(1, 0, 1, 3), transfer(1, 0, 2, 5)],
+                fee_account: 0,
+            },
+            RollupOpsBlock {
+                block_num: 3,
+                ops: vec![transfer(0, 1, 1, 4)],
+                fee_account: 1,
+            },
+        ];
+
+        let mut tree = TreeState::new(vec![50]);
+        for block in &blocks {
+            tree.update_tree_states_from_ops_block(block)
+                .expect("Cant update state");
+        }
+        assert_eq!(tree.fees_collected().len(), 2);
+        assert_eq!(tree.fees_collected()[&1], BigUint::from(7u32));
+        assert_eq!(tree.fees_collected()[&2], BigUint::from(5u32));
+
+        // Fees of the blocks 2 and 3 go to the different operators.
+        let operator_fees = tree.operator_fees();
+        assert_eq!(operator_fees.len(), 2);
+        assert_eq!(operator_fees[&0].len(), 2);
+        assert_eq!(operator_fees[&0][&1], BigUint::from(3u32));
+        assert_eq!(operator_fees[&0][&2], BigUint::from(5u32));
+        assert_eq!(operator_fees[&1].len(), 1);
+        assert_eq!(operator_fees[&1][&1], BigUint::from(4u32));
+    }
+
+    #[test]
+    fn test_accounts_by_pubkey() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let public_key = public_key_from_private(&PrivateKey(rng.gen()));
+        let other_public_key = public_key_from_private(&PrivateKey(rng.gen()));
+
+        let mut accounts = AccountMap::default();
+        for (id, key) in [&public_key, &other_public_key, &public_key]
+            .iter()
+            .enumerate()
+        {
+            let mut account = Account::default_with_address(&[id as u8; 20].into());
+            account.pub_key_hash = PubKeyHash::from_pubkey(key);
+            accounts.insert(id as u32, account);
+        }
+        let tree = TreeState::load(1, accounts, 0, 0, vec![50]);
+
+        assert_eq!(tree.accounts_by_pubkey(&public_key), vec![0, 2]);
+        assert_eq!(tree.accounts_by_pubkey(&other_public_key), vec![1]);
+        let unknown_public_key = public_key_from_private(&PrivateKey(rng.gen()));
+        assert!(tree.accounts_by_pubkey(&unknown_public_key).is_empty());
+    }
+
+    #[test]
+    fn test_balance_change_callback() {
+        let deposit = |to: u8, account_id| {
+            ZkSyncOp::Deposit(Box::new(DepositOp {
+                priority_op: Deposit {
+                    from: [1u8; 20].into(),
+                    token: 1,
+                    amount: BigUint::from(1000u32),
+                    to: [to; 20].into(),
+                },
+                account_id,
+            }))
+        };
+        let transfer = ZkSyncOp::Transfer(Box::new(TransferOp {
+            tx: Transfer::new(
+                1,
+                [8u8; 20].into(),
+                [9u8; 20].into(),
+                1,
+                BigUint::from(100u32),
+                BigUint::from(0u32),
+                0,
+                None,
+            ),
+            from: 1,
+            to: 2,
+        }));
+        let deposits_block = RollupOpsBlock {
+            block_num: 1,
+            ops: vec![deposit(7, 0), deposit(8, 1), deposit(9, 2)],
+            fee_account: 0,
+        };
+        let transfer_block = RollupOpsBlock {
+            block_num: 2,
+            ops: vec![transfer],
+            fee_account: 0,
+        };
+
+        let mut tree = TreeState::new(vec![50]);
+        tree.update_tree_states_from_ops_block(&deposits_block)
+            .expect("Cant update state");
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        {
+            let changes = changes.clone();
+            tree.set_balance_change_callback(Box::new(move |change| {
+                changes.lock().unwrap().push(change.clone())
+            }));
+        }
+        tree.update_tree_states_from_ops_block(&transfer_block)
+            .expect("Cant update state");
+
+        // The transfer has no fee, so only the sender and the recipient balances change.
+        let change = |account_id, delta: i32| BalanceChange {
+            account_id,
+            token: 1,
+            delta: BigInt::from(delta),
+            block_number: 2,
+        };
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![change(1, -100), change(2, 100)]
+        );
+    }
+
+    #[test]
+    fn test_take_settings_from() {
+        let mut tree = TreeState::new(vec![50]);
+        tree.strict_mode = true;
+        tree.record_transfer_roots = true;
+        tree.enable_account_cache(10);
+        let changes_count = Arc::new(Mutex::new(0));
+        {
+            let changes_count = changes_count.clone();
+            tree.set_balance_change_callback(Box::new(move |_| {
+                *changes_count.lock().unwrap() += 1
+            }));
+        }
+        tree.update_tree_states_from_ops_block(&deposit_block(1))
+            .expect("Cant update state");
+        assert!(tree.get_account(0).is_some());
+
+        let mut loaded_tree = TreeState::new(vec![50]);
+        loaded_tree.take_settings_from(&mut tree);
+        assert!(loaded_tree.strict_mode);
+        assert!(loaded_tree.record_transfer_roots);
+        // The cached account of the replaced state isn't served.
+        assert!(loaded_tree.get_account(0).is_none());
+
+        // The callback is moved as well.
+        loaded_tree
+            .update_tree_states_from_ops_block(&deposit_block(1))
+            .expect("Cant update state");
+        assert_eq!(*changes_count.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_account_activity() {
+        let deposit = ZkSyncOp::Deposit(Box::new(DepositOp {
+            priority_op: Deposit {
+                from: [1u8; 20].into(),
+                token: 1,
+                amount: BigUint::from(1000u32),
+                to: [7u8; 20].into(),
+            },
+            account_id: 0,
+        }));
+        let transfer_to_new = ZkSyncOp::TransferToNew(Box::new(TransferToNewOp {
+            tx: Transfer::new(
+                0,
+                [7u8; 20].into(),
+                [8u8; 20].into(),
+                1,
+                BigUint::from(100u32),
+                BigUint::from(10u32),
+                0,
+                None,
+            ),
+            from: 0,
+            to: 1,
+        }));
+        let block = RollupOpsBlock {
+            block_num: 1,
+            ops: vec![deposit, transfer_to_new],
+            fee_account: 0,
+        };
+
+        let mut tree = TreeState::new(vec![50]);
+        tree.update_tree_states_from_ops_block(&block)
+            .expect("Cant update state");
+
+        let activity = tree.account_activity();
+        assert_eq!(activity.len(), 2);
+        assert_eq!(
+            activity[&0],
+            AccountActivity {
+                deposits: 1,
+                transfers_sent: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            activity[&1],
+            AccountActivity {
+                transfers_received: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_record_transfer_roots() {
+        let deposit = |to: u8, account_id| {
+            ZkSyncOp::Deposit(Box::new(DepositOp {
+                priority_op: Deposit {
+                    from: [1u8; 20].into(),
+                    token: 1,
+                    amount: BigUint::from(1000u32),
+                    to: [to; 20].into(),
+                },
+                account_id,
+            }))
+        };
+        let transfer = |from, to, amount: u32| {
+            let tx = Transfer::new(
+                from,
+                [8u8; 20].into(),
+                [7u8; 20].into(),
+                1,
+                BigUint::from(amount),
+                BigUint::from(0u32),
+                0,
+                None,
+            );
+            ZkSyncOp::Transfer(Box::new(TransferOp { tx, from, to }))
+        };
+        let block1 = RollupOpsBlock {
+            block_num: 1,
+            ops: vec![deposit(7, 0), deposit(8, 1)],
+            fee_account: 0,
+        };
+        let block2 = RollupOpsBlock {
+            block_num: 2,
+            ops: vec![transfer(1, 0, 40), transfer(0, 1, 10)],
+            fee_account: 0,
+        };
+
+        let mut tree = TreeState::new(vec![50]);
+        tree.update_tree_states_from_ops_block(&block1)
+            .expect("Cant update state from block 1");
+        tree.update_tree_states_from_ops_block(&block2)
+            .expect("Cant update state from block 2");
+        assert!(tree.last_block_transfer_roots.is_empty());
+
+        let mut tree = TreeState::new(vec![50]);
+        tree.record_transfer_roots = true;
+        tree.update_tree_states_from_ops_block(&block1)
+            .expect("Cant update state from block 1");
+        assert!(tree.last_block_transfer_roots.is_empty());
+
+        let mut cursor = tree.start_ops_block();
+        tree.apply_ops_block_chunk(&block2, &mut cursor, 1)
+            .expect("Cant apply the first transfer");
+        let first_transfer_root = tree.root_hash();
+        tree.apply_ops_block_chunk(&block2, &mut cursor, 1)
+            .expect("Cant apply the second transfer");
+        let second_transfer_root = tree.root_hash();
+        tree.finish_ops_block(&block2, cursor)
+            .expect("Cant finish block 2");
+
+        assert_eq!(
+            tree.last_block_transfer_roots,
+            vec![first_transfer_root, second_transfer_root]
+        );
+        assert_ne!(first_transfer_root, second_transfer_root);
+    }
+
+    #[test]
+    fn test_zero_amount_deposit() {
+        let block = RollupOpsBlock {
+            block_num: 1,
+            ops: vec![ZkSyncOp::Deposit(Box::new(DepositOp {
+                priority_op: Deposit {
+                    from: [1u8; 20].into(),
+                    token: 1,
+                    amount: BigUint::from(0u32),
+                    to: [7u8; 20].into(),
+                },
+                account_id: 0,
+            }))],
+            fee_account: 0,
+        };
+
+        let mut tree = TreeState::new(vec![50]);
+        tree.update_tree_states_from_ops_block(&block)
+            .expect("Zero amount deposit must be applied in the default mode");
+        assert_eq!(tree.get_accounts().len(), 1);
+
+        let mut tree = TreeState::new(vec![50]);
+        tree.strict_mode = true;
+        let err = tree
+            .update_tree_states_from_ops_block(&block)
+            .err()
+            .expect("Zero amount deposit must be rejected in strict mode");
+        assert!(err.to_string().contains("zero amount deposit"));
+    }
+
+    #[test]
+    fn test_block_gap() {
+        let mut tree = TreeState::new(vec![50]);
+        for block_num in 1..=2 {
+            tree.update_tree_states_from_ops_block(&deposit_block(block_num))
+                .expect("Cant update state");
+        }
+        let root_hash = tree.root_hash();
+        let err = tree
+            .update_tree_states_from_ops_block(&deposit_block(4))
+            .err()
+            .expect("Block gap must be detected");
+        assert_eq!(
+            err.downcast_ref::<BlockGap>(),
+            Some(&BlockGap {
+                expected: 3,
+                got: 4
+            })
+        );
+        assert_eq!(tree.state.block_number, 2);
+        assert_eq!(tree.root_hash(), root_hash);
+
+        // The already applied block is rejected as well.
+        let err = tree
+            .update_tree_states_from_ops_block(&deposit_block(2))
+            .err()
+            .expect("Repeated block must be detected");
+        assert_eq!(
+            err.downcast_ref::<BlockGap>(),
+            Some(&BlockGap {
+                expected: 3,
+                got: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_reused_account_id() {
+        let deposit = |to: u8, account_id| {
+            ZkSyncOp::Deposit(Box::new(DepositOp {
+                priority_op: Deposit {
+                    from: [1u8; 20].into(),
+                    token: 1,
+                    amount: BigUint::from(1000u32),
+                    to: [to; 20].into(),
+                },
+                account_id,
+            }))
+        };
+
+        let mut tree = TreeState::new(vec![50]);
+        tree.strict_mode = true;
+        let block = RollupOpsBlock {
+            block_num: 1,
+            ops: vec![deposit(7, 0), deposit(8, 1)],
+            fee_account: 0,
+        };
+        tree.update_tree_states_from_ops_block(&block)
+            .expect("Cant update state from block 1");
+
+        // Deposit to the new address claims the already used account id.
+        let block = RollupOpsBlock {
+            block_num: 2,
+            ops: vec![deposit(9, 1)],
+            fee_account: 0,
+        };
+        let mut lenient_tree =
+            TreeState::load(1, tree.get_accounts().into_iter().collect(), 2, 0, vec![50]);
+        lenient_tree
+            .update_tree_states_from_ops_block(&block)
+            .expect("Account id mismatch must be allowed in non-strict mode");
+        assert!(tree.try_update_tree_states_from_ops_block(&block).is_err());
+
+        // Transfer to the new account can't override the existing one.
+        let transfer_to_new = |block_num| {
+            let tx = Transfer::new(
+                0,
+                [7u8; 20].into(),
+                [10u8; 20].into(),
+                1,
+                BigUint::from(40u32),
+                BigUint::from(1u32),
+                0,
+                None,
+            );
+            RollupOpsBlock {
+                block_num,
+                ops: vec![ZkSyncOp::TransferToNew(Box::new(TransferToNewOp {
+                    tx,
+                    from: 0,
+                    to: 1,
+                }))],
+                fee_account: 0,
+            }
+        };
+        assert!(tree
+            .try_update_tree_states_from_ops_block(&transfer_to_new(2))
+            .is_err());
+        // In non-strict mode the amount goes to the existing account instead.
+        lenient_tree
+            .update_tree_states_from_ops_block(&transfer_to_new(3))
+            .expect("Account reuse must be allowed in non-strict mode");
+        let second_acc = lenient_tree.get_account(1).expect("Cant get 1 account");
+        assert_eq!(second_acc.address, [8u8; 20].into());
+        assert_eq!(second_acc.get_balance(1), BigUint::from(1040u32));
+
+        // Full exits don't delete the accounts, so the deposit after the exit
+        // gets the same account id without triggering the reuse checks.
+        let block = RollupOpsBlock {
+            block_num: 2,
+            ops: vec![
+                ZkSyncOp::FullExit(Box::new(FullExitOp {
+                    priority_op: FullExit {
+                        account_id: 0,
+                        eth_address: [7u8; 20].into(),
+                        token: 1,
+                    },
+                    withdraw_amount: Some(BigUint::from(1000u32).into()),
+                })),
+                deposit(7, 0),
+            ],
+            fee_account: 0,
+        };
+        tree.update_tree_states_from_ops_block(&block)
+            .expect("Deposit after the full exit must be applied in strict mode");
+        let first_acc = tree.get_account(0).expect("Cant get 0 account");
+        assert_eq!(first_acc.address, [7u8; 20].into());
+        assert_eq!(first_acc.get_balance(1), BigUint::from(1000u32));
+    }
+
+    #[test]
+    fn test_decode_block_transfers() {
+        let deposit = ZkSyncOp::Deposit(Box::new(DepositOp {
+            priority_op: Deposit {
+                from: [1u8; 20].into(),
+                token: 1,
+                amount: BigUint::from(1000u32),
+                to: [7u8; 20].into(),
+            },
+            account_id: 0,
+        }));
+        let mut tree = TreeState::new(vec![50]);
+        tree.update_tree_states_from_ops_block(&RollupOpsBlock {
+            block_num: 1,
+            ops: vec![deposit],
+            fee_account: 0,
+        })
+        .expect("Cant update state from block 1");
+
+        let tx1 = Transfer::new(
+            0,
+            [7u8; 20].into(),
+            [8u8; 20].into(),
+            1,
+            BigUint::from(40u32),
+            BigUint::from(1u32),
+            0,
+            None,
+        );
+        let op1 = ZkSyncOp::TransferToNew(Box::new(TransferToNewOp {
+            tx: tx1,
+            from: 0,
+            to: 1,
+        }));
+        let tx2 = Transfer::new(
+            1,
+            [8u8; 20].into(),
+            [7u8; 20].into(),
+            1,
+            BigUint::from(19u32),
+            BigUint::from(1u32),
+            0,
+            None,
+        );
+        let op2 = ZkSyncOp::Transfer(Box::new(TransferOp {
+            tx: tx2,
+            from: 1,
+            to: 0,
+        }));
+        let mut pub_data = op1.public_data();
+        pub_data.extend(op2.public_data());
+        let block = RollupOpsBlock {
+            block_num: 2,
+            ops: RollupOpsBlock::get_rollup_ops_from_data(&pub_data)
+                .expect("cant get ops from data"),
+            fee_account: 0,
+        };
+
+        let transfers = tree.decode_block_transfers(&block);
+        assert_eq!(
+            transfers,
+            vec![
+                DecodedTransfer {
+                    from: 0,
+                    from_address: Some([7u8; 20].into()),
+                    to: 1,
+                    to_address: Some([8u8; 20].into()),
+                    token: 1,
+                    amount: BigUint::from(40u32),
+                    fee: BigUint::from(1u32),
+                    to_new_account: true,
+                },
+                DecodedTransfer {
+                    from: 1,
+                    from_address: Some([8u8; 20].into()),
+                    to: 0,
+                    to_address: Some([7u8; 20].into()),
+                    token: 1,
+                    amount: BigUint::from(19u32),
+                    fee: BigUint::from(1u32),
+                    to_new_account: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_block_exits() {
+        let full_exit = |account_id, eth_address: u8, amount: u32| {
+            ZkSyncOp::FullExit(Box::new(FullExitOp {
+                priority_op: FullExit {
+                    account_id,
+                    eth_address: [eth_address; 20].into(),
+                    token: 1,
+                },
+                withdraw_amount: Some(BigUint::from(amount).into()),
+            }))
+        };
+        let deposit = ZkSyncOp::Deposit(Box::new(DepositOp {
+            priority_op: Deposit {
+                from: [1u8; 20].into(),
+                token: 1,
+                amount: BigUint::from(1000u32),
+                to: [7u8; 20].into(),
+            },
+            account_id: 0,
+        }));
+        let mut pub_data = deposit.public_data();
+        pub_data.extend(full_exit(0, 7, 1000).public_data());
+        // The balance is already withdrawn, so the second full exit withdraws nothing.
+        pub_data.extend(full_exit(0, 7, 0).public_data());
+        let block = RollupOpsBlock {
+            block_num: 1,
+            ops: RollupOpsBlock::get_rollup_ops_from_data(&pub_data)
+                .expect("cant get ops from data"),
+            fee_account: 0,
+        };
+
+        let mut tree = TreeState::new(vec![50]);
+        tree.update_tree_states_from_ops_block(&block)
+            .expect("Cant update state from block 1");
+        assert_eq!(
+            TreeState::decode_block_exits(&block),
+            vec![ExitedAccount {
+                account_id: 0,
+                eth_address: [7u8; 20].into(),
+                token: 1,
+                amount: BigUint::from(1000u32),
+            }]
+        );
+        assert_eq!(
+            tree.get_account(0).unwrap().get_balance(1),
+            BigUint::from(0u32)
+        );
+    }
+
+    #[cfg(feature = "strict_invariants")]
+    #[test]
+    fn test_block_invariants() {
+        use super::check_block_invariants;
+        use zksync_types::AccountUpdate;
+
+        let create = (
+            1,
+            AccountUpdate::Create {
+                address: [7u8; 20].into(),
+                nonce: 0,
+            },
+        );
+        let update = |old_nonce, new_nonce| {
+            (
+                1,
+                AccountUpdate::UpdateBalance {
+                    old_nonce,
+                    new_nonce,
+                    balance_update: (0, BigUint::from(0u32), BigUint::from(10u32)),
+                },
+            )
+        };
+
+        assert!(check_block_invariants(1, 2, &[create.clone(), update(0, 1)]).is_ok());
+        // Nonce decreased.
+        let error = check_block_invariants(1, 2, &[create.clone(), update(2, 1)])
+            .expect_err("Nonce decrease must be detected");
+        assert!(error.to_string().contains("nonce monotonicity"));
+        // Created account is missing in the state.
+        let error = check_block_invariants(1, 1, &[create, update(0, 1)])
+            .expect_err("Missing account must be detected");
+        assert!(error.to_string().contains("accounts count consistency"));
+    }
+
+    #[test]
+    fn test_try_get_account() {
+        let mut accounts = AccountMap::default();
+        accounts.insert(0, Account::default_with_address(&[7u8; 20].into()));
+        let tree = TreeState::load(0, accounts, 0, 0, vec![50]);
+
+        let account = tree
+            .try_get_account(0)
+            .expect("Cant get account")
+            .expect("Account 0 must exist");
+        assert_eq!(account.address, [7u8; 20].into());
+        assert!(tree.try_get_account(1).expect("Cant get account").is_none());
+    }
+
+    #[test]
+    fn test_verify_tree_consistency() {
+        let mut accounts = AccountMap::default();
+        accounts.insert(0, Account::default_with_address(&[7u8; 20].into()));
+        accounts.insert(1, Account::default_with_address(&[8u8; 20].into()));
+        let mut tree = TreeState::load(0, accounts, 0, 0, vec![50]);
+        tree.verify_tree_consistency()
+            .expect("Loaded tree must be consistent");
+
+        // Inserting the account with the already used address leaves the first account
+        // unreachable by its address.
+        tree.state
+            .insert_account(2, Account::default_with_address(&[7u8; 20].into()));
+        let err = tree
+            .verify_tree_consistency()
+            .err()
+            .expect("Desynced tree must be detected");
+        assert!(err.to_string().contains("is indexed as the account"));
+
+        // The account is changed in the tree items behind the cached tree hashes,
+        // so the tree root is outdated.
+        let mut balance_tree = AccountTree::new(account_tree_depth());
+        let mut account_id_by_address = HashMap::new();
+        for id in 0..2u32 {
+            let account = Account::default_with_address(&[id as u8 + 7; 20].into());
+            account_id_by_address.insert(account.address, id);
+            balance_tree.insert(id, account);
+        }
+        balance_tree.root_hash();
+        balance_tree
+            .items
+            .get_mut(&1)
+            .unwrap()
+            .set_balance(1, BigUint::from(100u32));
+        tree.state = ZkSyncState::new(balance_tree, account_id_by_address, 0);
+        let err = tree
+            .verify_tree_consistency()
+            .err()
+            .expect("Outdated tree root must be detected");
+        assert!(err.to_string().contains("doesn't match the root hash"));
+    }
+
+    #[test]
+    fn test_account_with_proof() {
+        let mut accounts = AccountMap::default();
+        for id in 0..5u32 {
+            let mut account = Account::default_with_address(&[id as u8; 20].into());
+            account.set_balance(1, BigUint::from(id * 100));
+            accounts.insert(id, account);
+        }
+        let tree = TreeState::load(1, accounts.clone(), 0, 0, vec![50]);
+
+        let (account, proof) = tree.account_with_proof(3).expect("Account 3 exists");
+        assert_eq!(account.get_balance(1), BigUint::from(300u32));
+
+        // The wallet verifies the proof against the root hash it trusts.
+        let mut verifier_tree = AccountTree::new(account_tree_depth());
+        for (id, account) in accounts {
+            verifier_tree.insert(id, account);
+        }
+        assert_eq!(verifier_tree.root_hash(), tree.root_hash());
+        assert!(verifier_tree.verify_proof(3, account.clone(), proof.clone()));
+        assert!(!verifier_tree.verify_proof(2, account.clone(), proof.clone()));
+        let mut forged_account = account;
+        forged_account.set_balance(1, BigUint::from(301u32));
+        assert!(!verifier_tree.verify_proof(3, forged_account, proof));
+
+        assert!(tree.account_with_proof(7).is_none());
+    }
+
+    #[test]
+    fn test_verify_proofs() {
+        let mut accounts = AccountMap::default();
+        for id in 0..4u32 {
+            let mut account = Account::default_with_address(&[id as u8; 20].into());
+            account.set_balance(1, BigUint::from(id * 100));
+            accounts.insert(id, account);
+        }
+        let tree = TreeState::load(1, accounts, 0, 0, vec![50]);
+        let proof = |account_id| {
+            let (account, proof) = tree.account_with_proof(account_id).unwrap();
+            (account_id, account, proof)
+        };
+
+        let mut forged_balance = proof(1);
+        forged_balance.1.set_balance(1, BigUint::from(1000u32));
+        let mut forged_index = proof(2);
+        forged_index.0 = 3;
+        let mut forged_path = proof(3);
+        forged_path.2[5].0 = forged_path.2[4].0;
+        let mut truncated_path = proof(0);
+        truncated_path.2.pop();
+
+        let proofs = vec![
+            proof(0),
+            forged_balance,
+            proof(2),
+            forged_index,
+            forged_path,
+            proof(3),
+            truncated_path,
+        ];
+        assert_eq!(
+            verify_proofs(&proofs, tree.root_hash()),
+            vec![true, false, true, false, false, true, false]
+        );
+        // Valid proofs don't match the other root.
+        let mut other_tree = TreeState::new(vec![50]);
+        other_tree.state.insert_account(0, proof(0).1);
+        assert_eq!(
+            verify_proofs(&proofs[..1], other_tree.root_hash()),
+            vec![false]
+        );
+        assert!(verify_proofs(&[], tree.root_hash()).is_empty());
+    }
+
+    #[test]
+    fn test_get_accounts_by_ids() {
+        let mut accounts = AccountMap::default();
+        for id in 0..3u32 {
+            accounts.insert(id, Account::default_with_address(&[id as u8; 20].into()));
+        }
+        let tree = TreeState::load(1, accounts, 0, 0, vec![50]);
+
+        let found: Vec<_> = tree
+            .get_accounts_by_ids(&[2, 7, 0, 2])
+            .into_iter()
+            .map(|(id, account)| (id, account.map(|account| account.address)))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (2, Some([2u8; 20].into())),
+                (7, None),
+                (0, Some([0u8; 20].into())),
+                (2, Some([2u8; 20].into())),
+            ]
+        );
+        assert!(tree.get_accounts_by_ids(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_account_cache_invalidation() {
+        let deposit = |account_id, amount: u32| {
+            ZkSyncOp::Deposit(Box::new(DepositOp {
+                priority_op: Deposit {
+                    from: [1u8; 20].into(),
+                    token: 1,
+                    amount: BigUint::from(amount),
+                    to: [7u8; 20].into(),
+                },
+                account_id,
+            }))
+        };
+        let mut tree = TreeState::new(vec![50]);
+        tree.enable_account_cache(10);
+        tree.update_tree_states_from_ops_block(&RollupOpsBlock {
+            block_num: 1,
+            ops: vec![deposit(0, 1000)],
+            fee_account: 0,
+        })
+        .expect("Cant update state from block 1");
+        let account = tree.get_account(0).expect("Cant get 0 account");
+        assert_eq!(account.get_balance(1), BigUint::from(1000u32));
+
+        // The cached account is invalidated by the block updating it.
+        tree.update_tree_states_from_ops_block(&RollupOpsBlock {
+            block_num: 2,
+            ops: vec![deposit(0, 500)],
+            fee_account: 0,
+        })
+        .expect("Cant update state from block 2");
+        let account = tree.get_account(0).expect("Cant get 0 account");
+        assert_eq!(account.get_balance(1), BigUint::from(1500u32));
+
+        // Direct changes of the state have to be invalidated explicitly.
+        let mut changed_account = account;
+        changed_account.set_balance(1, BigUint::from(1u32));
+        tree.state.insert_account(0, changed_account);
+        assert_eq!(
+            tree.get_account(0).unwrap().get_balance(1),
+            BigUint::from(1500u32)
+        );
+        tree.invalidate_cached_account(0);
+        assert_eq!(
+            tree.get_account(0).unwrap().get_balance(1),
+            BigUint::from(1u32)
+        );
+    }
+
+    /// Run with `cargo test account_cache_vs_tree_reads -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn account_cache_vs_tree_reads() {
+        let mut accounts = AccountMap::default();
+        for id in 0..10_000u32 {
+            let mut account = Account::default_with_address(&[id as u8; 20].into());
+            account.set_balance(1, BigUint::from(id));
+            accounts.insert(id, account);
+        }
+        let tree = TreeState::load(1, accounts.clone(), 0, 0, vec![50]);
+        let mut cached_tree = TreeState::load(1, accounts, 0, 0, vec![50]);
+        cached_tree.enable_account_cache(1000);
+        // The read accounts fit into the cache, so all the reads but the first ones are hits.
+        let ids: Vec<u32> = (0..100_000u32).map(|i| (i * 31) % 1000).collect();
+
+        let started = Instant::now();
+        for id in &ids {
+            assert!(tree.get_account(*id).is_some());
+        }
+        let tree_time = started.elapsed();
+
+        let started = Instant::now();
+        for id in &ids {
+            assert!(cached_tree.get_account(*id).is_some());
+        }
+        let cached_time = started.elapsed();
+
+        println!(
+            "{} reads without cache: {:?}; with cache: {:?}",
+            ids.len(),
+            tree_time,
+            cached_time
+        );
+    }
+
+    #[test]
+    fn test_revert_transfer() {
+        let deposit = |to: u8, account_id| {
+            ZkSyncOp::Deposit(Box::new(DepositOp {
+                priority_op: Deposit {
+                    from: [1u8; 20].into(),
+                    token: 1,
+                    amount: BigUint::from(1000u32),
+                    to: [to; 20].into(),
+                },
+                account_id,
+            }))
+        };
+        let transfer_op = TransferOp {
+            tx: Transfer::new(
+                0,
+                [7u8; 20].into(),
+                [8u8; 20].into(),
+                1,
+                BigUint::from(100u32),
+                BigUint::from(10u32),
+                0,
+                None,
+            ),
+            from: 0,
+            to: 1,
+        };
+        let total_supply = |tree: &TreeState| -> BigUint {
+            tree.get_accounts()
+                .iter()
+                .map(|(_, account)| account.get_balance(1))
+                .sum()
+        };
+        let mut tree = TreeState::new(vec![50]);
+        tree.update_tree_states_from_ops_block(&RollupOpsBlock {
+            block_num: 1,
+            ops: vec![deposit(7, 0), deposit(8, 1), deposit(9, 2)],
+            fee_account: 0,
+        })
+        .expect("Cant update state");
+        let sender_baseline = tree.get_account(0).unwrap();
+        let activity_baseline = tree.account_activity();
+        assert_eq!(total_supply(&tree), BigUint::from(3000u32));
+
+        // The fee goes to the account 2.
+        tree.update_tree_states_from_ops_block(&RollupOpsBlock {
+            block_num: 2,
+            ops: vec![ZkSyncOp::Transfer(Box::new(transfer_op.clone()))],
+            fee_account: 2,
+        })
+        .expect("Cant update state");
+        assert_eq!(tree.get_account(0).unwrap().nonce, 1);
+        assert_eq!(tree.fees_collected()[&1], BigUint::from(10u32));
+
+        let updates = tree
+            .revert_transfer(&transfer_op, 2)
+            .expect("Applied transfer must be reverted");
+        assert_eq!(updates.len(), 3);
+        let sender = tree.get_account(0).unwrap();
+        assert_eq!(sender.nonce, sender_baseline.nonce);
+        assert_eq!(sender.get_balance(1), sender_baseline.get_balance(1));
+        assert_eq!(
+            tree.get_account(1).unwrap().get_balance(1),
+            BigUint::from(1000u32)
+        );
+        assert_eq!(
+            tree.get_account(2).unwrap().get_balance(1),
+            BigUint::from(1000u32)
+        );
+        assert_eq!(total_supply(&tree), BigUint::from(3000u32));
+        assert!(tree.fees_collected().is_empty());
+        assert!(tree.operator_fees().is_empty());
+        assert_eq!(tree.account_activity(), activity_baseline);
+
+        // The sender nonce is zero again, so the transfer can't be reverted twice.
+        assert!(tree.revert_transfer(&transfer_op, 2).is_err());
+    }
+
+    #[test]
+    fn test_apply_ops_block_checked() {
+        let deposit_block = |block_num: u32, amount: u32| RollupOpsBlock {
+            block_num,
+            ops: vec![ZkSyncOp::Deposit(Box::new(DepositOp {
+                priority_op: Deposit {
+                    from: [1u8; 20].into(),
+                    token: 1,
+                    amount: BigUint::from(amount),
+                    to: [7u8; 20].into(),
+                },
+                account_id: 0,
+            }))],
+            fee_account: 0,
+        };
+        let mut expected_tree = TreeState::new(vec![50]);
+        let mut expected_roots = Vec::new();
+        for block_num in 1..3 {
+            expected_tree
+                .update_tree_states_from_ops_block(&deposit_block(block_num, 1000))
+                .expect("Cant update state");
+            expected_roots.push(expected_tree.root_hash());
+        }
+
+        let mut tree = TreeState::new(vec![50]);
+        tree.apply_ops_block_checked(&deposit_block(1, 1000), expected_roots[0])
+            .expect("Block with the expected root must be applied");
+        assert_eq!(tree.root_hash(), expected_roots[0]);
+
+        // The corrupted block deposits another amount, so the resulting root differs.
+        let err = tree
+            .apply_ops_block_checked(&deposit_block(2, 999), expected_roots[1])
+            .err()
+            .expect("Block with the other root must be rejected");
+        assert!(err.to_string().contains("Root mismatch"));
+        assert_eq!(tree.root_hash(), expected_roots[0]);
+        assert_eq!(tree.state.block_number, 1);
+        assert_eq!(tree.current_unprocessed_priority_op, 1);
+        assert_eq!(
+            tree.get_account(0).unwrap().get_balance(1),
+            BigUint::from(1000u32)
+        );
+
+        tree.apply_ops_block_checked(&deposit_block(2, 1000), expected_roots[1])
+            .expect("Block with the expected root must be applied");
+        assert_eq!(tree.root_hash(), expected_roots[1]);
+    }
+
+    #[test]
+    fn test_apply_ops_block_checked_failed_op() {
+        let deposit = ZkSyncOp::Deposit(Box::new(DepositOp {
+            priority_op: Deposit {
+                from: [1u8; 20].into(),
+                token: 1,
+                amount: BigUint::from(1000u32),
+                to: [7u8; 20].into(),
+            },
+            account_id: 0,
+        }));
+        let mut tree = TreeState::new(vec![50]);
+        tree.update_tree_states_from_ops_block(&RollupOpsBlock {
+            block_num: 1,
+            ops: vec![deposit.clone()],
+            fee_account: 0,
+        })
+        .expect("Cant update state");
+        let root_hash = tree.root_hash();
+        let activity = tree.account_activity();
+
+        // The second operation transfers from the nonexistent account 5.
+        let transfer = ZkSyncOp::Transfer(Box::new(TransferOp {
+            tx: Transfer::new(
+                5,
+                [9u8; 20].into(),
+                [7u8; 20].into(),
+                1,
+                BigUint::from(10u32),
+                BigUint::from(1u32),
+                0,
+                None,
+            ),
+            from: 5,
+            to: 0,
+        }));
+        let block = RollupOpsBlock {
+            block_num: 2,
+            ops: vec![deposit, transfer],
+            fee_account: 0,
+        };
+        assert!(tree.apply_ops_block_checked(&block, root_hash).is_err());
+
+        assert_eq!(tree.root_hash(), root_hash);
+        assert_eq!(tree.state.block_number, 1);
+        assert_eq!(tree.current_unprocessed_priority_op, 1);
+        assert_eq!(
+            tree.get_account(0).unwrap().get_balance(1),
+            BigUint::from(1000u32)
+        );
+        assert_eq!(tree.account_activity(), activity);
+    }
+
+    #[test]
+    fn test_old_root_verification() {
+        let mut tree = TreeState::new(vec![50]);
+        let genesis_root = tree.root_hash();
+        tree.update_tree_states_from_ops_block_with_old_root(&deposit_block(1), genesis_root)
+            .expect("Block on top of the current root must be applied");
+        let first_block_root = tree.root_hash();
+
+        // The block 3 is committed on top of the block 2 root, which is not applied yet.
+        let mut other_tree = TreeState::new(vec![50]);
+        for block_num in 1..3 {
+            other_tree
+                .update_tree_states_from_ops_block(&deposit_block(block_num))
+                .expect("Cant update state");
+        }
+        let err = tree
+            .update_tree_states_from_ops_block_with_old_root(
+                &deposit_block(3),
+                other_tree.root_hash(),
+            )
+            .err()
+            .expect("Block with the other old root must be rejected");
+        assert!(err.to_string().contains("Root mismatch"));
+        assert_eq!(tree.root_hash(), first_block_root);
+        assert_eq!(tree.state.block_number, 1);
+    }
+
+    #[test]
+    fn test_rebuild_tree() {
+        let accounts: Vec<_> = (0..20u32)
+            .map(|id| {
+                let mut account = Account::default_with_address(&[id as u8; 20].into());
+                account.set_balance(1, BigUint::from(id * 100));
+                (id, account)
+            })
+            .collect();
+
+        let mut sequential_tree = TreeState::new(vec![50]);
+        for (id, account) in accounts.iter().cloned() {
+            sequential_tree.state.insert_account(id, account);
+        }
+
+        let mut tree = TreeState::new(vec![50]);
+        tree.state.block_number = 4;
+        tree.rebuild_tree(accounts.clone())
+            .expect("Cant rebuild tree");
+        assert_eq!(tree.root_hash(), sequential_tree.root_hash());
+        assert_eq!(tree.get_accounts().len(), 20);
+        assert_eq!(tree.state.block_number, 4);
+
+        let mut duplicated_accounts = accounts;
+        duplicated_accounts.push((3, Account::default_with_address(&[99u8; 20].into())));
+        assert!(tree.rebuild_tree(duplicated_accounts).is_err());
+    }
+
+    #[test]
+    fn test_export_changes_since() {
+        let deposit = |to: u8, account_id| {
+            ZkSyncOp::Deposit(Box::new(DepositOp {
+                priority_op: Deposit {
+                    from: [1u8; 20].into(),
+                    token: 1,
+                    amount: BigUint::from(1000u32),
+                    to: [to; 20].into(),
+                },
+                account_id,
+            }))
+        };
+        let mut tree = TreeState::new(vec![50]);
+        tree.update_tree_states_from_ops_block(&RollupOpsBlock {
+            block_num: 1,
+            ops: vec![deposit(7, 0), deposit(8, 1)],
+            fee_account: 0,
+        })
+        .expect("Cant update state from block 1");
+        tree.update_tree_states_from_ops_block(&RollupOpsBlock {
+            block_num: 2,
+            ops: vec![deposit(8, 1)],
+            fee_account: 0,
+        })
+        .expect("Cant update state from block 2");
+
+        let mut export = Vec::new();
+        tree.export_changes_since(1, &mut export)
+            .expect("Cant export changes");
+        let changes: Vec<AccountChange> = String::from_utf8(export)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].id, 1);
+        assert_eq!(changes[0].block_number, 2);
+        assert_eq!(
+            changes[0].account.as_ref().unwrap().get_balance(1),
+            BigUint::from(2000u32)
+        );
+
+        let mut export = Vec::new();
+        tree.export_changes_since(0, &mut export)
+            .expect("Cant export changes");
+        assert_eq!(String::from_utf8(export).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut accounts = AccountMap::default();
+        accounts.insert(0, Account::default_with_address(&[7u8; 20].into()));
+        accounts.insert(1, Account::default_with_address(&[8u8; 20].into()));
+        let mut tree = TreeState::load(3, accounts, 2, 1, vec![50]);
+        tree.strict_mode = true;
+
+        tree.reset();
+        assert!(tree.get_accounts().is_empty());
+        assert_eq!(tree.root_hash(), TreeState::new(vec![50]).root_hash());
+        assert_eq!(tree.state.block_number, 0);
+        assert_eq!(tree.current_unprocessed_priority_op, 0);
+        assert_eq!(tree.available_block_chunk_sizes, vec![50]);
+        assert!(tree.strict_mode);
+    }
+
+    #[test]
+    fn test_balance_histogram() {
+        let mut accounts = AccountMap::default();
+        for (id, balance) in [0u32, 5, 10, 99, 100, 1000].iter().enumerate() {
+            let mut account = Account::default_with_address(&[id as u8; 20].into());
+            account.set_balance(1, BigUint::from(*balance));
+            accounts.insert(id as u32, account);
+        }
+        let tree = TreeState::load(0, accounts, 0, 0, vec![50]);
+
+        let buckets = [
+            BigUint::from(1u32),
+            BigUint::from(10u32),
+            BigUint::from(100u32),
+        ];
+        assert_eq!(tree.balance_histogram(1, &buckets), vec![1, 1, 2, 2]);
+        assert_eq!(tree.balance_histogram(2, &buckets), vec![6, 0, 0, 0]);
+        assert_eq!(tree.balance_histogram(1, &[]), vec![6]);
+    }
+
+    #[test]
+    fn test_balance_extremes() {
+        let mut accounts = AccountMap::default();
+        for (id, balance) in [50u32, 0, 7, 300, 7, 300].iter().enumerate() {
+            let mut account = Account::default_with_address(&[id as u8; 20].into());
+            account.set_balance(1, BigUint::from(*balance));
+            accounts.insert(id as u32, account);
+        }
+        let tree = TreeState::load(0, accounts, 0, 0, vec![50]);
+
+        assert_eq!(
+            tree.balance_extremes(1),
+            Some((2, BigUint::from(7u32), 3, BigUint::from(300u32)))
+        );
+        assert_eq!(tree.balance_extremes(2), None);
+    }
+
+    #[test]
+    fn test_estimated_memory_bytes() {
+        let tree_with_accounts = |count: u32| {
+            let mut accounts = AccountMap::default();
+            for id in 0..count {
+                let mut account = Account::default_with_address(&[id as u8; 20].into());
+                account.set_balance(1, BigUint::from(10u32));
+                accounts.insert(id, account);
+            }
+            TreeState::load(0, accounts, 0, 0, vec![50])
+        };
+
+        let small = tree_with_accounts(1).estimated_memory_bytes();
+        let large = tree_with_accounts(100).estimated_memory_bytes();
+        assert!(small > 0);
+        assert!(large > small * 50);
+    }
+
+    #[test]
+    fn test_active_accounts() {
+        let mut accounts = AccountMap::default();
+        for id in 0..4u8 {
+            let mut account = Account::default_with_address(&[id; 20].into());
+            if id % 2 == 1 {
+                account.set_balance(u16::from(id), BigUint::from(10u32));
+            }
+            // Explicitly zeroed balance doesn't make the account active.
+            account.set_balance(0, BigUint::from(0u32));
+            accounts.insert(u32::from(id), account);
+        }
+        let tree = TreeState::load(0, accounts, 0, 0, vec![50]);
+
+        let mut active_accounts: Vec<_> = tree
+            .active_accounts()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        active_accounts.sort_unstable();
+        assert_eq!(active_accounts, vec![1, 3]);
+        assert_eq!(tree.get_accounts().len(), 4);
+    }
+
+    #[test]
+    fn test_empty_accounts() {
+        let mut accounts = AccountMap::default();
+        for id in 0..5u8 {
+            accounts.insert(
+                u32::from(id),
+                Account::default_with_address(&[id; 20].into()),
+            );
+        }
+        accounts
+            .get_mut(&1)
+            .unwrap()
+            .set_balance(1, BigUint::from(10u32));
+        accounts.get_mut(&2).unwrap().nonce = 1;
+        accounts.get_mut(&3).unwrap().pub_key_hash = PubKeyHash { data: [3u8; 20] };
+        // Explicitly zeroed balance doesn't make the account used.
+        accounts
+            .get_mut(&4)
+            .unwrap()
+            .set_balance(1, BigUint::from(0u32));
+        let tree = TreeState::load(0, accounts, 0, 0, vec![50]);
+
+        assert_eq!(tree.empty_accounts(), vec![0, 4]);
+    }
+
     #[test]
     fn test_update_tree_with_multiple_txs_per_block() {
         let tx1 = Deposit {
@@ -98,6 +98,12 @@ impl ZkSyncState {
         hash
     }
 
+    /// Returns the merkle path of the account leaf to the tree root: the sibling hashes
+    /// with the flags set if the sibling is the left child.
+    pub fn merkle_path(&self, account_id: AccountId) -> Vec<(Fr, bool)> {
+        self.balance_tree.merkle_path(account_id)
+    }
+
     pub fn get_account(&self, account_id: AccountId) -> Option<Account> {
         let start = std::time::Instant::now();
 